@@ -0,0 +1,154 @@
+// Command line handling
+//
+//   ttymon [OPTION...] [-- COMMAND [ARG...]]
+//
+// Everything after -- is the command to run instead of the user's shell.
+
+use std::ffi::OsString;
+use std::path::PathBuf;
+
+pub const USAGE: &str = "\
+Usage: ttymon [OPTION...] [-- COMMAND [ARG...]]
+
+Runs COMMAND, or your shell, in a new terminal session, keeping the window
+title up to date with what is running in it.
+
+Options:
+  --log FILE         Append everything the command outputs to FILE
+  --log-timing FILE  Write timing information for --log to FILE, for
+                     scriptreplay(1)
+  --list-sessions    Print the state of registered ttymon instances as JSON
+  --inspect PID      Print what ttymon finds out about the terminal session
+                     PID leads, like a shell's $$, and exit
+  --help             Show this help and exit
+  --version          Show the version and exit
+";
+
+#[derive(Debug, Default, PartialEq)]
+pub struct RunOptions {
+    /// The command to run; if empty, the user's shell
+    pub command: Vec<OsString>,
+    pub log: Option<PathBuf>,
+    pub log_timing: Option<PathBuf>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Mode {
+    Run(RunOptions),
+    ListSessions,
+    Inspect(i32),
+    Help,
+    Version,
+}
+
+/// Parses the arguments, not including argv[0]
+pub fn parse_args<I>(args: I) -> Result<Mode, String>
+where
+    I: IntoIterator<Item = OsString>,
+{
+    let mut args = args.into_iter();
+    let mut mode = None;
+    let mut options = RunOptions::default();
+    while let Some(arg) = args.next() {
+        let arg_mode = match arg.to_str() {
+            Some("--") => {
+                options.command = args.collect();
+                break;
+            }
+            Some(option @ "--log") | Some(option @ "--log-timing") => {
+                let value = match args.next() {
+                    Some(value) => PathBuf::from(value),
+                    None => return Err(format!("{} requires an argument", option)),
+                };
+                if option == "--log" {
+                    options.log = Some(value);
+                } else {
+                    options.log_timing = Some(value);
+                }
+                continue;
+            }
+            Some("--list-sessions") => Mode::ListSessions,
+            Some("--inspect") => match args.next().as_ref().and_then(|v| v.to_str()) {
+                Some(value) => match value.parse() {
+                    Ok(pid) if pid > 0 => Mode::Inspect(pid),
+                    _ => return Err(format!("Invalid pid for --inspect: {}", value)),
+                },
+                None => return Err(String::from("--inspect requires an argument")),
+            },
+            Some("--help") | Some("-h") => Mode::Help,
+            Some("--version") => Mode::Version,
+            _ => return Err(format!("Unknown argument: {}", arg.to_string_lossy())),
+        };
+
+        // The first of --help, --version, etc. wins
+        if mode.is_none() {
+            mode = Some(arg_mode);
+        }
+    }
+
+    Ok(mode.unwrap_or(Mode::Run(options)))
+}
+
+pub fn version() -> String {
+    format!("ttymon {}", env!("CARGO_PKG_VERSION"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Mode, String> {
+        parse_args(args.iter().map(OsString::from))
+    }
+
+    fn run(command: &[&str]) -> Mode {
+        Mode::Run(RunOptions {
+            command: command.iter().map(OsString::from).collect(),
+            ..RunOptions::default()
+        })
+    }
+
+    #[test]
+    fn test_parse_args() {
+        assert_eq!(parse(&[]), Ok(run(&[])));
+        assert_eq!(parse(&["--"]), Ok(run(&[])));
+        assert_eq!(
+            parse(&["--", "vim", "--help", "--"]),
+            Ok(run(&["vim", "--help", "--"]))
+        );
+        assert_eq!(parse(&["--help"]), Ok(Mode::Help));
+        assert_eq!(parse(&["--version", "--help"]), Ok(Mode::Version));
+        assert_eq!(parse(&["--version", "--", "vim"]), Ok(Mode::Version));
+        assert_eq!(parse(&["--list-sessions"]), Ok(Mode::ListSessions));
+        assert_eq!(parse(&["--inspect", "1234"]), Ok(Mode::Inspect(1234)));
+        assert_eq!(
+            parse(&["--inspect", "1234", "--help"]),
+            Ok(Mode::Inspect(1234))
+        );
+
+        assert_eq!(
+            parse(&["--log", "/tmp/log", "--log-timing", "timing", "--", "vim"]),
+            Ok(Mode::Run(RunOptions {
+                command: vec![OsString::from("vim")],
+                log: Some(PathBuf::from("/tmp/log")),
+                log_timing: Some(PathBuf::from("timing")),
+            }))
+        );
+        // The value is taken as it is, even if it looks like an option
+        assert_eq!(
+            parse(&["--log", "--help"]),
+            Ok(Mode::Run(RunOptions {
+                log: Some(PathBuf::from("--help")),
+                ..RunOptions::default()
+            }))
+        );
+        assert_eq!(parse(&["--help", "--log", "log"]), Ok(Mode::Help));
+
+        assert!(parse(&["--verbose"]).is_err());
+        assert!(parse(&["vim"]).is_err());
+        assert!(parse(&["--log"]).is_err());
+        assert!(parse(&["--inspect"]).is_err());
+        assert!(parse(&["--inspect", "0"]).is_err());
+        assert!(parse(&["--inspect", "bash"]).is_err());
+    }
+}
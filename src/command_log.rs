@@ -0,0 +1,152 @@
+// A log of the commands run in the terminal
+//
+// Each time the foreground command changes, a line is appended to the log:
+//
+//   2024-01-02T15:04:05Z cwd=/home/user/src container=fedora cmd=vim main.rs
+//
+// container= is only there when the command is running in a container. The
+// values are quoted like shell arguments when they need to be.
+
+use crate::monitor::StateListener;
+use crate::state::ForegroundInfo;
+use crate::title::quote_arg;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub struct CommandLog {
+    file: File,
+    // The command line that was last logged
+    last_argv: Vec<String>,
+    failed: bool,
+}
+
+impl CommandLog {
+    /// Opens the log, appending to it if it already exists
+    pub fn open(path: &Path) -> io::Result<CommandLog> {
+        Ok(CommandLog {
+            file: OpenOptions::new().append(true).create(true).open(path)?,
+            last_argv: vec![],
+            failed: false,
+        })
+    }
+}
+
+impl StateListener for CommandLog {
+    /// Logs the foreground command if it's different from the last one
+    fn update(&mut self, foreground: &ForegroundInfo) {
+        if self.failed || foreground.argv().is_empty() || foreground.argv() == &self.last_argv[..] {
+            return;
+        }
+        self.last_argv = foreground.argv().to_vec();
+
+        let line = format_line(
+            SystemTime::now(),
+            foreground.cwd(),
+            foreground
+                .container_info()
+                .map(|ci| ci.container_name.as_str()),
+            foreground.argv(),
+        );
+        // A single write, so lines from several ttymon instances logging to
+        // the same file aren't mixed up
+        if let Err(e) = self.file.write_all(line.as_bytes()) {
+            warn!("Can't write to command log, stopping logging: {}", e);
+            self.failed = true;
+        }
+    }
+}
+
+fn format_line(
+    time: SystemTime,
+    cwd: &Path,
+    container_name: Option<&str>,
+    argv: &[String],
+) -> String {
+    let mut line = format_utc(time);
+    line.push_str(" cwd=");
+    line.push_str(&quote_arg(&cwd.to_string_lossy()));
+    if let Some(container_name) = container_name {
+        line.push_str(" container=");
+        line.push_str(&quote_arg(container_name));
+    }
+    line.push_str(" cmd=");
+    let args: Vec<String> = argv.iter().map(|arg| quote_arg(arg)).collect();
+    line.push_str(&args.join(" "));
+    line.push('\n');
+
+    line
+}
+
+/// Formats a time as ISO 8601 in UTC, to the second
+pub fn format_utc(time: SystemTime) -> String {
+    let secs = match time.duration_since(UNIX_EPOCH) {
+        Ok(since_epoch) => since_epoch.as_secs(),
+        Err(_) => 0,
+    };
+    let (days, secs_of_day) = (secs / 86400, secs % 86400);
+
+    // Converting days to a date in the proleptic Gregorian calendar, as in
+    // http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = days as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let day_of_era = z.rem_euclid(146097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        secs_of_day / 3600,
+        secs_of_day / 60 % 60,
+        secs_of_day % 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_format_utc() {
+        let at = |secs| format_utc(UNIX_EPOCH + Duration::from_secs(secs));
+        assert_eq!(at(0), "1970-01-01T00:00:00Z");
+        assert_eq!(at(1704207845), "2024-01-02T15:04:05Z");
+        assert_eq!(at(951782400), "2000-02-29T00:00:00Z");
+        assert_eq!(at(4102444799), "2099-12-31T23:59:59Z");
+    }
+
+    #[test]
+    fn test_format_line() {
+        let time = UNIX_EPOCH + Duration::from_secs(1704207845);
+        let argv = |args: &[&str]| args.iter().map(|arg| arg.to_string()).collect::<Vec<_>>();
+        assert_eq!(
+            format_line(
+                time,
+                Path::new("/home/user/src"),
+                None,
+                &argv(&["vim", "main.rs"])
+            ),
+            "2024-01-02T15:04:05Z cwd=/home/user/src cmd=vim main.rs\n"
+        );
+        assert_eq!(
+            format_line(
+                time,
+                Path::new("/home/user/My Documents"),
+                Some("fedora toolbox 34"),
+                &argv(&["grep", "it's", "x"])
+            ),
+            "2024-01-02T15:04:05Z cwd='/home/user/My Documents' container='fedora toolbox 34' \
+             cmd=grep 'it'\\''s' x\n"
+        );
+    }
+}
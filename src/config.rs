@@ -0,0 +1,616 @@
+// This module handles the configuration file
+//
+// Settings are read from $XDG_CONFIG_HOME/ttymon/config.toml (by default,
+// ~/.config/ttymon/config.toml), which is optional. The file is a flat list
+// of TOML keys:
+//
+//   shell = "/bin/zsh"               - run instead of $SHELL
+//   title_format = "{cwd} - {title}" - see title.rs
+//   title_script = "/path/to/rules"  - choose between formats by rules, see
+//                                      title_script.rs; needs ttymon built
+//                                      with the title-script feature
+//   rewrite_title = true             - false leaves the title to applications
+//   root_indicator = "[root]"        - shown by {rootwarn}
+//   max_title_len = 60               - shorten longer titles with an ellipsis...
+//   title_ellipsis = "middle"        - ...in the middle, rather than at the end
+//   cwd_style = "short"              - shorten the directories in {cwd}...
+//   cwd_max_components = 3           - ...and show at most this many
+//   app_title_at_prompt = false      - at the shell prompt, outside of a
+//                                      container, show the title the shell
+//                                      set as it is, if it set one
+//   wrappers = ["mybox"]             - more commands to treat like toolbox
+//   container_detection = true       - whether to look for containers at all
+//   max_scan_fds = 1024              - when looking for containers, skip
+//                                      processes with more open files; 0 for
+//                                      no limit
+//   reset_screen = false             - leave the alternate screen on startup
+//   query_title = false              - ask the terminal for its title on
+//                                      startup, to show until the
+//                                      application sets one
+//   max_output_buffer = 4194304      - bytes of output to buffer for the terminal
+//   max_read_buffer = 65536          - largest read of output from the child
+//   idle_timeout = 0                 - seconds without input before hanging up
+//   check_min_ms = 100               - how soon to check what's running after
+//                                      it may have changed...
+//   check_max_ms = 60000             - ...the longest to wait between checks...
+//   check_multiplier = 5             - ...and how fast to get there
+//   log = "/tmp/out.log"             - append all output to this file...
+//   log_timing = "/tmp/out.timing"   - ...with timing for scriptreplay(1)
+//   log_context = false              - ...and mark changes of container and
+//                                      directory in it
+//   command_log = "/tmp/cmd.log"     - log each command run, with the time
+//   events = "/tmp/events"           - write changes of state as JSON, see
+//                                      events.rs...
+//   events_fd = 3                    - ...or to this fd
+//   state_socket = true              - serve our state on a socket, see
+//                                      server.rs; can also be a path, where
+//                                      {pid} is replaced with our pid
+//   registry = false                 - register, so that ttymon
+//                                      --list-sessions shows this session,
+//                                      see registry.rs
+//
+// Environment variables override the file, with on or off (or 1 or 0, true or
+// false) for booleans: TTYMON_TITLE (for rewrite_title),
+// TTYMON_TITLE_FORMAT, TTYMON_TITLE_SCRIPT, TTYMON_ROOT_INDICATOR,
+// TTYMON_MAX_TITLE_LENGTH, TTYMON_TITLE_ELLIPSIS, TTYMON_CWD_STYLE,
+// TTYMON_CWD_MAX_COMPONENTS, TTYMON_APP_TITLE_AT_PROMPT, TTYMON_MAX_SCAN_FDS,
+// TTYMON_RESET_SCREEN, TTYMON_QUERY_TITLE, TTYMON_MAX_OUTPUT_BUFFER,
+// TTYMON_MAX_READ_BUFFER, TTYMON_IDLE_TIMEOUT, TTYMON_CHECK_MIN_MS,
+// TTYMON_CHECK_MAX_MS, TTYMON_CHECK_MULT, TTYMON_LOG, TTYMON_LOG_CONTEXT,
+// TTYMON_LOG_TIMING, TTYMON_COMMAND_LOG, TTYMON_EVENTS, TTYMON_EVENTS_FD,
+// TTYMON_STATE_SOCKET (a path), and TTYMON_REGISTRY.
+
+use crate::pty::{DEFAULT_MAX_CHECK_INTERVAL, DEFAULT_MIN_CHECK_INTERVAL};
+use crate::server;
+use crate::title::{CwdStyle, Ellipsis};
+use serde::de::Error;
+use serde::{Deserialize, Deserializer};
+use std::convert::TryFrom;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::time::Duration;
+
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct Config {
+    pub shell: Option<PathBuf>,
+    pub title_format: Option<String>,
+    pub title_script: Option<PathBuf>,
+    pub rewrite_title: bool,
+    pub root_indicator: Option<String>,
+    #[serde(rename = "max_title_len", deserialize_with = "limit")]
+    pub max_title_length: Option<usize>,
+    #[serde(deserialize_with = "ellipsis")]
+    pub title_ellipsis: Ellipsis,
+    #[serde(deserialize_with = "cwd_style")]
+    pub cwd_style: CwdStyle,
+    #[serde(deserialize_with = "limit")]
+    pub cwd_max_components: Option<usize>,
+    pub app_title_at_prompt: bool,
+    pub wrappers: Vec<String>,
+    pub container_detection: bool,
+    #[serde(deserialize_with = "non_negative")]
+    pub max_scan_fds: Option<usize>,
+    pub reset_screen: bool,
+    pub query_title: bool,
+    #[serde(deserialize_with = "positive")]
+    pub max_output_buffer: Option<usize>,
+    #[serde(deserialize_with = "positive")]
+    pub max_read_buffer: Option<usize>,
+    #[serde(deserialize_with = "seconds")]
+    pub idle_timeout: Option<Duration>,
+    #[serde(rename = "check_min_ms", deserialize_with = "millis")]
+    pub check_min: Option<Duration>,
+    #[serde(rename = "check_max_ms", deserialize_with = "millis")]
+    pub check_max: Option<Duration>,
+    #[serde(deserialize_with = "positive")]
+    pub check_multiplier: Option<u32>,
+    pub log: Option<PathBuf>,
+    pub log_timing: Option<PathBuf>,
+    pub log_context: bool,
+    pub command_log: Option<PathBuf>,
+    pub events: Option<PathBuf>,
+    #[serde(deserialize_with = "non_negative")]
+    pub events_fd: Option<i32>,
+    #[serde(deserialize_with = "state_socket")]
+    pub state_socket: Option<PathBuf>,
+    pub registry: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            shell: None,
+            title_format: None,
+            title_script: None,
+            rewrite_title: true,
+            root_indicator: None,
+            max_title_length: None,
+            title_ellipsis: Ellipsis::End,
+            cwd_style: CwdStyle::Full,
+            cwd_max_components: None,
+            app_title_at_prompt: false,
+            wrappers: vec![],
+            container_detection: true,
+            max_scan_fds: None,
+            reset_screen: false,
+            query_title: false,
+            max_output_buffer: None,
+            max_read_buffer: None,
+            idle_timeout: None,
+            check_min: None,
+            check_max: None,
+            check_multiplier: None,
+            log: None,
+            log_timing: None,
+            log_context: false,
+            command_log: None,
+            events: None,
+            events_fd: None,
+            state_socket: None,
+            registry: false,
+        }
+    }
+}
+
+// Helpers for #[serde(deserialize_with)], for settings that need more than
+// the type of the field to read them
+
+// An integer setting, which has to be at least min and fit in T
+fn integer<'de, D, T>(deserializer: D, min: i64) -> Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: TryFrom<i64>,
+{
+    let value = i64::deserialize(deserializer)?;
+    if value < min {
+        return Err(D::Error::custom(format!("must be at least {}", min)));
+    }
+    T::try_from(value).map_err(|_| D::Error::custom("is too large"))
+}
+
+fn positive<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: TryFrom<i64>,
+{
+    integer(deserializer, 1).map(Some)
+}
+
+fn non_negative<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: TryFrom<i64>,
+{
+    integer(deserializer, 0).map(Some)
+}
+
+// 0 means no limit
+fn limit<'de, D>(deserializer: D) -> Result<Option<usize>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let value: usize = integer(deserializer, 0)?;
+    Ok(Some(value).filter(|&v| v > 0))
+}
+
+// 0 means never
+fn seconds<'de, D>(deserializer: D) -> Result<Option<Duration>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let value = integer(deserializer, 0)?;
+    Ok(Some(Duration::from_secs(value)).filter(|_| value > 0))
+}
+
+fn millis<'de, D>(deserializer: D) -> Result<Option<Duration>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    integer(deserializer, 1).map(|v| Some(Duration::from_millis(v)))
+}
+
+fn ellipsis<'de, D>(deserializer: D) -> Result<Ellipsis, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let name = String::deserialize(deserializer)?;
+    Ellipsis::from_name(&name)
+        .ok_or_else(|| D::Error::custom(format!("unknown ellipsis `{}`", name)))
+}
+
+fn cwd_style<'de, D>(deserializer: D) -> Result<CwdStyle, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let name = String::deserialize(deserializer)?;
+    CwdStyle::from_name(&name).ok_or_else(|| D::Error::custom(format!("unknown style `{}`", name)))
+}
+
+// state_socket is either true, for the default path, or a path
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum StateSocket {
+    Enabled(bool),
+    Path(String),
+}
+
+fn state_socket<'de, D>(deserializer: D) -> Result<Option<PathBuf>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Ok(match StateSocket::deserialize(deserializer)? {
+        StateSocket::Enabled(enabled) => Some(server::default_path()).filter(|_| enabled),
+        StateSocket::Path(template) => Some(socket_path(&template)),
+    })
+}
+
+impl Config {
+    /// Reads the configuration file, if there is one, then applies
+    /// environment variable overrides. Problems with the file are logged,
+    /// and the defaults used instead.
+    pub fn load() -> Config {
+        let mut config = match config_path() {
+            Some(path) => match fs::read_to_string(&path) {
+                Ok(text) => match Config::parse(&text) {
+                    Ok(config) => config,
+                    Err(e) => {
+                        warn!("{}: {}", path.display(), e);
+                        Config::default()
+                    }
+                },
+                Err(e) if e.kind() == io::ErrorKind::NotFound => Config::default(),
+                Err(e) => {
+                    warn!("Can't read {}: {}", path.display(), e);
+                    Config::default()
+                }
+            },
+            None => Config::default(),
+        };
+        config.apply_env(|name| std::env::var(name).ok());
+        config.check_intervals();
+
+        config
+    }
+
+    // The minimum check interval can only be checked against the maximum once
+    // both are known, from either the file or the environment
+    fn check_intervals(&mut self) {
+        let check_min = self.check_min.unwrap_or(DEFAULT_MIN_CHECK_INTERVAL);
+        let check_max = self.check_max.unwrap_or(DEFAULT_MAX_CHECK_INTERVAL);
+        if check_min > check_max {
+            warn!(
+                "Minimum check interval {}ms is more than the maximum {}ms, using the defaults",
+                check_min.as_millis(),
+                check_max.as_millis()
+            );
+            self.check_min = None;
+            self.check_max = None;
+        }
+    }
+
+    fn parse(text: &str) -> Result<Config, toml::de::Error> {
+        toml::from_str(text)
+    }
+
+    fn apply_env<F>(&mut self, getenv: F)
+    where
+        F: Fn(&str) -> Option<String>,
+    {
+        let boolean = |name| getenv(name).as_deref().and_then(parse_bool);
+        if let Some(rewrite_title) = boolean("TTYMON_TITLE") {
+            self.rewrite_title = rewrite_title;
+        }
+        if let Some(format) = getenv("TTYMON_TITLE_FORMAT") {
+            self.title_format = Some(format);
+        }
+        if let Some(title_script) = getenv("TTYMON_TITLE_SCRIPT").filter(|v| !v.is_empty()) {
+            self.title_script = Some(PathBuf::from(title_script));
+        }
+        if let Some(root_indicator) = getenv("TTYMON_ROOT_INDICATOR") {
+            self.root_indicator = Some(root_indicator);
+        }
+        if let Some(max_title_length) =
+            getenv("TTYMON_MAX_TITLE_LENGTH").and_then(|v| v.parse::<usize>().ok())
+        {
+            self.max_title_length = Some(max_title_length).filter(|v| *v > 0);
+        }
+        if let Some(ellipsis) =
+            getenv("TTYMON_TITLE_ELLIPSIS").and_then(|v| Ellipsis::from_name(&v))
+        {
+            self.title_ellipsis = ellipsis;
+        }
+        if let Some(cwd_style) = getenv("TTYMON_CWD_STYLE").and_then(|v| CwdStyle::from_name(&v)) {
+            self.cwd_style = cwd_style;
+        }
+        if let Some(cwd_max_components) =
+            getenv("TTYMON_CWD_MAX_COMPONENTS").and_then(|v| v.parse::<usize>().ok())
+        {
+            self.cwd_max_components = Some(cwd_max_components).filter(|v| *v > 0);
+        }
+        if let Some(app_title_at_prompt) = boolean("TTYMON_APP_TITLE_AT_PROMPT") {
+            self.app_title_at_prompt = app_title_at_prompt;
+        }
+        if let Some(max_scan_fds) = getenv("TTYMON_MAX_SCAN_FDS").and_then(|v| v.parse().ok()) {
+            self.max_scan_fds = Some(max_scan_fds);
+        }
+        if let Some(reset_screen) = boolean("TTYMON_RESET_SCREEN") {
+            self.reset_screen = reset_screen;
+        }
+        if let Some(query_title) = boolean("TTYMON_QUERY_TITLE") {
+            self.query_title = query_title;
+        }
+        if let Some(max_output_buffer) = getenv("TTYMON_MAX_OUTPUT_BUFFER")
+            .and_then(|v| v.parse().ok())
+            .filter(|&v| v > 0)
+        {
+            self.max_output_buffer = Some(max_output_buffer);
+        }
+        if let Some(max_read_buffer) = getenv("TTYMON_MAX_READ_BUFFER")
+            .and_then(|v| v.parse().ok())
+            .filter(|&v| v > 0)
+        {
+            self.max_read_buffer = Some(max_read_buffer);
+        }
+        if let Some(idle_timeout) = getenv("TTYMON_IDLE_TIMEOUT").and_then(|v| v.parse().ok()) {
+            self.idle_timeout =
+                Some(Duration::from_secs(idle_timeout)).filter(|_| idle_timeout > 0);
+        }
+        let millis = |name| {
+            getenv(name)
+                .and_then(|v| v.parse().ok())
+                .filter(|&v| v > 0)
+                .map(Duration::from_millis)
+        };
+        if let Some(check_min) = millis("TTYMON_CHECK_MIN_MS") {
+            self.check_min = Some(check_min);
+        }
+        if let Some(check_max) = millis("TTYMON_CHECK_MAX_MS") {
+            self.check_max = Some(check_max);
+        }
+        if let Some(check_multiplier) = getenv("TTYMON_CHECK_MULT")
+            .and_then(|v| v.parse().ok())
+            .filter(|&v| v >= 1)
+        {
+            self.check_multiplier = Some(check_multiplier);
+        }
+        if let Some(log) = getenv("TTYMON_LOG").filter(|v| !v.is_empty()) {
+            self.log = Some(PathBuf::from(log));
+        }
+        if let Some(log_context) = boolean("TTYMON_LOG_CONTEXT") {
+            self.log_context = log_context;
+        }
+        if let Some(log_timing) = getenv("TTYMON_LOG_TIMING").filter(|v| !v.is_empty()) {
+            self.log_timing = Some(PathBuf::from(log_timing));
+        }
+        if let Some(command_log) = getenv("TTYMON_COMMAND_LOG").filter(|v| !v.is_empty()) {
+            self.command_log = Some(PathBuf::from(command_log));
+        }
+        if let Some(events) = getenv("TTYMON_EVENTS").filter(|v| !v.is_empty()) {
+            self.events = Some(PathBuf::from(events));
+        }
+        if let Some(events_fd) = getenv("TTYMON_EVENTS_FD")
+            .and_then(|v| v.parse().ok())
+            .filter(|&v| v >= 0)
+        {
+            self.events_fd = Some(events_fd);
+        }
+        if let Some(state_socket) = getenv("TTYMON_STATE_SOCKET").filter(|v| !v.is_empty()) {
+            self.state_socket = Some(socket_path(&state_socket));
+        }
+        if let Some(registry) = boolean("TTYMON_REGISTRY") {
+            self.registry = registry;
+        }
+    }
+}
+
+// Boolean environment variables; anything else leaves the setting as it is
+fn parse_bool(value: &str) -> Option<bool> {
+    match value {
+        "on" | "1" | "true" => Some(true),
+        "off" | "0" | "false" => Some(false),
+        _ => None,
+    }
+}
+
+fn socket_path(template: &str) -> PathBuf {
+    PathBuf::from(template.replace("{pid}", &std::process::id().to_string()))
+}
+
+fn config_path() -> Option<PathBuf> {
+    let config_dir = std::env::var_os("XDG_CONFIG_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| dirs::home_dir().map(|home| home.join(".config")))?;
+
+    Some(config_dir.join("ttymon").join("config.toml"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let config = Config::parse(
+            r#"
+# Comments and blank lines are skipped
+shell = "/bin/zsh"
+title_format = "{cwd} \"{title}\"" # A comment after a value
+root_indicator = '[\root]'
+max_title_len = 60
+title_ellipsis = "middle"
+cwd_style = "short"
+cwd_max_components = 3
+app_title_at_prompt = true
+wrappers = ["mybox", 'otherbox',]
+container_detection = false
+max_scan_fds = 0
+query_title = true
+idle_timeout = 1_800
+check_max_ms = 10_000
+max_read_buffer = 16_384
+log = "/tmp/ttymon.log"
+log_context = true
+events_fd = 3
+state_socket = "/tmp/state"
+something_new = 1
+"#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            config,
+            Config {
+                shell: Some(PathBuf::from("/bin/zsh")),
+                title_format: Some(String::from("{cwd} \"{title}\"")),
+                rewrite_title: true,
+                root_indicator: Some(String::from("[\\root]")),
+                max_title_length: Some(60),
+                title_ellipsis: Ellipsis::Middle,
+                cwd_style: CwdStyle::Short,
+                cwd_max_components: Some(3),
+                app_title_at_prompt: true,
+                wrappers: vec![String::from("mybox"), String::from("otherbox")],
+                container_detection: false,
+                max_scan_fds: Some(0),
+                query_title: true,
+                idle_timeout: Some(Duration::from_secs(1800)),
+                check_max: Some(Duration::from_secs(10)),
+                max_read_buffer: Some(16384),
+                log: Some(PathBuf::from("/tmp/ttymon.log")),
+                log_context: true,
+                events_fd: Some(3),
+                state_socket: Some(PathBuf::from("/tmp/state")),
+                ..Config::default()
+            }
+        );
+
+        assert_eq!(Config::parse("").unwrap(), Config::default());
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let error = |text| Config::parse(text).unwrap_err().to_string();
+
+        assert!(error("shell = 1").starts_with("invalid type: integer `1`"));
+        assert!(error("wrappers = [1]").starts_with("invalid type: integer `1`"));
+        assert!(error("title_ellipsis = 'left'")
+            .starts_with("unknown ellipsis `left` for key `title_ellipsis`"));
+        assert!(error("cwd_style = 'fish'").starts_with("unknown style `fish` for key `cwd_style`"));
+        assert!(error("state_socket = 1").starts_with("data did not match any variant"));
+        assert!(error("\nshell").ends_with("at line 2 column 6"));
+        assert!(error("reset_screen = yes").contains("at line 1"));
+    }
+
+    #[test]
+    fn test_parse_out_of_range() {
+        let error = |text| Config::parse(text).unwrap_err().to_string();
+
+        assert!(error("check_multiplier = 0")
+            .starts_with("must be at least 1 for key `check_multiplier`"));
+        assert!(error("check_multiplier = 5_000_000_000")
+            .starts_with("is too large for key `check_multiplier`"));
+        assert!(error("check_min_ms = 0").starts_with("must be at least 1 for key `check_min_ms`"));
+        assert!(error("check_max_ms = -1").starts_with("must be at least 1 for key `check_max_ms`"));
+        assert!(error("idle_timeout = -1").starts_with("must be at least 0 for key `idle_timeout`"));
+        assert!(error("events_fd = -1").starts_with("must be at least 0 for key `events_fd`"));
+        assert!(error("max_read_buffer = 0")
+            .starts_with("must be at least 1 for key `max_read_buffer`"));
+    }
+
+    #[test]
+    fn test_check_intervals() {
+        let mut config = Config::parse("check_min_ms = 2000\ncheck_max_ms = 1000").unwrap();
+        config.check_intervals();
+        assert_eq!(config.check_min, None);
+        assert_eq!(config.check_max, None);
+
+        // Against the default maximum
+        let mut config = Config::parse("check_min_ms = 120_000").unwrap();
+        config.check_intervals();
+        assert_eq!(config.check_min, None);
+
+        let mut config = Config::parse("check_min_ms = 1000\ncheck_multiplier = 1").unwrap();
+        config.check_intervals();
+        assert_eq!(config.check_min, Some(Duration::from_secs(1)));
+        assert_eq!(config.check_multiplier, Some(1));
+    }
+
+    #[test]
+    fn test_env() {
+        let mut config =
+            Config::parse("title_format = '{cwd}'\nidle_timeout = 60\nmax_output_buffer = 8192")
+                .unwrap();
+        config.apply_env(|name| match name {
+            "TTYMON_TITLE_FORMAT" => Some(String::from("{command}")),
+            "TTYMON_TITLE_SCRIPT" => Some(String::from("/tmp/title-rules")),
+            "TTYMON_IDLE_TIMEOUT" => Some(String::from("0")),
+            "TTYMON_RESET_SCREEN" => Some(String::from("on")),
+            "TTYMON_QUERY_TITLE" => Some(String::from("1")),
+            "TTYMON_CWD_STYLE" => Some(String::from("short")),
+            "TTYMON_CWD_MAX_COMPONENTS" => Some(String::from("2")),
+            "TTYMON_CHECK_MIN_MS" => Some(String::from("50")),
+            "TTYMON_CHECK_MULT" => Some(String::from("0")),
+            "TTYMON_MAX_READ_BUFFER" => Some(String::from("0")),
+            "TTYMON_MAX_OUTPUT_BUFFER" => Some(String::from("0")),
+            "TTYMON_MAX_SCAN_FDS" => Some(String::from("256")),
+            "TTYMON_TITLE" => Some(String::from("off")),
+            "TTYMON_LOG_TIMING" => Some(String::from("/tmp/timing")),
+            "TTYMON_LOG_CONTEXT" => Some(String::from("true")),
+            "TTYMON_STATE_SOCKET" => Some(String::from("/tmp/state-{pid}")),
+            "TTYMON_REGISTRY" => Some(String::from("1")),
+            _ => None,
+        });
+
+        assert_eq!(config.title_format.as_deref(), Some("{command}"));
+        assert_eq!(config.title_script, Some(PathBuf::from("/tmp/title-rules")));
+        assert_eq!(config.idle_timeout, None);
+        assert!(config.reset_screen);
+        assert!(config.query_title);
+        assert_eq!(config.cwd_style, CwdStyle::Short);
+        assert_eq!(config.cwd_max_components, Some(2));
+        assert_eq!(config.check_min, Some(Duration::from_millis(50)));
+        assert_eq!(config.check_multiplier, None);
+        assert_eq!(config.max_read_buffer, None);
+        assert_eq!(config.max_output_buffer, Some(8192));
+        assert_eq!(config.max_scan_fds, Some(256));
+        assert!(!config.rewrite_title);
+        assert_eq!(config.log_timing, Some(PathBuf::from("/tmp/timing")));
+        assert!(config.log_context);
+        assert!(config.registry);
+        assert_eq!(
+            config.state_socket,
+            Some(PathBuf::from(format!("/tmp/state-{}", std::process::id())))
+        );
+    }
+
+    #[test]
+    fn test_env_booleans() {
+        let mut config = Config::parse(
+            "reset_screen = true
+query_title = true",
+        )
+        .unwrap();
+        config.apply_env(|name| match name {
+            "TTYMON_RESET_SCREEN" => Some(String::from("0")),
+            "TTYMON_QUERY_TITLE" => Some(String::from("off")),
+            "TTYMON_LOG_CONTEXT" => Some(String::from("false")),
+            "TTYMON_TITLE" => Some(String::from("1")),
+            // Neither on nor off, so ignored
+            "TTYMON_APP_TITLE_AT_PROMPT" => Some(String::new()),
+            "TTYMON_REGISTRY" => Some(String::from("yes")),
+            "TTYMON_EVENTS_FD" => Some(String::from("-1")),
+            _ => None,
+        });
+
+        assert!(!config.reset_screen);
+        assert!(!config.query_title);
+        assert!(!config.log_context);
+        assert!(config.rewrite_title);
+        assert!(!config.app_title_at_prompt);
+        assert!(!config.registry);
+        assert_eq!(config.events_fd, None);
+    }
+}
@@ -0,0 +1,197 @@
+// Reporting changes of state, for scripts
+//
+// Each time something we track changes, a line of JSON is written to a file,
+// or to an fd that ttymon was started with (never the terminal's stdin or
+// stdout, since that would mix it into the session):
+//
+//   {"event":"container","container":"fedora-toolbox-34"}
+//   {"event":"cwd","cwd":"/home/user/src"}
+//   {"event":"foreground","argv0":"vim","argv":["vim","main.rs"],"cwd":"/home/user/src"}
+//
+// "container" is null when leaving a container.
+
+use crate::monitor::StateListener;
+use crate::state::ForegroundInfo;
+use nix::fcntl::{fcntl, FcntlArg, FdFlag};
+use serde::Serialize;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::io::{FromRawFd, RawFd};
+use std::path::{Path, PathBuf};
+
+// The parts of ForegroundInfo that we report changes of
+#[derive(Default)]
+struct Tracked {
+    container: Option<String>,
+    cwd: PathBuf,
+    argv0: String,
+    argv: Vec<String>,
+}
+
+impl Tracked {
+    fn from_foreground(foreground: &ForegroundInfo) -> Tracked {
+        Tracked {
+            container: foreground
+                .container_info()
+                .map(|ci| ci.container_name.clone()),
+            cwd: foreground.cwd().to_path_buf(),
+            argv0: foreground.argv0().to_string(),
+            argv: foreground.argv().to_vec(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+#[serde(tag = "event", rename_all = "lowercase")]
+enum Event<'a> {
+    Container {
+        container: Option<&'a str>,
+    },
+    Cwd {
+        cwd: &'a str,
+    },
+    Foreground {
+        argv0: &'a str,
+        argv: &'a [String],
+        cwd: &'a str,
+    },
+}
+
+pub struct EventLog {
+    out: File,
+    last: Tracked,
+    failed: bool,
+}
+
+impl EventLog {
+    /// Opens a file to write events to, appending if it already exists
+    pub fn open(path: &Path) -> io::Result<EventLog> {
+        Ok(EventLog::new(
+            OpenOptions::new().append(true).create(true).open(path)?,
+        ))
+    }
+
+    /// Writes events to an fd that we were started with, which is taken over
+    /// by the EventLog
+    pub fn from_fd(fd: RawFd) -> io::Result<EventLog> {
+        if fd == 0 || fd == 1 {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "Events can't be written to stdin or stdout",
+            ));
+        }
+        // Check that it's open, and don't pass it on to the child
+        fcntl(fd, FcntlArg::F_SETFD(FdFlag::FD_CLOEXEC))
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+
+        Ok(EventLog::new(unsafe { File::from_raw_fd(fd) }))
+    }
+
+    fn new(out: File) -> EventLog {
+        EventLog {
+            out,
+            last: Tracked::default(),
+            failed: false,
+        }
+    }
+}
+
+impl StateListener for EventLog {
+    fn update(&mut self, foreground: &ForegroundInfo) {
+        let tracked = Tracked::from_foreground(foreground);
+        let events = events(&self.last, &tracked);
+        self.last = tracked;
+        if self.failed || events.is_empty() {
+            return;
+        }
+
+        if let Err(e) = self.out.write_all(events.concat().as_bytes()) {
+            warn!("Can't write events, stopping: {}", e);
+            self.failed = true;
+        }
+    }
+}
+
+// The lines to write for the changes from old to new
+fn events(old: &Tracked, new: &Tracked) -> Vec<String> {
+    let mut result = vec![];
+
+    if new.container != old.container {
+        result.push(Event::Container {
+            container: new.container.as_deref(),
+        });
+    }
+
+    let cwd = new.cwd.to_string_lossy();
+    if new.cwd != old.cwd {
+        result.push(Event::Cwd { cwd: &cwd });
+    }
+
+    // When nothing is running (yet), there's nothing to report
+    if new.argv != old.argv && !new.argv.is_empty() {
+        result.push(Event::Foreground {
+            argv0: &new.argv0,
+            argv: &new.argv,
+            cwd: &cwd,
+        });
+    }
+
+    result
+        .iter()
+        .map(|event| {
+            // Nothing in an event can fail to serialize
+            let mut line = serde_json::to_string(event).unwrap();
+            line.push('\n');
+            line
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tracked(container: Option<&str>, cwd: &str, argv: &[&str]) -> Tracked {
+        Tracked {
+            container: container.map(String::from),
+            cwd: PathBuf::from(cwd),
+            argv0: argv.first().copied().unwrap_or("").to_string(),
+            argv: argv.iter().map(|arg| arg.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn test_events() {
+        let start = Tracked::default();
+        let bash = tracked(None, "/home/user", &["-bash"]);
+        assert_eq!(
+            events(&start, &bash),
+            vec![
+                "{\"event\":\"cwd\",\"cwd\":\"/home/user\"}\n",
+                "{\"event\":\"foreground\",\"argv0\":\"-bash\",\"argv\":[\"-bash\"],\
+                 \"cwd\":\"/home/user\"}\n",
+            ]
+        );
+        assert_eq!(events(&bash, &bash), Vec::<String>::new());
+
+        let toolbox = tracked(Some("fedora"), "/home/user", &["bash"]);
+        assert_eq!(
+            events(&bash, &toolbox),
+            vec![
+                "{\"event\":\"container\",\"container\":\"fedora\"}\n",
+                "{\"event\":\"foreground\",\"argv0\":\"bash\",\"argv\":[\"bash\"],\
+                 \"cwd\":\"/home/user\"}\n",
+            ]
+        );
+        assert_eq!(
+            events(&toolbox, &bash)[0],
+            "{\"event\":\"container\",\"container\":null}\n"
+        );
+
+        let gone = tracked(None, "", &[]);
+        assert_eq!(
+            events(&bash, &gone),
+            vec!["{\"event\":\"cwd\",\"cwd\":\"\"}\n"]
+        );
+    }
+}
@@ -1,8 +1,16 @@
+use crate::terminfo::Terminfo;
+use crate::uri::decode_file_uri;
+use std::path::{Path, PathBuf};
 use vte::{Params, Parser, Perform};
 
 pub struct Filter {
     parser: Parser,
     state: FilterState,
+    // Continuation bytes left in the current UTF-8 character, which aren't
+    // C1 controls even when they are in the range 0x80-0x9f
+    utf8_remaining: u8,
+    // The last byte passed to the parser was ESC
+    after_esc: bool,
 }
 
 const BEL: u8 = 0x7;
@@ -12,74 +20,424 @@ const CSI: [u8; 2] = [ESC, b'['];
 const OSC: [u8; 2] = [ESC, b']'];
 const ST: [u8; 2] = [ESC, b'\\'];
 
+/// What a title sets, as distinguished by the xterm OSC number
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TitleKind {
+    /// OSC 0 - both the icon name and the window title
+    IconNameAndWindowTitle,
+    /// OSC 1
+    IconName,
+    /// OSC 2
+    WindowTitle,
+}
+
+impl TitleKind {
+    fn from_osc(osc: &[u8]) -> Option<TitleKind> {
+        match osc {
+            b"0" => Some(TitleKind::IconNameAndWindowTitle),
+            b"1" => Some(TitleKind::IconName),
+            b"2" => Some(TitleKind::WindowTitle),
+            _ => None,
+        }
+    }
+
+    fn osc(self) -> u8 {
+        match self {
+            TitleKind::IconNameAndWindowTitle => b'0',
+            TitleKind::IconName => b'1',
+            TitleKind::WindowTitle => b'2',
+        }
+    }
+}
+
+/// How the composed title is sent to the terminal. bell_terminated is whether
+/// the application's last OSC sequence ended with BEL rather than ST, for
+/// sinks that can match it.
+pub trait TitleSink {
+    fn write_title(&self, kind: TitleKind, title: &str, bell_terminated: bool, out: &mut Vec<u8>);
+}
+
+/// The xterm title sequences, OSC 0/1/2 ; <title> ST, understood by nearly
+/// all terminal emulators
+pub struct OscTitleSink;
+
+impl TitleSink for OscTitleSink {
+    fn write_title(&self, kind: TitleKind, title: &str, bell_terminated: bool, out: &mut Vec<u8>) {
+        out.extend_from_slice(&OSC);
+        out.push(kind.osc());
+        out.push(b';');
+        out.extend_from_slice(title.as_bytes());
+        if bell_terminated {
+            out.push(BEL);
+        } else {
+            out.extend_from_slice(&ST);
+        }
+    }
+}
+
+/// The status line of a hardware terminal (like the DEC VT320 and later),
+/// using the sequences from terminfo
+pub struct StatusLineTitleSink {
+    to_status_line: Vec<u8>,
+    clear_to_eol: Vec<u8>,
+    from_status_line: Vec<u8>,
+}
+
+impl StatusLineTitleSink {
+    /// Returns None unless the terminal has a status line. xterm-family
+    /// terminfo entries describe the title as a status line reached with
+    /// OSC 0, which is better left to OscTitleSink.
+    pub fn from_terminfo(terminfo: &Terminfo) -> Option<StatusLineTitleSink> {
+        let to_status_line = terminfo.tsl(0)?;
+        let from_status_line = terminfo.fsl()?;
+        if to_status_line.starts_with(&OSC) {
+            return None;
+        }
+
+        Some(StatusLineTitleSink {
+            to_status_line,
+            clear_to_eol: terminfo.el().unwrap_or_default(),
+            from_status_line,
+        })
+    }
+}
+
+impl TitleSink for StatusLineTitleSink {
+    fn write_title(&self, kind: TitleKind, title: &str, _: bool, out: &mut Vec<u8>) {
+        // There's nowhere to show an icon name
+        if kind == TitleKind::IconName {
+            return;
+        }
+        out.extend_from_slice(&self.to_status_line);
+        out.extend_from_slice(title.as_bytes());
+        out.extend_from_slice(&self.clear_to_eol);
+        out.extend_from_slice(&self.from_status_line);
+    }
+}
+
+// Shell integration: the shell can report information that only it knows
+// by sending OSC 777 ; ttymon ; <key> ; <value> ST. (OSC 777 is the urxvt
+// extension OSC, which terminals ignore unknown subcommands of, so it's
+// harmless if ttymon isn't running.) These sequences are always consumed.
+//
+// Supported keys:
+//
+//   jobs ; <count>   - the number of jobs in the shell's job table
+//
+// For bash, for example:
+//
+//   PROMPT_COMMAND='printf "\e]777;ttymon;jobs;%s\e\\" $(jobs -p | wc -l)'
+//
+const TTYMON_OSC: &[u8] = b"777";
+const TTYMON_OSC_COMMAND: &[u8] = b"ttymon";
+
 impl Filter {
     pub fn new() -> Filter {
         Filter {
             parser: Parser::new(),
             state: FilterState::new(),
+            utf8_remaining: 0,
+            after_esc: false,
         }
     }
 
     pub fn fill(&mut self, buffer: &[u8]) {
-        for c in buffer {
-            self.parser.advance(&mut self.state, *c);
+        for &c in buffer {
+            if self.utf8_remaining > 0 && (0x80..=0xbf).contains(&c) {
+                self.utf8_remaining -= 1;
+            } else {
+                self.utf8_remaining = match c {
+                    0xc2..=0xdf => 1,
+                    0xe0..=0xef => 2,
+                    0xf0..=0xf4 => 3,
+                    _ => 0,
+                };
+
+                // The parser is UTF-8 based, and drops 8-bit C1 sequence
+                // introducers (CSI as 0x9b, and so on), so feed it the 7-bit
+                // ESC form instead; what we pass on is then 7-bit too.
+                if (0x80..=0x9f).contains(&c) {
+                    self.advance(ESC);
+                    self.advance(c - 0x40);
+                    continue;
+                }
+            }
+
+            self.advance(c);
         }
     }
 
-    #[allow(dead_code)]
-    pub fn current_directory(&self) -> &str {
-        &self.state.current_directory
+    // The parameters vte gives us for a CSI sequence (or the start of a DCS
+    // sequence) lose some details - an empty parameter can't be told from 0,
+    // for example - so we keep the raw bytes of the sequence, to pass it on
+    // exactly as it was.
+    fn advance(&mut self, c: u8) {
+        // vte ignores SOS, PM and APC strings (ESC X, ESC ^ and ESC _ ...
+        // ST), so we pass them on ourselves
+        if self.state.string == Some(StringKind::SosPmApc) {
+            match c {
+                // ESC starts the ST; CAN and SUB cancel the string
+                0x18 | 0x1a | ESC => {
+                    if c == ESC {
+                        self.state.append_many(&ST);
+                        self.state.string_terminated = true;
+                    }
+                    self.state.end_string();
+                }
+                _ => self.state.append(c),
+            }
+        } else if self.after_esc && (c == b'X' || c == b'^' || c == b'_') {
+            self.state.string = Some(StringKind::SosPmApc);
+            self.state.append_many(&[ESC, c]);
+        }
+
+        let mut sequence_done = false;
+        match &mut self.state.sequence_raw {
+            None => {
+                if self.after_esc && (c == b'[' || c == b'P') {
+                    self.state.sequence_raw = Some(vec![ESC, c]);
+                }
+            }
+            Some(raw) => match c {
+                // CAN and SUB cancel the sequence; ESC starts a new one
+                0x18 | 0x1a | ESC => self.state.sequence_raw = None,
+                // Parameters, intermediates and private markers
+                0x20..=0x3f => raw.push(c),
+                // The final byte; for DCS, the data follows
+                0x40..=0x7e => {
+                    raw.push(c);
+                    sequence_done = true;
+                }
+                // Other controls are executed as they come, and are passed on
+                // then; anything else is ignored
+                _ => {}
+            },
+        }
+        self.after_esc = c == ESC;
+
+        self.parser.advance(&mut self.state, c);
+
+        // If the sequence was malformed, it's dropped without a dispatch
+        if sequence_done {
+            self.state.sequence_raw = None;
+        }
+    }
+
+    /// The working directory the shell reported via OSC 7, if it has
+    pub fn current_directory(&self) -> Option<&Path> {
+        self.state.current_directory.as_deref()
     }
 
     pub fn in_window_title(&self) -> &str {
         &self.state.in_window_title
     }
 
+    /// Whether in_window_title() is a title the application set, rather than
+    /// our default
+    pub fn in_window_title_set(&self) -> bool {
+        self.state.in_window_title_set
+    }
+
+    /// Returns true if the application has set a new window title since the
+    /// last call; the caller should recompose and re-inject its own title.
+    pub fn take_in_window_title_changed(&mut self) -> bool {
+        std::mem::replace(&mut self.state.in_window_title_changed, false)
+    }
+
+    /// The target of the hyperlink the application most recently opened with
+    /// OSC 8; closing the link doesn't clear it.
+    #[allow(dead_code)]
+    pub fn current_hyperlink(&self) -> Option<&str> {
+        self.state.current_hyperlink.as_deref()
+    }
+
+    /// The number of jobs the shell reported via the ttymon OSC, if it has
+    pub fn shell_jobs(&self) -> Option<u32> {
+        self.state.shell_jobs
+    }
+
+    /// Whether the application has rung the bell since the title was last
+    /// composed; a BEL ending an OSC sequence doesn't count.
+    pub fn bell_pending(&self) -> bool {
+        self.state.bell_pending
+    }
+
+    pub fn clear_bell_pending(&mut self) {
+        self.state.bell_pending = false;
+    }
+
+    /// Returns true if the bell has newly become pending since the last call;
+    /// the caller should recompose its title, so {bell} shows promptly.
+    pub fn take_bell_changed(&mut self) -> bool {
+        std::mem::replace(&mut self.state.bell_changed, false)
+    }
+
+    /// Whether the application has switched the terminal to the alternate screen
+    /// (and not yet switched back.)
+    pub fn alt_screen(&self) -> bool {
+        self.state.alt_screen
+    }
+
     pub fn set_out_window_title(&mut self, title: &str) {
         self.state.set_out_window_title(title);
     }
 
+    /// Takes title as the application's title until it sets its own, like
+    /// the title the terminal had before we started
+    pub fn set_initial_window_title(&mut self, title: &str) {
+        self.state.in_window_title = sanitize_title(title);
+        self.state.in_window_title_set = true;
+    }
+
+    /// Replaces our composed title with the application's own title, or an
+    /// empty one if it never set one, for when we stop tracking the session
+    pub fn restore_window_title(&mut self) {
+        let title = if self.state.in_window_title_set {
+            self.state.in_window_title.clone()
+        } else {
+            String::new()
+        };
+        self.state.out_window_title = title;
+        self.state.append_out_window_title();
+    }
+
+    /// Whether we replace the application's titles with our composed title.
+    /// If not, titles are still tracked, but pass through untouched.
+    pub fn set_rewrite_titles(&mut self, rewrite_titles: bool) {
+        self.state.rewrite_titles = rewrite_titles;
+    }
+
+    pub fn rewrite_titles(&self) -> bool {
+        self.state.rewrite_titles
+    }
+
+    pub fn set_title_sink(&mut self, title_sink: Box<dyn TitleSink>) {
+        self.state.title_sink = title_sink;
+    }
+
     pub fn buffer(&self) -> &[u8] {
         return &self.state.buffer;
     }
 
+    #[allow(dead_code)]
     pub fn clear_buffer(&mut self) {
         self.state.buffer.clear();
     }
+
+    /// Removes the first count bytes of the buffer, after they've been written out
+    pub fn consume_buffer(&mut self, count: usize) {
+        self.state.buffer.drain(..count);
+    }
+}
+
+// Remove control characters (C0, DEL, and C1) from a title - otherwise a title
+// containing ESC \ or BEL would terminate the OSC sequence we put it in early,
+// and the remainder would be interpreted by the terminal.
+fn sanitize_title(title: &str) -> String {
+    title.chars().filter(|c| !c.is_control()).collect()
+}
+
+// The limit xterm has
+const MAX_TITLE_STACK: usize = 10;
+
+// String sequences that we pass on as they come, rather than all at once when
+// they end, as we do OSC; our title can't be put in the middle of one.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum StringKind {
+    Dcs,
+    // Which vte ignores, so we handle them in Filter::advance()
+    SosPmApc,
+}
+
+struct SavedTitle {
+    icon_name: Option<String>,
+    window_title: String,
 }
 
 struct FilterState {
     buffer: Vec<u8>,
-    current_directory: String,
+    current_directory: Option<PathBuf>,
+    current_hyperlink: Option<String>,
     in_window_title: String,
+    in_window_title_changed: bool,
+    // Whether in_window_title came from the application, rather than being
+    // the default
+    in_window_title_set: bool,
+    // The icon name, if the application set it separately from the window
+    // title with OSC 1; otherwise, it gets our composed title too
+    in_icon_name: Option<String>,
+    // Titles saved by XTPUSHTITLE; we handle these rather than the terminal,
+    // since what the terminal has is our composed title
+    title_stack: Vec<SavedTitle>,
     out_window_title: String,
+    // Set when our title changes in the middle of a string, to be passed on
+    // when it ends
     out_window_title_pending: bool,
-    in_dcs: bool,
+    string: Option<StringKind>,
+    alt_screen: bool,
+    shell_jobs: Option<u32>,
+    bell_pending: bool,
+    bell_changed: bool,
+    title_sink: Box<dyn TitleSink>,
+    rewrite_titles: bool,
+    // How the application last terminated an OSC sequence, which we follow
+    osc_bell_terminated: bool,
+    // The bytes of the CSI sequence or DCS header being parsed, see
+    // Filter::advance()
+    sequence_raw: Option<Vec<u8>>,
+    // An OSC or DCS string just ended with ESC, and we passed on (or dropped)
+    // a whole ST for it; the \ that follows is part of that
+    string_terminated: bool,
 }
 
 impl FilterState {
     fn new() -> FilterState {
         FilterState {
             buffer: vec![],
-            current_directory: String::new(),
+            current_directory: None,
+            current_hyperlink: None,
             in_window_title: String::from("ttymon"),
+            in_window_title_changed: false,
+            in_window_title_set: false,
+            in_icon_name: None,
+            title_stack: vec![],
             out_window_title: String::new(),
             out_window_title_pending: false,
-            in_dcs: false,
+            string: None,
+            alt_screen: false,
+            shell_jobs: None,
+            bell_pending: false,
+            bell_changed: false,
+            title_sink: Box::new(OscTitleSink),
+            rewrite_titles: true,
+            osc_bell_terminated: false,
+            sequence_raw: None,
+            string_terminated: false,
         }
     }
 
     fn set_out_window_title(&mut self, title: &str) {
+        let title = sanitize_title(title);
         if self.out_window_title != title {
-            self.out_window_title = String::from(title);
-            if self.in_dcs {
+            self.out_window_title = title;
+            if self.string.is_some() {
                 self.out_window_title_pending = true;
             } else {
-                self.append_window_title(title);
+                self.append_out_window_title();
             }
         }
     }
 
+    // Called once the terminator of a string has been passed on, if it had
+    // one
+    fn end_string(&mut self) {
+        self.string = None;
+        if self.out_window_title_pending {
+            self.out_window_title_pending = false;
+            self.append_out_window_title();
+        }
+    }
+
     #[inline]
     fn append(&mut self, byte: u8) {
         self.buffer.push(byte);
@@ -108,6 +466,32 @@ impl FilterState {
         }
     }
 
+    // Passes on a CSI sequence or DCS header as it was received, if we have
+    // it, otherwise as best we can from what vte parsed
+    fn append_sequence(
+        &mut self,
+        introducer: &[u8],
+        params: &Params,
+        intermediates: &[u8],
+        action: char,
+    ) {
+        match self.sequence_raw.take() {
+            Some(raw) => self.append_many(&raw),
+            None => {
+                // Private markers come before the parameters, intermediates
+                // after them
+                let (markers, intermediates): (Vec<u8>, Vec<u8>) = intermediates
+                    .iter()
+                    .partition(|c| (0x3c..=0x3f).contains(*c));
+                self.append_many(introducer);
+                self.append_many(&markers);
+                self.append_params(params);
+                self.append_many(&intermediates);
+                self.append(action as u8);
+            }
+        }
+    }
+
     fn append_params(&mut self, params: &Params) {
         for (i, param) in params.iter().enumerate() {
             if i != 0 {
@@ -116,7 +500,7 @@ impl FilterState {
 
             for (i, subparam) in param.iter().enumerate() {
                 if i != 0 {
-                    self.append(b';');
+                    self.append(b':');
                 }
 
                 self.append_u16(*subparam);
@@ -124,31 +508,116 @@ impl FilterState {
         }
     }
 
-    fn append_window_title(&mut self, title: &str) {
-        self.append_many(&OSC);
-        self.append_many(b"0;");
-        self.append_many(title.as_bytes());
-        self.append_many(&ST);
+    fn handle_ttymon_osc(&mut self, key: &[u8], value: &[u8]) {
+        if key == b"jobs" {
+            self.shell_jobs = std::str::from_utf8(value)
+                .ok()
+                .and_then(|v| v.trim().parse().ok());
+        } else {
+            debug!("Unknown ttymon OSC: {}", String::from_utf8_lossy(key));
+        }
+    }
+
+    fn append_window_title(&mut self, kind: TitleKind, title: &str) {
+        if !self.rewrite_titles {
+            return;
+        }
+        self.title_sink
+            .write_title(kind, title, self.osc_bell_terminated, &mut self.buffer);
+    }
+
+    // Our composed title replaces the application's window title, and its
+    // icon name too, unless it has set that separately.
+    fn append_out_window_title(&mut self) {
+        let kind = match self.in_icon_name {
+            Some(_) => TitleKind::WindowTitle,
+            None => TitleKind::IconNameAndWindowTitle,
+        };
+        // Copy here because rustc doesn't know that append_window_title()
+        // doesn't modify self.out_window_title
+        let out_window_title = self.out_window_title.clone();
+        self.append_window_title(kind, &out_window_title);
+    }
+
+    // XTPUSHTITLE (CSI 22 ; Ps t) and XTPOPTITLE (CSI 23 ; Ps t), where Ps
+    // is 0 for both the icon name and window title, 1 for the icon name, and
+    // 2 for the window title.
+    fn handle_title_stack(&mut self, push: bool, which: u16) {
+        if push {
+            if self.title_stack.len() == MAX_TITLE_STACK {
+                self.title_stack.remove(0);
+            }
+            self.title_stack.push(SavedTitle {
+                icon_name: self.in_icon_name.clone(),
+                window_title: self.in_window_title.clone(),
+            });
+            return;
+        }
+
+        let saved = match self.title_stack.pop() {
+            Some(saved) => saved,
+            None => return,
+        };
+        if which == 0 || which == 1 {
+            match saved.icon_name {
+                Some(icon_name) => self.set_in_title(TitleKind::IconName, icon_name),
+                None => {
+                    if self.in_icon_name.take().is_some() && !self.out_window_title.is_empty() {
+                        self.append_out_window_title();
+                    }
+                }
+            }
+        }
+        if which == 0 || which == 2 {
+            self.set_in_title(TitleKind::WindowTitle, saved.window_title);
+        }
+    }
+
+    fn set_in_title(&mut self, kind: TitleKind, title: String) {
+        if kind == TitleKind::IconName {
+            // Passed on, since we don't replace it
+            self.append_window_title(kind, &title);
+            self.in_icon_name = Some(title);
+            return;
+        }
+
+        // The icon name goes back to being our title
+        if kind == TitleKind::IconNameAndWindowTitle
+            && self.in_icon_name.take().is_some()
+            && !self.out_window_title.is_empty()
+        {
+            self.append_out_window_title();
+        }
+
+        self.in_window_title_set = true;
+        if self.in_window_title != title {
+            self.in_window_title = title;
+            self.in_window_title_changed = true;
+        }
     }
 }
 
 impl Perform for FilterState {
     fn print(&mut self, c: char) {
+        self.string_terminated = false;
         let mut b = [0; 4];
         let result = c.encode_utf8(&mut b);
         self.append_many(result.as_bytes());
     }
 
     fn execute(&mut self, byte: u8) {
+        self.string_terminated = false;
+        if byte == BEL && !self.bell_pending {
+            self.bell_pending = true;
+            self.bell_changed = true;
+        }
         self.append(byte);
     }
 
     fn hook(&mut self, params: &Params, intermediates: &[u8], _ignore: bool, action: char) {
-        self.in_dcs = true;
-        self.append_many(&DCS);
-        self.append_params(params);
-        self.append_many(intermediates);
-        self.append(action as u8);
+        self.string_terminated = false;
+        self.string = Some(StringKind::Dcs);
+        self.append_sequence(&DCS, params, intermediates, action);
     }
 
     fn put(&mut self, byte: u8) {
@@ -156,20 +625,50 @@ impl Perform for FilterState {
     }
 
     fn unhook(&mut self) {
-        self.in_dcs = false;
+        // vte doesn't tell us what ended the string; if it was CAN or SUB
+        // rather than ST, we end it properly anyway
         self.append_many(&ST);
-        if self.out_window_title_pending {
-            // Copy here because rustc doesn't know that append_window_title()
-            // doesn't modify self.out_window_title
-            let out_window_title = self.out_window_title.clone();
-            self.append_window_title(&out_window_title);
-        }
+        self.string_terminated = true;
+        self.end_string();
     }
 
     fn osc_dispatch(&mut self, params: &[&[u8]], bell_terminated: bool) {
-        if params.len() == 2 && params[0] == b"0" {
-            if let Ok(title) = std::str::from_utf8(params[1]) {
-                self.in_window_title = title.to_string();
+        self.osc_bell_terminated = bell_terminated;
+        self.string_terminated = !bell_terminated;
+
+        if params.len() == 2 {
+            if let Some(kind) = TitleKind::from_osc(params[0]) {
+                if let Ok(title) = std::str::from_utf8(params[1]) {
+                    self.set_in_title(kind, sanitize_title(title));
+                }
+                if self.rewrite_titles {
+                    return;
+                }
+            }
+        }
+
+        // The shell's working directory; this is also useful to the terminal,
+        // so passes through.
+        if params.len() >= 2 && params[0] == b"7" {
+            let uri = params[1..].join(&b';');
+            match std::str::from_utf8(&uri).ok().and_then(decode_file_uri) {
+                Some(path) => self.current_directory = Some(path),
+                None => debug!("Bad OSC 7 URI: {}", String::from_utf8_lossy(&uri)),
+            }
+        }
+
+        // Hyperlinks: OSC 8 ; <id=...:...> ; <URI> ST, with an empty URI closing
+        // the link. These pass through too.
+        if params.len() >= 3 && params[0] == b"8" {
+            let uri = params[2..].join(&b';');
+            if !uri.is_empty() {
+                self.current_hyperlink = Some(String::from_utf8_lossy(&uri).into_owned());
+            }
+        }
+
+        if params.len() >= 2 && params[0] == TTYMON_OSC && params[1] == TTYMON_OSC_COMMAND {
+            if params.len() == 4 {
+                self.handle_ttymon_osc(params[2], params[3]);
             }
             return;
         }
@@ -189,13 +688,39 @@ impl Perform for FilterState {
     }
 
     fn csi_dispatch(&mut self, params: &Params, intermediates: &[u8], _ignore: bool, action: char) {
-        self.append_many(&CSI);
-        self.append_params(params);
-        self.append_many(intermediates);
-        self.append(action as u8);
+        self.string_terminated = false;
+        // DECSET/DECRST of the alternate screen modes
+        if intermediates == b"?" && (action == 'h' || action == 'l') {
+            for param in params.iter() {
+                if let [47] | [1047] | [1049] = param {
+                    self.alt_screen = action == 'h';
+                }
+            }
+        }
+
+        if intermediates.is_empty() && action == 't' {
+            let mut params = params.iter();
+            let op = params.next();
+            if op == Some(&[22]) || op == Some(&[23]) {
+                let which = params.next().map_or(0, |which| which[0]);
+                self.handle_title_stack(op == Some(&[22]), which);
+                if self.rewrite_titles {
+                    return;
+                }
+            }
+        }
+
+        self.append_sequence(&CSI, params, intermediates, action);
     }
 
     fn esc_dispatch(&mut self, intermediates: &[u8], _ignore: bool, byte: u8) {
+        if std::mem::replace(&mut self.string_terminated, false)
+            && intermediates.is_empty()
+            && byte == b'\\'
+        {
+            return;
+        }
+
         self.append(ESC);
         self.append_many(intermediates);
         self.append(byte);
@@ -212,4 +737,608 @@ mod tests {
         filter.fill(b"\x1b[105m\x1b[0m");
         assert_eq!(filter.buffer(), b"\x1b[105m\x1b[0m");
     }
+
+    #[test]
+    fn test_csi_unchanged() {
+        for sequence in &[
+            &b"\x1b[?2004h"[..],
+            b"\x1b[>c",
+            b"\x1b[!p",
+            b"\x1b[m",
+            b"\x1b[?25;1049l",
+            b"\x1b[1;;5H",
+            b"\x1b[38:2::255:128:0m",
+            b"\x1b[ q",
+            b"\x1b[>4;2m",
+            b"\x1b[=1;5u",
+            b"\x1b[123456789m",
+        ] {
+            let mut filter = Filter::new();
+            filter.fill(sequence);
+            assert_eq!(
+                filter.buffer(),
+                *sequence,
+                "{}",
+                String::from_utf8_lossy(sequence)
+            );
+        }
+
+        let mut filter = Filter::new();
+        // A control in the middle of the sequence is executed there
+        filter.fill(b"\x1b[1\n;2H");
+        assert_eq!(filter.buffer(), b"\n\x1b[1;2H");
+        // Cancelled
+        filter.clear_buffer();
+        filter.fill(b"\x1b[1;2\x18\x1b[?7h");
+        assert_eq!(filter.buffer(), b"\x18\x1b[?7h");
+        // 8-bit CSI, passed on as 7-bit
+        filter.clear_buffer();
+        filter.fill(b"\x9b?1049h");
+        assert_eq!(filter.buffer(), b"\x1b[?1049h");
+        assert!(filter.alt_screen());
+        // Sequences split across reads
+        filter.clear_buffer();
+        filter.fill(b"\x1b");
+        filter.fill(b"[?20");
+        filter.fill(b"04l");
+        assert_eq!(filter.buffer(), b"\x1b[?2004l");
+    }
+
+    #[test]
+    fn test_sos_pm_apc() {
+        for sequence in &[
+            &b"\x1b_Gf=100,a=T;iVBORw0KGgo=\x1b\\"[..],
+            b"\x1b^private message\x1b\\",
+            b"\x1bXstart of string\x1b\\",
+            b"\x1b_\x1b\\",
+        ] {
+            let mut filter = Filter::new();
+            filter.fill(sequence);
+            assert_eq!(filter.buffer(), *sequence);
+        }
+
+        let mut filter = Filter::new();
+        // 8-bit, passed on as 7-bit
+        filter.fill(b"\x9fapc\x9c");
+        assert_eq!(filter.buffer(), b"\x1b_apc\x1b\\");
+        // Cancelled
+        filter.clear_buffer();
+        filter.fill(b"\x1b_apc\x18x");
+        assert_eq!(filter.buffer(), b"\x1b_apc\x18x");
+
+        // Our title waits for the end of the string
+        filter.clear_buffer();
+        filter.fill(b"\x1b_Ga=T;AAAA");
+        filter.set_out_window_title("bash");
+        assert_eq!(filter.buffer(), b"\x1b_Ga=T;AAAA");
+        filter.fill(b"\x1b\\");
+        assert_eq!(filter.buffer(), b"\x1b_Ga=T;AAAA\x1b\\\x1b]0;bash\x1b\\");
+    }
+
+    #[test]
+    fn test_title_deferred_in_dcs() {
+        let mut filter = Filter::new();
+        filter.fill(b"\x1bPq#0;2;0;0;0#0~~");
+        // A check in the middle of the string
+        filter.set_out_window_title("bash");
+        assert_eq!(filter.buffer(), b"\x1bPq#0;2;0;0;0#0~~");
+        filter.fill(b"@@\x1b\\");
+        assert_eq!(
+            filter.buffer(),
+            b"\x1bPq#0;2;0;0;0#0~~@@\x1b\\\x1b]0;bash\x1b\\"
+        );
+
+        // Passed on once only
+        filter.clear_buffer();
+        filter.fill(b"\x1bP1$r0m\x1b\\");
+        assert_eq!(filter.buffer(), b"\x1bP1$r0m\x1b\\");
+
+        // An OSC in progress is passed on all at once when it ends, so the
+        // title doesn't need to wait for it
+        filter.clear_buffer();
+        filter.fill(b"\x1b]8;;http://exa");
+        filter.set_out_window_title("vim");
+        filter.fill(b"mple.com\x07");
+        assert_eq!(
+            filter.buffer(),
+            b"\x1b]0;vim\x1b\\\x1b]8;;http://example.com\x07"
+        );
+    }
+
+    #[test]
+    fn test_append_u16() {
+        let format = |val| {
+            let mut state = FilterState::new();
+            state.append_u16(val);
+            String::from_utf8(state.buffer).unwrap()
+        };
+
+        for (val, expected) in &[
+            (0, "0"),
+            (9, "9"),
+            (10, "10"),
+            (99, "99"),
+            (100, "100"),
+            (10000, "10000"),
+            (65535, "65535"),
+        ] {
+            assert_eq!(format(*val), *expected);
+        }
+        for val in 0..=u16::MAX {
+            assert_eq!(format(val), val.to_string());
+        }
+    }
+
+    #[test]
+    fn test_c1() {
+        let mut filter = Filter::new();
+        filter.fill(b"\x9b105m\x84\x9b0m");
+        assert_eq!(filter.buffer(), b"\x1b[105m\x1bD\x1b[0m");
+
+        // 8-bit OSC, terminated by 8-bit ST or BEL
+        filter.fill(b"\x9d0;caf\xc3\xa9 \xe2\x9c\x93\x9c");
+        assert_eq!(filter.in_window_title(), "caf\u{e9} \u{2713}");
+        filter.fill(b"\x9d0;vim\x07");
+        assert_eq!(filter.in_window_title(), "vim");
+
+        // Bytes in the C1 range inside UTF-8 characters are left alone
+        filter.clear_buffer();
+        filter.fill("\u{2713}\u{1f41c}".as_bytes());
+        assert_eq!(filter.buffer(), "\u{2713}\u{1f41c}".as_bytes());
+    }
+
+    #[test]
+    fn test_alt_screen() {
+        let mut filter = Filter::new();
+        assert!(!filter.alt_screen());
+        filter.fill(b"\x1b[?1049h");
+        assert!(filter.alt_screen());
+        filter.fill(b"\x1b[?25;1049l");
+        assert!(!filter.alt_screen());
+        filter.fill(b"\x1b[?47h");
+        assert!(filter.alt_screen());
+        // Not a DEC private mode
+        filter.fill(b"\x1b[47l");
+        assert!(filter.alt_screen());
+    }
+
+    #[test]
+    fn test_shell_jobs() {
+        let mut filter = Filter::new();
+        assert_eq!(filter.shell_jobs(), None);
+        filter.fill(b"$ \x1b]777;ttymon;jobs;2\x07");
+        assert_eq!(filter.shell_jobs(), Some(2));
+        assert_eq!(filter.buffer(), b"$ ");
+        filter.fill(b"\x1b]777;ttymon;jobs;0\x07");
+        assert_eq!(filter.shell_jobs(), Some(0));
+
+        // Other OSC 777 sequences pass through
+        filter.clear_buffer();
+        filter.fill(b"\x1b]777;notify;Done;make\x07");
+        assert_eq!(filter.buffer(), b"\x1b]777;notify;Done;make\x07");
+        assert_eq!(filter.shell_jobs(), Some(0));
+    }
+
+    #[test]
+    fn test_bell_pending() {
+        let mut filter = Filter::new();
+        filter.fill(b"\x1b]0;vim\x07\x1b]777;ttymon;jobs;1\x07");
+        assert!(!filter.bell_pending());
+        filter.fill(b"\x1bP1$r0m\x07\x1b\\");
+        assert!(!filter.bell_pending());
+
+        assert!(!filter.take_bell_changed());
+
+        filter.clear_buffer();
+        filter.fill(b"done\x07");
+        assert!(filter.bell_pending());
+        assert_eq!(filter.buffer(), b"done\x07");
+        assert!(filter.take_bell_changed());
+        // Only the first bell until the title is composed again is a change
+        filter.fill(b"\x07");
+        assert!(!filter.take_bell_changed());
+        filter.clear_bell_pending();
+        assert!(!filter.bell_pending());
+        filter.fill(b"\x07");
+        assert!(filter.take_bell_changed());
+    }
+
+    #[test]
+    fn test_current_directory() {
+        let mut filter = Filter::new();
+        assert_eq!(filter.current_directory(), None);
+
+        filter.fill(b"\x1b]7;file://localhost/home/user/a%20b;c%2\x07");
+        assert_eq!(
+            filter.current_directory(),
+            Some(Path::new("/home/user/a b;c%2"))
+        );
+        assert_eq!(
+            filter.buffer(),
+            b"\x1b]7;file://localhost/home/user/a%20b;c%2\x07"
+        );
+
+        filter.fill(b"\x1b]7;file:///tmp\x1b\\");
+        assert_eq!(filter.current_directory(), Some(Path::new("/tmp")));
+
+        // Not a file URI
+        filter.fill(b"\x1b]7;http://example.com/\x07");
+        filter.fill(b"\x1b]7;file://host\x07");
+        // Another host's directory
+        filter.fill(b"\x1b]7;file://not-this-host.invalid/home\x07");
+        assert_eq!(filter.current_directory(), Some(Path::new("/tmp")));
+    }
+
+    #[test]
+    fn test_current_hyperlink() {
+        let mut filter = Filter::new();
+        assert_eq!(filter.current_hyperlink(), None);
+
+        let link = b"\x1b]8;id=1;file://localhost/src/main.rs\x07main.rs\x1b]8;;\x07";
+        filter.fill(link);
+        assert_eq!(filter.buffer(), link);
+        assert_eq!(
+            filter.current_hyperlink(),
+            Some("file://localhost/src/main.rs")
+        );
+
+        filter.fill(b"\x1b]8;;https://example.com/?a=1;b=2\x07link\x1b]8;;\x07");
+        assert_eq!(
+            filter.current_hyperlink(),
+            Some("https://example.com/?a=1;b=2")
+        );
+    }
+
+    #[test]
+    fn test_title_sanitized() {
+        let mut filter = Filter::new();
+        filter.set_out_window_title("evil\x1b\\\x07\x1b]0;pwned\u{9c}");
+        assert_eq!(filter.buffer(), b"\x1b]0;evil\\]0;pwned\x1b\\");
+
+        filter.fill("\x1b]0;in\u{9c}\u{9d}put\x07".as_bytes());
+        assert_eq!(filter.in_window_title(), "input");
+    }
+
+    #[test]
+    fn test_title_reasserted() {
+        let mut filter = Filter::new();
+        filter.set_out_window_title("bash - ttymon");
+        filter.clear_buffer();
+
+        filter.fill(b"\x1b]0;vim\x07more output");
+        assert!(filter.take_in_window_title_changed());
+        assert!(!filter.take_in_window_title_changed());
+
+        let out_window_title = format!("bash - {}", filter.in_window_title());
+        filter.set_out_window_title(&out_window_title);
+        assert_eq!(filter.buffer(), b"more output\x1b]0;bash - vim\x07");
+
+        // Setting the same title again isn't a change
+        filter.clear_buffer();
+        filter.fill(b"\x1b]0;vim\x07");
+        assert!(!filter.take_in_window_title_changed());
+        assert_eq!(filter.buffer(), b"");
+    }
+
+    #[test]
+    fn test_icon_name() {
+        let mut filter = Filter::new();
+        filter.set_out_window_title("bash - ttymon");
+        filter.clear_buffer();
+
+        // A window title alone is replaced like OSC 0
+        filter.fill(b"\x1b]2;vim\x07");
+        assert!(filter.take_in_window_title_changed());
+        assert_eq!(filter.in_window_title(), "vim");
+        assert_eq!(filter.buffer(), b"");
+
+        // A separate icon name is kept, and from then on only the window
+        // title is replaced
+        filter.fill(b"\x1b]1;vim\x07");
+        assert!(!filter.take_in_window_title_changed());
+        assert_eq!(filter.buffer(), b"\x1b]1;vim\x07");
+        filter.clear_buffer();
+        filter.set_out_window_title("bash - vim");
+        assert_eq!(filter.buffer(), b"\x1b]2;bash - vim\x07");
+
+        // Until OSC 0 sets both again
+        filter.clear_buffer();
+        filter.fill(b"\x1b]0;less\x07");
+        assert!(filter.take_in_window_title_changed());
+        assert_eq!(filter.buffer(), b"\x1b]0;bash - vim\x07");
+    }
+
+    #[test]
+    fn test_title_stack() {
+        let mut filter = Filter::new();
+        filter.set_out_window_title("bash - ttymon");
+        filter.fill(b"\x1b]0;bash\x07");
+        filter.take_in_window_title_changed();
+        filter.clear_buffer();
+
+        filter.fill(b"\x1b[22;0t\x1b]0;vim\x07");
+        assert_eq!(filter.in_window_title(), "vim");
+        filter.take_in_window_title_changed();
+
+        // Popping restores the saved title, without passing the sequence on
+        filter.fill(b"\x1b[23;0t");
+        assert_eq!(filter.in_window_title(), "bash");
+        assert!(filter.take_in_window_title_changed());
+        assert_eq!(filter.buffer(), b"");
+
+        // An icon name set since the push is reverted to our title
+        filter.fill(b"\x1b[22t\x1b]1;less\x07");
+        filter.clear_buffer();
+        filter.fill(b"\x1b[23;1t");
+        assert_eq!(filter.buffer(), b"\x1b]0;bash - ttymon\x07");
+
+        // Popping an empty stack does nothing, and other window operations
+        // pass through
+        filter.clear_buffer();
+        filter.fill(b"\x1b[23;0t\x1b[8;24;80t");
+        assert_eq!(filter.in_window_title(), "bash");
+        assert_eq!(filter.buffer(), b"\x1b[8;24;80t");
+    }
+
+    #[test]
+    fn test_no_rewrite_titles() {
+        let mut filter = Filter::new();
+        filter.set_rewrite_titles(false);
+        filter.set_out_window_title("bash - ttymon");
+        assert_eq!(filter.buffer(), b"");
+
+        // Titles are tracked, but pass through as they are
+        let input = b"\x1b]0;vim\x07\x1b[22;0t\x1b]1;icon\x07\x1b]2;vi\x07\x1b[23;0t";
+        filter.fill(input);
+        assert_eq!(filter.buffer(), input);
+        assert_eq!(filter.in_window_title(), "vim");
+        assert!(filter.take_in_window_title_changed());
+
+        filter.clear_buffer();
+        filter.set_out_window_title("vim - ttymon");
+        filter.restore_window_title();
+        assert_eq!(filter.buffer(), b"");
+    }
+
+    #[test]
+    fn test_title_terminator() {
+        let mut filter = Filter::new();
+        filter.set_out_window_title("bash");
+        assert_eq!(filter.buffer(), b"\x1b]0;bash\x1b\\");
+
+        filter.clear_buffer();
+        filter.fill(b"\x1b]0;vim\x07");
+        filter.set_out_window_title("vim");
+        assert_eq!(filter.buffer(), b"\x1b]0;vim\x07");
+
+        filter.clear_buffer();
+        filter.fill(b"\x1b]7;file:///tmp\x1b\\");
+        filter.clear_buffer();
+        filter.set_out_window_title("bash");
+        assert_eq!(filter.buffer(), b"\x1b]0;bash\x1b\\");
+    }
+
+    #[test]
+    fn test_restore_window_title() {
+        let mut filter = Filter::new();
+        filter.set_out_window_title("bash - ttymon");
+        filter.clear_buffer();
+        filter.restore_window_title();
+        assert_eq!(filter.buffer(), b"\x1b]0;\x1b\\");
+        assert!(!filter.in_window_title_set());
+
+        filter.fill(b"\x1b]0;vim\x07");
+        assert!(filter.in_window_title_set());
+        filter.clear_buffer();
+        filter.restore_window_title();
+        assert_eq!(filter.buffer(), b"\x1b]0;vim\x07");
+
+        // The title the terminal had when we started goes back
+        let mut filter = Filter::new();
+        filter.set_initial_window_title("Terminal\x07");
+        assert_eq!(filter.in_window_title(), "Terminal");
+        filter.restore_window_title();
+        assert_eq!(filter.buffer(), b"\x1b]0;Terminal\x1b\\");
+    }
+
+    #[test]
+    fn test_status_line_title() {
+        let mut filter = Filter::new();
+        filter.set_title_sink(Box::new(StatusLineTitleSink {
+            to_status_line: b"\x1b[2$~\x1b[1$}".to_vec(),
+            clear_to_eol: b"\x1b[K".to_vec(),
+            from_status_line: b"\x1b[0$}".to_vec(),
+        }));
+        filter.set_out_window_title("bash - ~");
+        assert_eq!(filter.buffer(), b"\x1b[2$~\x1b[1$}bash - ~\x1b[K\x1b[0$}");
+    }
+
+    // A small xorshift generator, so the test is repeatable
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        fn below(&mut self, n: usize) -> usize {
+            (self.next() % n as u64) as usize
+        }
+
+        fn pick<'a, T>(&mut self, items: &'a [T]) -> &'a T {
+            &items[self.below(items.len())]
+        }
+
+        fn byte_in(&mut self, low: u8, high: u8) -> u8 {
+            low + self.below((high - low) as usize + 1) as u8
+        }
+    }
+
+    fn random_params(rng: &mut Rng, out: &mut Vec<u8>) {
+        for i in 0..rng.below(5) {
+            if i != 0 {
+                out.push(*rng.pick(b";;;:"));
+            }
+            if rng.below(4) != 0 {
+                out.extend_from_slice(rng.below(70000).to_string().as_bytes());
+            }
+        }
+    }
+
+    // The header of a CSI or DCS sequence after the introducer: a private
+    // marker, parameters, an intermediate, and the final byte
+    fn random_header(rng: &mut Rng, out: &mut Vec<u8>, finals: &[u8]) {
+        if rng.below(3) == 0 {
+            out.push(*rng.pick(b"<=>?"));
+        }
+        random_params(rng, out);
+        if rng.below(4) == 0 {
+            out.push(rng.byte_in(0x20, 0x2f));
+        }
+        out.push(*rng.pick(finals));
+    }
+
+    fn random_text(rng: &mut Rng, out: &mut Vec<u8>) {
+        let text = ["hello", " ", "caf\u{e9}", "\u{2713}", "\u{1f41c}", "\u{9c}"];
+        for _ in 0..rng.below(4) + 1 {
+            out.extend_from_slice(rng.pick(&text).as_bytes());
+        }
+    }
+
+    // Adds a random piece of output to input, and what the filter should
+    // pass on for it to expected; returns the title, if it's one
+    fn random_output(rng: &mut Rng, input: &mut Vec<u8>, expected: &mut Vec<u8>) -> Option<String> {
+        // 7-bit or 8-bit C1 controls; what's passed on is always 7-bit
+        let eight_bit = rng.below(4) == 0;
+        let c1 = |input: &mut Vec<u8>, expected: &mut Vec<u8>, c: u8| {
+            if eight_bit {
+                input.push(c + 0x40);
+            } else {
+                input.extend_from_slice(&[ESC, c]);
+            }
+            expected.extend_from_slice(&[ESC, c]);
+        };
+        let csi_finals: Vec<u8> = (0x40..=0x7e).filter(|c| *c != b't').collect();
+        let st = |input: &mut Vec<u8>, expected: &mut Vec<u8>| {
+            c1(input, expected, b'\\');
+        };
+
+        let start = input.len();
+        match rng.below(8) {
+            0 => random_text(rng, input),
+            // C0 controls, except ESC, and CAN and SUB which cancel sequences
+            1 => loop {
+                let c = rng.byte_in(0, 0x1f);
+                if c != ESC && c != 0x18 && c != 0x1a {
+                    input.push(c);
+                    break;
+                }
+            },
+            2 => {
+                c1(input, expected, b'[');
+                let start = input.len();
+                random_header(rng, input, &csi_finals);
+                expected.extend_from_slice(&input[start..]);
+                return None;
+            }
+            3 => {
+                // ESC sequences that aren't the start of something longer
+                input.push(ESC);
+                if rng.below(3) == 0 {
+                    input.push(rng.byte_in(0x20, 0x2f));
+                }
+                input.push(*rng.pick(b"0678=>BDEHMNOZc"));
+            }
+            4 => {
+                c1(input, expected, b']');
+                let start = input.len();
+                let osc = rng.pick(&["8;;", "7;", "4;1;", "52;c;", "133;"]);
+                input.extend_from_slice(osc.as_bytes());
+                random_text(rng, input);
+                expected.extend_from_slice(&input[start..]);
+                if rng.below(2) == 0 {
+                    input.push(BEL);
+                    expected.push(BEL);
+                } else {
+                    st(input, expected);
+                }
+                return None;
+            }
+            5 => {
+                c1(input, expected, b'P');
+                let start = input.len();
+                random_header(rng, input, b"pqr|{");
+                for _ in 0..rng.below(20) {
+                    input.push(rng.byte_in(0x20, 0x7e));
+                }
+                expected.extend_from_slice(&input[start..]);
+                st(input, expected);
+                return None;
+            }
+            6 => {
+                c1(input, expected, *rng.pick(b"X^_"));
+                let start = input.len();
+                for _ in 0..rng.below(20) {
+                    input.push(rng.byte_in(0x20, 0x7e));
+                }
+                expected.extend_from_slice(&input[start..]);
+                st(input, expected);
+                return None;
+            }
+            _ => {
+                // A title, which isn't passed on
+                let title = if rng.below(2) == 0 {
+                    "vim"
+                } else {
+                    "caf\u{e9} \u{2713}"
+                };
+                let mut ignored = vec![];
+                c1(input, &mut ignored, b']');
+                input.extend_from_slice(rng.pick(&["0;", "2;"]).as_bytes());
+                input.extend_from_slice(title.as_bytes());
+                if rng.below(2) == 0 {
+                    input.push(BEL);
+                } else {
+                    st(input, &mut ignored);
+                }
+                return Some(String::from(title));
+            }
+        }
+        expected.extend_from_slice(&input[start..]);
+
+        None
+    }
+
+    #[test]
+    fn test_passthrough() {
+        let mut rng = Rng(0x2545_f491_4f6c_dd1d);
+        for _ in 0..2000 {
+            let (mut input, mut expected) = (vec![], vec![]);
+            let mut title = None;
+            for _ in 0..rng.below(20) {
+                if let Some(t) = random_output(&mut rng, &mut input, &mut expected) {
+                    title = Some(t);
+                }
+            }
+
+            let mut filter = Filter::new();
+            // Split at a random point, as output may be read
+            let split = rng.below(input.len() + 1);
+            filter.fill(&input[..split]);
+            filter.fill(&input[split..]);
+            assert_eq!(
+                filter.buffer(),
+                &expected[..],
+                "{:?}",
+                String::from_utf8_lossy(&input)
+            );
+            assert_eq!(
+                filter.in_window_title(),
+                title.as_deref().unwrap_or("ttymon")
+            );
+        }
+    }
 }
@@ -4,63 +4,412 @@ extern crate lazy_static;
 #[macro_use]
 extern crate log;
 
+mod args;
+mod command_log;
+mod config;
+mod events;
 mod filter;
+mod monitor;
+mod multiplexer;
 mod podman;
 mod process;
 mod pty;
+mod recorder;
+mod registry;
+mod sandbox;
+mod server;
+mod signals;
 mod socket;
+mod ssh;
 mod state;
+mod terminfo;
+mod title;
+#[cfg(feature = "title-script")]
+mod title_script;
+mod uri;
 
-use pty::{Pty, PtyActions};
-use state::TerminalState;
-use std::path::PathBuf;
+use args::Mode;
+use command_log::CommandLog;
+use config::Config;
+use events::EventLog;
+use filter::{Filter, StatusLineTitleSink};
+use monitor::{StateListener, StateMonitor};
+use nix::unistd::{gethostname, getuid, User};
+use podman::short_image_id;
+use process::{tty_nr_from_rdev, Process};
+use pty::{
+    Pty, PtyActions, DEFAULT_CHECK_INTERVAL_MULTIPLIER, DEFAULT_MAX_CHECK_INTERVAL,
+    DEFAULT_MIN_CHECK_INTERVAL,
+};
+use recorder::Recorder;
+use registry::Registry;
+use server::StateServer;
+use state::{ForegroundInfo, TerminalState};
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::io::RawFd;
+use std::path::{Path, PathBuf};
+use terminfo::Terminfo;
+use title::{
+    truncate_title, CwdStyle, Ellipsis, TitleContext, TitleFormat, DEFAULT_ROOT_INDICATOR,
+};
+#[cfg(feature = "title-script")]
+use title_script::TitleScript;
 
 struct Actions {
-    home: PathBuf,
-    state: TerminalState,
+    home: Option<PathBuf>,
+    host: Option<String>,
+    tty: Option<String>,
+    title_format: TitleFormat,
+    #[cfg(feature = "title-script")]
+    title_script: Option<TitleScript>,
+    root_indicator: String,
+    max_title_length: Option<usize>,
+    title_ellipsis: Ellipsis,
+    cwd_style: CwdStyle,
+    cwd_max_components: Option<usize>,
+    app_title_at_prompt: bool,
+    log_context: bool,
+    monitor: StateMonitor,
+    // The most recent result from monitor
+    foreground: ForegroundInfo,
+    registry: Option<Registry>,
+    state_server: Option<StateServer>,
 }
 
 impl Actions {
-    fn new(child_pid: i32) -> Actions {
-        Actions {
-            home: dirs::home_dir().unwrap(),
-            state: TerminalState::new(child_pid),
+    fn new(
+        child_pid: i32,
+        tty: Option<String>,
+        tty_nr: Option<i32>,
+        registry: Option<Registry>,
+        listeners: Vec<Box<dyn StateListener>>,
+        config: &Config,
+    ) -> io::Result<Actions> {
+        let title_format = match &config.title_format {
+            Some(format) => TitleFormat::parse(format),
+            None => TitleFormat::default(),
+        };
+
+        #[cfg(feature = "title-script")]
+        let title_script = config.title_script.clone().map(TitleScript::new);
+        #[cfg(not(feature = "title-script"))]
+        if config.title_script.is_some() {
+            warn!("Built without the title-script feature, ignoring the title script");
+        }
+
+        let root_indicator = config
+            .root_indicator
+            .clone()
+            .unwrap_or_else(|| String::from(DEFAULT_ROOT_INDICATOR));
+
+        let mut state = TerminalState::new(child_pid);
+        state.set_extra_podman_forwarders(config.wrappers.clone());
+        state.set_container_detection(config.container_detection);
+        if let Some(max_scan_fds) = config.max_scan_fds {
+            state.set_max_scan_fds(max_scan_fds);
+        }
+        if let Some(tty_nr) = tty_nr {
+            state.set_tty_nr(tty_nr);
+        }
+
+        let state_server =
+            config
+                .state_socket
+                .as_ref()
+                .and_then(|path| match StateServer::start(path) {
+                    Ok(state_server) => Some(state_server),
+                    Err(e) => {
+                        warn!("Can't serve state on {}: {}", path.display(), e);
+                        None
+                    }
+                });
+
+        Ok(Actions {
+            home: dirs::home_dir(),
+            host: host_name(),
+            tty,
+            title_format,
+            #[cfg(feature = "title-script")]
+            title_script,
+            root_indicator,
+            max_title_length: config.max_title_length,
+            title_ellipsis: config.title_ellipsis,
+            cwd_style: config.cwd_style,
+            cwd_max_components: config.cwd_max_components,
+            app_title_at_prompt: config.app_title_at_prompt,
+            log_context: config.log_context,
+            monitor: StateMonitor::start(state, listeners)?,
+            foreground: ForegroundInfo::default(),
+            registry,
+            state_server,
+        })
+    }
+
+    fn compose_title(&self, ctx: &TitleContext) -> String {
+        #[cfg(feature = "title-script")]
+        if let Some(title) = self.title_script.as_ref().and_then(|s| s.compose(ctx)) {
+            return title;
         }
+
+        self.title_format.compose(ctx)
     }
 }
 
 impl PtyActions for Actions {
     fn check(&mut self) {
-        self.state.update();
+        self.monitor.request_update();
+        #[cfg(feature = "title-script")]
+        if let Some(title_script) = &mut self.title_script {
+            title_script.reload_if_changed();
+        }
+    }
+
+    fn wakeup_fd(&self) -> Option<RawFd> {
+        Some(self.monitor.fd())
+    }
+
+    fn wakeup(&mut self) -> bool {
+        let foreground = match self.monitor.take_update() {
+            Some(foreground) => foreground,
+            None => return false,
+        };
+        if let Some(registry) = &self.registry {
+            registry.update(self.tty.as_deref(), &foreground.snapshot());
+        }
+        if let Some(state_server) = &self.state_server {
+            state_server.update(&foreground);
+        }
+        self.foreground = foreground;
+
+        true
+    }
+
+    fn log_context(&self) -> Option<String> {
+        if !self.log_context || self.foreground.cwd().as_os_str().is_empty() {
+            return None;
+        }
+
+        let cwd = self.foreground.cwd().display();
+        Some(match self.foreground.container_info() {
+            Some(ci) => format!("container={} cwd={}", ci.container_name, cwd),
+            None => format!("cwd={}", cwd),
+        })
     }
 
-    fn make_window_title(&self, in_window_title: &str) -> String {
-        let container_info = self.state.container_info();
-        let container_string = match container_info {
-            Some(ci) => format!("{} - ", ci.container_name),
-            None => String::from(""),
+    fn make_window_title(&self, filter: &Filter) -> String {
+        let ctx = TitleContext {
+            container_name: self
+                .foreground
+                .container_info()
+                .map(|ci| ci.container_name.as_str()),
+            image_name: self
+                .foreground
+                .container_info()
+                .map(|ci| ci.image_name.as_str())
+                .filter(|name| !name.is_empty()),
+            image_id: self
+                .foreground
+                .container_info()
+                .map(|ci| ci.image_id.as_str())
+                .filter(|id| !id.is_empty()),
+            // The shell knows better than /proc, if it tells us
+            cwd: filter
+                .current_directory()
+                .unwrap_or_else(|| self.foreground.cwd()),
+            home: self.home.as_deref(),
+            cwd_style: self.cwd_style,
+            cwd_max_components: self.cwd_max_components,
+            argv0: self.foreground.argv0(),
+            argv: self.foreground.argv(),
+            command: self.foreground.command(),
+            // When sshing somewhere, the host that matters is that one
+            host: self.foreground.remote_host().or(self.host.as_deref()),
+            local_host: self.host.as_deref(),
+            jobs: filter.shell_jobs(),
+            bell: filter.bell_pending(),
+            login_user: self.foreground.login_user(),
+            root: self.foreground.is_root(),
+            root_indicator: &self.root_indicator,
+            tty: self.tty.as_deref(),
+            in_window_title: filter.in_window_title(),
+        };
+
+        // At a prompt, outside of a container, the shell's own title
+        // usually says what we would, so if it set one, it's left alone
+        let title = if self.app_title_at_prompt
+            && self.foreground.at_shell()
+            && self.foreground.container_info().is_none()
+            && filter.in_window_title_set()
+        {
+            filter.in_window_title().to_string()
+        } else {
+            self.compose_title(&ctx)
+        };
+        let title = match self.max_title_length {
+            Some(max_length) => truncate_title(&title, max_length, self.title_ellipsis),
+            None => title,
         };
 
-        let mut foreground_cwd = PathBuf::from(self.state.foreground_cwd());
-        if let Ok(home_suffix) = foreground_cwd.strip_prefix(&self.home) {
-            foreground_cwd = PathBuf::from("~").join(home_suffix);
+        if let Some(state_server) = &self.state_server {
+            state_server.set_title(&title);
+        }
+
+        title
+    }
+}
+
+// What to tell about changes of state. This is done before the child is
+// started, so that it doesn't inherit events_fd.
+fn state_listeners(config: &Config) -> Vec<Box<dyn StateListener>> {
+    let mut listeners: Vec<Box<dyn StateListener>> = vec![];
+    if let Some(path) = &config.command_log {
+        match CommandLog::open(path) {
+            Ok(command_log) => listeners.push(Box::new(command_log)),
+            Err(e) => warn!("Can't open {}: {}", path.display(), e),
         }
+    }
+    if let Some(path) = &config.events {
+        match EventLog::open(path) {
+            Ok(event_log) => listeners.push(Box::new(event_log)),
+            Err(e) => warn!("Can't open {}: {}", path.display(), e),
+        }
+    }
+    if let Some(fd) = config.events_fd {
+        match EventLog::from_fd(fd) {
+            Ok(event_log) => listeners.push(Box::new(event_log)),
+            Err(e) => warn!("Can't write events to fd {}: {}", fd, e),
+        }
+    }
 
-        let foreground_argv = self.state.foreground_argv0();
+    listeners
+}
 
-        format!(
-            "{}{} - {} - {}",
-            container_string,
-            foreground_cwd.to_string_lossy(),
-            foreground_argv,
-            in_window_title
-        )
+fn host_name() -> Option<String> {
+    let mut buf = [0u8; 256];
+    match gethostname(&mut buf) {
+        Ok(name) => Some(name.to_string_lossy().into_owned()),
+        Err(e) => {
+            warn!("Can't get host name: {}", e);
+            None
+        }
     }
 }
 
+// The shell to run: the configured shell, $SHELL, then the user's shell from
+// the password database, then /bin/bash
+fn user_shell(config: &Config) -> PathBuf {
+    if let Some(shell) = &config.shell {
+        return shell.clone();
+    }
+
+    if let Some(shell) = std::env::var_os("SHELL").filter(|shell| !shell.is_empty()) {
+        return PathBuf::from(shell);
+    }
+
+    match User::from_uid(getuid()) {
+        Ok(Some(user)) if !user.shell.as_os_str().is_empty() => user.shell,
+        _ => PathBuf::from("/bin/bash"),
+    }
+}
+
+// Whether we were started as a login shell, which login(1) and friends
+// indicate by prefixing argv[0] with '-'; if so, the shell we run is one too.
+fn is_login_shell() -> bool {
+    match std::env::args_os().next() {
+        Some(arg0) => arg0.as_bytes().starts_with(b"-"),
+        None => false,
+    }
+}
+
+// Updates the state for the session that pid leads once, and prints it;
+// returns the exit code
+fn inspect(pid: i32) -> i32 {
+    if let Err(e) = Process::new(pid).stat() {
+        error!("Can't read process {}: {}", pid, e);
+        return 1;
+    }
+
+    let config = Config::load();
+    let mut state = TerminalState::new(pid);
+    state.set_extra_podman_forwarders(config.wrappers.clone());
+    state.set_container_detection(config.container_detection);
+    if let Some(max_scan_fds) = config.max_scan_fds {
+        state.set_max_scan_fds(max_scan_fds);
+    }
+    state.update();
+
+    let foreground = state.foreground();
+    println!("{}", state);
+    match foreground.container_info() {
+        Some(ci) => println!(
+            "container: {} {} (image {}, {})",
+            ci.container_name,
+            short_image_id(&ci.container_id),
+            ci.image_name,
+            short_image_id(&ci.image_id)
+        ),
+        None => println!("container: none"),
+    }
+    if let Some(remote_host) = foreground.remote_host() {
+        println!("remote host: {}", remote_host);
+    }
+    println!("cwd: {}", foreground.cwd().display());
+    println!("command: {}", foreground.command());
+    println!("argv: {:?}", foreground.argv());
+    if let Some(login_user) = foreground.login_user() {
+        println!("login user: {}", login_user);
+    }
+    println!("root: {}", foreground.is_root());
+
+    0
+}
+
 fn main() {
     env_logger::init();
 
+    // ttymon -- COMMAND [ARGS...]: run a command rather than a shell
+    let options = match args::parse_args(std::env::args_os().skip(1)) {
+        Ok(Mode::Run(options)) => options,
+        // Print the state of all ttymon instances that registered themselves
+        // (registry = true in the configuration), as JSON
+        Ok(Mode::ListSessions) => match registry::query() {
+            Ok(list) => {
+                print!("{}", list);
+                std::process::exit(0);
+            }
+            Err(e) => {
+                error!("Failed to query registry: {}", e);
+                std::process::exit(1);
+            }
+        },
+        // Print what we find out about an existing session, for debugging
+        // container detection and the like
+        Ok(Mode::Inspect(pid)) => std::process::exit(inspect(pid)),
+        Ok(Mode::Help) => {
+            print!("{}", args::USAGE);
+            std::process::exit(0);
+        }
+        Ok(Mode::Version) => {
+            println!("{}", args::version());
+            std::process::exit(0);
+        }
+        Err(e) => {
+            eprint!("{}\n\n{}", e, args::USAGE);
+            std::process::exit(2);
+        }
+    };
+
+    let mut config = Config::load();
+    // The command line overrides the configuration
+    if options.log.is_some() {
+        config.log = options.log;
+    }
+    if options.log_timing.is_some() {
+        config.log_timing = options.log_timing;
+    }
+
     let mut pty = match Pty::new() {
         Ok(pty) => pty,
         Err(e) => {
@@ -69,7 +418,47 @@ fn main() {
         }
     };
 
-    let child_pid = match pty.fork() {
+    // Hardware terminals with a status line get the title there
+    if let Ok(terminfo) = Terminfo::from_env() {
+        if let Some(title_sink) = StatusLineTitleSink::from_terminfo(&terminfo) {
+            pty.set_title_sink(Box::new(title_sink));
+        }
+    }
+
+    pty.set_reset_screen(config.reset_screen);
+    pty.set_query_title(config.query_title);
+    pty.set_rewrite_titles(config.rewrite_title);
+    if let Some(max_output_buffer) = config.max_output_buffer {
+        pty.set_max_output_buffer(max_output_buffer);
+    }
+    if let Some(max_read_buffer) = config.max_read_buffer {
+        pty.set_max_read_buffer(max_read_buffer);
+    }
+    pty.set_idle_timeout(config.idle_timeout);
+    pty.set_check_intervals(
+        config.check_min.unwrap_or(DEFAULT_MIN_CHECK_INTERVAL),
+        config.check_max.unwrap_or(DEFAULT_MAX_CHECK_INTERVAL),
+        config
+            .check_multiplier
+            .unwrap_or(DEFAULT_CHECK_INTERVAL_MULTIPLIER),
+    );
+    if let Some(log) = &config.log {
+        match Recorder::open(log, config.log_timing.as_deref()) {
+            Ok(recorder) => pty.set_recorder(recorder),
+            Err(e) => {
+                error!("Can't open {}: {}", log.display(), e);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    let listeners = state_listeners(&config);
+
+    let forked = match options.command.split_first() {
+        Some((program, args)) => pty.fork(Path::new(program), args, false),
+        None => pty.fork(&user_shell(&config), &[], is_login_shell()),
+    };
+    let child_pid = match forked {
         Ok(pid) => pid,
         Err(e) => {
             error!("Failed to fork subprocess: {}", e);
@@ -77,10 +466,36 @@ fn main() {
         }
     };
 
-    let mut actions = Actions::new(child_pid as i32);
+    let tty = pty.peer_name().strip_prefix("/dev/").map(String::from);
+    // To check that the processes we look at are on it
+    let tty_nr = match std::fs::metadata(pty.peer_name()) {
+        Ok(metadata) => Some(tty_nr_from_rdev(metadata.rdev())),
+        Err(e) => {
+            warn!("Can't stat {}: {}", pty.peer_name(), e);
+            None
+        }
+    };
+
+    let registry = if config.registry {
+        Some(Registry::start())
+    } else {
+        None
+    };
+
+    let mut actions =
+        match Actions::new(child_pid as i32, tty, tty_nr, registry, listeners, &config) {
+            Ok(actions) => actions,
+            Err(e) => {
+                error!("Failed to start monitoring: {}", e);
+                std::process::exit(1);
+            }
+        };
 
-    match pty.handle(&mut actions) {
-        Ok(()) => {}
+    let result = pty.handle(&mut actions);
+    // Clean up, since exit() doesn't run destructors
+    drop(actions);
+    match result {
+        Ok(exit_code) => std::process::exit(exit_code),
         Err(e) => {
             error!("Failed to handle IO with subprocess: {}", e);
             std::process::exit(1);
@@ -0,0 +1,122 @@
+// Updating the terminal state in the background
+//
+// Finding out what is running in the terminal can be slow - scanning /proc,
+// netlink queries, running podman inspect - and if it happened in the main
+// loop, input and output would stall until it was done. So TerminalState
+// lives on a separate thread: the main loop asks for an update, and when it's
+// done, the thread stores the result and writes a byte to a socket that the
+// main loop watches, so it can pick up the result and recompose the title.
+
+use crate::state::{ForegroundInfo, TerminalState};
+use std::io::{self, Read, Write};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+
+/// Something to tell about every update, on the monitor's thread
+pub trait StateListener: Send {
+    fn update(&mut self, foreground: &ForegroundInfo);
+}
+
+pub struct StateMonitor {
+    requests: mpsc::Sender<()>,
+    latest: Arc<Mutex<Option<ForegroundInfo>>>,
+    wakeup: UnixStream,
+}
+
+impl StateMonitor {
+    /// Starts the thread that updates state. It exits when the StateMonitor
+    /// is dropped.
+    pub fn start(
+        state: TerminalState,
+        listeners: Vec<Box<dyn StateListener>>,
+    ) -> io::Result<StateMonitor> {
+        let (wakeup, thread_wakeup) = UnixStream::pair()?;
+        wakeup.set_nonblocking(true)?;
+
+        let (requests, receiver) = mpsc::channel();
+        let latest = Arc::new(Mutex::new(None));
+        let thread_latest = latest.clone();
+        thread::spawn(move || run(state, listeners, &receiver, &thread_latest, thread_wakeup));
+
+        Ok(StateMonitor {
+            requests,
+            latest,
+            wakeup,
+        })
+    }
+
+    /// Asks for the state to be updated; this doesn't wait for the update
+    pub fn request_update(&self) {
+        let _ = self.requests.send(());
+    }
+
+    /// The fd to watch for reading; it's readable when take_update() has
+    /// something to return
+    pub fn fd(&self) -> RawFd {
+        self.wakeup.as_raw_fd()
+    }
+
+    /// Returns the result of the most recent update, if there has been one
+    /// since the last call
+    pub fn take_update(&self) -> Option<ForegroundInfo> {
+        let mut buf = [0u8; 64];
+        while let Ok(count) = (&self.wakeup).read(&mut buf) {
+            if count == 0 {
+                break;
+            }
+        }
+
+        self.latest.lock().unwrap().take()
+    }
+}
+
+fn run(
+    mut state: TerminalState,
+    mut listeners: Vec<Box<dyn StateListener>>,
+    receiver: &mpsc::Receiver<()>,
+    latest: &Mutex<Option<ForegroundInfo>>,
+    mut wakeup: UnixStream,
+) {
+    while receiver.recv().is_ok() {
+        // If requests piled up while we were busy, one update covers them all
+        while receiver.try_recv().is_ok() {}
+
+        state.update();
+        for listener in &mut listeners {
+            listener.update(state.foreground());
+        }
+        *latest.lock().unwrap() = Some(state.foreground().clone());
+
+        // If the main loop has gone away, the next recv() will fail
+        let _ = wakeup.write_all(&[0]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_state_monitor() {
+        let pid = nix::unistd::getpid().as_raw();
+        let monitor = StateMonitor::start(TerminalState::new(pid), vec![]).unwrap();
+        assert!(monitor.take_update().is_none());
+
+        monitor.request_update();
+        monitor.request_update();
+        let start = Instant::now();
+        while monitor.take_update().is_none() {
+            assert!(start.elapsed() < Duration::from_secs(5));
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        // Two requests at once may or may not be combined, but nothing is left
+        // after that
+        thread::sleep(Duration::from_millis(100));
+        monitor.take_update();
+        assert!(monitor.take_update().is_none());
+    }
+}
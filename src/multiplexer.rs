@@ -0,0 +1,151 @@
+// Following tmux and screen to the session in the active pane
+//
+// What runs in our terminal is the multiplexer's client; the panes (or
+// windows) are sessions on ptys of their own, belonging to a server process.
+// The server has the client's terminal open - screen writes to it directly,
+// and the tmux client passes its terminal to the server - so we find the
+// server as the process that has our terminal open.
+//
+// For tmux, the server can tell us the pane active in the client. Otherwise,
+// we guess that the active session is the one whose terminal was written to
+// most recently.
+
+use crate::podman::output_with_timeout;
+use crate::process::Process;
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+use std::time::{Duration, SystemTime};
+
+const TMUX_TIMEOUT: Duration = Duration::from_secs(1);
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Multiplexer {
+    Tmux,
+    Screen,
+}
+
+impl Multiplexer {
+    pub fn detect(process: &Process) -> Option<Multiplexer> {
+        match process.command().ok().as_deref() {
+            Some("tmux") => Some(Multiplexer::Tmux),
+            Some("screen") => Some(Multiplexer::Screen),
+            _ => None,
+        }
+    }
+
+    // The screen server renames itself SCREEN
+    fn is_server(self, process: &Process) -> bool {
+        matches!(
+            (self, process.command().ok().as_deref()),
+            (Multiplexer::Tmux, Some("tmux"))
+                | (Multiplexer::Screen, Some("SCREEN"))
+                | (Multiplexer::Screen, Some("screen"))
+        )
+    }
+
+    /// The session leader in the active pane of the client in process group
+    /// pgrp
+    pub fn find_session(self, pgrp: i32) -> Option<i32> {
+        let client = Process::group_representative(pgrp);
+        let tty = client.tty_path()?;
+
+        let server = Process::find(|process| {
+            process.process_group().ok() != Some(pgrp)
+                && self.is_server(process)
+                && process.has_open(&tty)
+        })
+        .ok()??;
+
+        if self == Multiplexer::Tmux {
+            if let Some(pid) = tmux_pane_pid(&server, &tty) {
+                return Some(pid);
+            }
+        }
+
+        most_recently_written(&Process::children(server.pid()).ok()?)
+    }
+}
+
+// Asks the tmux server for the pane that the client on tty is showing
+fn tmux_pane_pid(server: &Process, tty: &Path) -> Option<i32> {
+    let mut command = Command::new("tmux");
+    // The server was started with the options that say which socket it uses
+    let argv = server.argv().ok()?;
+    command.args(socket_options(&argv));
+    command
+        .arg("display-message")
+        .arg("-p")
+        .arg("-c")
+        .arg(tty)
+        .arg("#{pane_pid}");
+
+    match output_with_timeout(&mut command, TMUX_TIMEOUT) {
+        Ok((true, stdout)) => String::from_utf8_lossy(&stdout).trim().parse().ok(),
+        Ok((false, _)) => None,
+        Err(e) => {
+            info!("Can't ask tmux for the active pane: {}", e);
+            None
+        }
+    }
+}
+
+// The -L and -S options of a tmux command line, which select the server
+fn socket_options(argv: &[String]) -> Vec<&str> {
+    let mut result = vec![];
+    let mut args = argv.iter().skip(1);
+    while let Some(arg) = args.next() {
+        if !arg.starts_with('-') || arg == "--" {
+            break;
+        }
+        if arg == "-L" || arg == "-S" {
+            if let Some(value) = args.next() {
+                result.push(arg.as_str());
+                result.push(value.as_str());
+            }
+        } else if arg.starts_with("-L") || arg.starts_with("-S") {
+            result.push(arg.as_str());
+        } else if arg == "-f" || arg == "-c" || arg == "-T" {
+            // Other options with a value
+            args.next();
+        }
+    }
+
+    result
+}
+
+fn most_recently_written(sessions: &[Process]) -> Option<i32> {
+    let modified = |process: &Process| -> Option<SystemTime> {
+        fs::metadata(process.tty_path()?).ok()?.modified().ok()
+    };
+
+    sessions
+        .iter()
+        .filter_map(|process| Some((modified(process)?, process.pid())))
+        .max()
+        .map(|(_, pid)| pid)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_socket_options() {
+        let options = |command_line: &str| {
+            let argv: Vec<String> = command_line.split(' ').map(String::from).collect();
+            socket_options(&argv)
+                .iter()
+                .map(|s| s.to_string())
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(options("tmux"), Vec::<String>::new());
+        assert_eq!(options("tmux -L work attach"), vec!["-L", "work"]);
+        assert_eq!(options("tmux -2 -S /tmp/sock new"), vec!["-S", "/tmp/sock"]);
+        assert_eq!(options("tmux -Lwork"), vec!["-Lwork"]);
+        assert_eq!(options("tmux -f tmux.conf -L x"), vec!["-L", "x"]);
+        // Options of the command aren't ours
+        assert_eq!(options("tmux new -S x"), Vec::<String>::new());
+    }
+}
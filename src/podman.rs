@@ -1,7 +1,48 @@
 use crate::process::Process;
-use crate::socket::get_socket_peer;
+use crate::socket::SockDiag;
+use serde_json::Value;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
 use std::io;
-use std::process::Command;
+use std::io::Read;
+use std::process::{Command, Stdio};
+use std::sync::{mpsc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Processes with more open files than this are skipped when scanning a process
+// group for sockets - a process like that isn't going to be a toolbox/podman
+// process, and reading all its fds would slow down every check.
+pub const DEFAULT_MAX_SCAN_FDS: usize = 1024;
+
+// podman can take a long time, or forever, if its storage is in a bad state;
+// the check runs on the monitor thread, which would stop noticing any changes
+// until podman returned, so rather than wait, we give up
+const INSPECT_TIMEOUT: Duration = Duration::from_secs(2);
+
+// How often output_with_timeout() checks whether a command that's done with
+// its output has exited
+const EXIT_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+lazy_static! {
+    static ref INSPECT_CACHE: Mutex<InspectCache> = Mutex::new(InspectCache::new());
+}
+
+/// What manages a container, which is what we ask about it
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Runtime {
+    Podman,
+    Docker,
+}
+
+impl Runtime {
+    fn command(self) -> &'static str {
+        match self {
+            Runtime::Podman => "podman",
+            Runtime::Docker => "docker",
+        }
+    }
+}
 
 #[derive(Clone)]
 pub struct ContainerInfo {
@@ -15,99 +56,379 @@ fn have_common_member(a: &[u32], b: &[u32]) -> bool {
     return a.into_iter().any(|v| b.contains(v));
 }
 
-pub fn find_podman_peer(tty_pgrp: i32) -> io::Result<(i32, Option<ContainerInfo>)> {
+fn is_permission_denied(e: &io::Error) -> bool {
+    e.kind() == io::ErrorKind::PermissionDenied
+}
+
+// max_scan_fds is as for TerminalState::set_max_scan_fds()
+pub fn find_podman_peer(
+    tty_pgrp: i32,
+    sock_diag: &mut SockDiag,
+    max_scan_fds: usize,
+) -> io::Result<(i32, Option<ContainerInfo>)> {
     let pgrp_members = Process::list_process_group(tty_pgrp)?;
     let mut sockets: Vec<u32> = vec![];
+    let mut unreadable_members = false;
     for pid in pgrp_members {
-        match Process::new(pid).list_sockets() {
+        let process = Process::new(pid);
+        if max_scan_fds > 0 {
+            if let Ok(fd_count) = process.open_fd_count() {
+                if fd_count > max_scan_fds {
+                    info!("Not scanning {} for sockets, {} open fds", pid, fd_count);
+                    continue;
+                }
+            }
+        }
+
+        match process.list_sockets() {
             Ok(s) => {
                 let mut new_sockets = s;
                 sockets.append(&mut new_sockets);
             }
+            // Like sudo podman, expected under rootful podman
+            Err(e) if is_permission_denied(&e) => {
+                debug!("Can't list sockets of {}: {}", pid, e);
+                unreadable_members = true;
+            }
             Err(e) => {
                 info!("Failed to list sockets: {}", e);
             }
         }
     }
 
-    let mut peer_sockets: Vec<u32> = vec![];
-    for socket_ino in sockets {
-        match get_socket_peer(socket_ino) {
-            Ok(peer) => {
-                if peer != 0 {
-                    peer_sockets.push(peer);
-                }
-            }
-            Err(e) => println!("{}: {:?}", socket_ino, e),
+    let peer_sockets: Vec<u32> = match sock_diag.get_socket_peers(&sockets) {
+        Ok(peers) => peers.values().copied().filter(|peer| *peer != 0).collect(),
+        Err(e) => {
+            // Writing to the terminal here would mix into the child's output
+            info!("Can't get socket peers: {}", e);
+            vec![]
         }
-    }
+    };
 
-    let conmon_pid = match Process::find(|process: &Process| {
-        if let Ok(argv0) = process.argv0() {
-            if argv0 == "/usr/bin/conmon" {
-                if let Ok(sockets) = process.list_sockets() {
-                    return have_common_member(&sockets, &peer_sockets);
+    // The conmons of rootful containers, whose sockets we can't see
+    let unreadable_conmons = RefCell::new(vec![]);
+    let found = Process::find(|process: &Process| {
+        if !process.is_alive() {
+            return false;
+        }
+        if let Ok(command) = process.command() {
+            if command == "conmon" {
+                match process.list_sockets() {
+                    Ok(sockets) => return have_common_member(&sockets, &peer_sockets),
+                    Err(e) if is_permission_denied(&e) => {
+                        unreadable_conmons.borrow_mut().push(process.pid());
+                    }
+                    Err(_) => (),
                 }
             }
         }
 
         return false;
-    }) {
-        Ok(Some(process)) => process.pid(),
-        Ok(None) => {
-            return Err(io::Error::new(
-                io::ErrorKind::Other,
-                "Can't find podman peer",
-            ))
-        }
-        Err(e) => return Err(e),
+    })?;
+
+    let conmon_pid = match found {
+        Some(process) => process.pid(),
+        None => match unreadable_conmons.into_inner().as_slice() {
+            // When neither side's sockets could be matched up, and there's
+            // only one conmon we couldn't look at, that must be it
+            [pid] if unreadable_members => {
+                debug!("Assuming podman peer is conmon {}", pid);
+                *pid
+            }
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    "Can't find podman peer",
+                ))
+            }
+        },
     };
 
     let container_info = get_container_info(conmon_pid)?;
 
-    return match Process::find(|process: &Process| {
-        if let Ok(ppid) = process.parent() {
-            ppid == conmon_pid
-        } else {
-            false
-        }
-    }) {
-        Ok(Some(process)) => Ok((process.pid(), container_info)),
-        Ok(None) => {
-            return Err(io::Error::new(
-                io::ErrorKind::Other,
-                "Can't find podman peer",
-            ))
-        }
-        Err(e) => return Err(e),
+    // conmon's child is the container's init
+    return match Process::children(conmon_pid)?.first() {
+        Some(process) => Ok((process.pid(), container_info)),
+        None => Err(io::Error::new(
+            io::ErrorKind::Other,
+            "Can't find podman peer",
+        )),
     };
 }
 
-fn get_container_info_for_id(id: &[u8]) -> io::Result<Option<ContainerInfo>> {
-    let container_id = std::string::String::from_utf8(id.to_vec()).unwrap();
-
-    let output = Command::new("podman")
-        .arg("inspect")
-        .arg(&container_id)
-        .arg("-f")
-        .arg("{{ .Name }} {{ .Image }} {{ .ImageName }}")
-        .output()?;
-
-    if output.status.success() {
-        if let Ok(str_output) = String::from_utf8(output.stdout) {
-            let fields: Vec<&str> = str_output.split(" ").collect();
-            if fields.len() == 3 {
-                return Ok(Some(ContainerInfo {
-                    container_id: String::from(container_id),
-                    container_name: String::from(fields[0]),
-                    image_id: String::from(fields[1]),
-                    image_name: String::from(fields[2]),
-                }));
+/// Finds the container a process is in from the contents of its
+/// /proc/<pid>/cgroup. With the systemd cgroup manager, the processes of a
+/// podman container are in a libpod-<id>.scope cgroup (or somewhere below
+/// it), with cgroupfs, under libpod_parent/libpod-<id>. conmon, which runs
+/// outside the container, is in libpod-conmon-<id>.scope. Docker is similar,
+/// with docker-<id>.scope or docker/<id>.
+pub fn container_id_from_cgroup(cgroup: &str) -> Option<(Runtime, &str)> {
+    find_container_id(cgroup, false)
+}
+
+// With conmon set, finds the container that a conmon process is monitoring,
+// from its libpod-conmon-<id>.scope, rather than the one a process is in
+fn find_container_id(cgroup: &str, conmon: bool) -> Option<(Runtime, &str)> {
+    let is_id = |id: &str| id.len() == 64 && id.bytes().all(|c| c.is_ascii_hexdigit());
+
+    for line in cgroup.lines() {
+        // hierarchy-ID:controller-list:cgroup-path
+        let path = match line.splitn(3, ':').nth(2) {
+            Some(path) => path,
+            None => continue,
+        };
+        let mut parent = "";
+        for component in path.split('/') {
+            let found = if let Some(id) = component.strip_prefix("libpod-conmon-") {
+                Some((Runtime::Podman, id.strip_suffix(".scope").unwrap_or(id))).filter(|_| conmon)
+            } else if conmon {
+                None
+            } else if let Some(id) = component.strip_prefix("libpod-") {
+                Some((Runtime::Podman, id.strip_suffix(".scope").unwrap_or(id)))
+            } else if let Some(id) = component.strip_prefix("docker-") {
+                Some((Runtime::Docker, id.strip_suffix(".scope").unwrap_or(id)))
+            } else if parent == "docker" {
+                Some((Runtime::Docker, component))
+            } else {
+                None
+            };
+            if let Some((runtime, id)) = found {
+                if is_id(id) {
+                    return Some((runtime, id));
+                }
             }
+            parent = component;
         }
     }
 
-    return Ok(None);
+    None
+}
+
+// The name and image of a container don't change, so we only need to run
+// podman inspect once for each container id. An entry is dropped once its
+// container stops being found, since the container may have been removed.
+struct InspectCache {
+    entries: HashMap<String, ContainerInfo>,
+    // Looked up since the last expire_unseen()
+    seen: HashSet<String>,
+}
+
+impl InspectCache {
+    fn new() -> Self {
+        InspectCache {
+            entries: HashMap::new(),
+            seen: HashSet::new(),
+        }
+    }
+
+    fn get_or_inspect<F>(
+        &mut self,
+        container_id: &str,
+        inspect: F,
+    ) -> io::Result<Option<ContainerInfo>>
+    where
+        F: FnOnce(&str) -> io::Result<Option<ContainerInfo>>,
+    {
+        self.seen.insert(container_id.to_string());
+        if let Some(container_info) = self.entries.get(container_id) {
+            return Ok(Some(container_info.clone()));
+        }
+
+        // Failures aren't cached: the container may not be running yet, or
+        // podman may be busy, and the next lookup can try again
+        let result = inspect(container_id);
+        if let Ok(Some(container_info)) = &result {
+            self.entries
+                .insert(container_id.to_string(), container_info.clone());
+        }
+
+        result
+    }
+
+    fn expire_unseen(&mut self) {
+        let seen = &self.seen;
+        self.entries
+            .retain(|container_id, _| seen.contains(container_id));
+        self.seen.clear();
+    }
+}
+
+/// Forgets the results of inspecting containers that haven't been looked up
+/// since the last call; called once for each update of the state
+pub fn expire_container_info() {
+    INSPECT_CACHE.lock().unwrap().expire_unseen();
+}
+
+/// Looks up the container with podman (or docker) inspect, or from the
+/// results of a previous lookup
+pub fn get_container_info_for_id(
+    runtime: Runtime,
+    container_id: &str,
+) -> io::Result<Option<ContainerInfo>> {
+    let mut cache = INSPECT_CACHE.lock().unwrap();
+    cache.get_or_inspect(container_id, |container_id| {
+        inspect_container(runtime, container_id)
+    })
+}
+
+fn inspect_container(runtime: Runtime, container_id: &str) -> io::Result<Option<ContainerInfo>> {
+    let mut command = Command::new(runtime.command());
+    command.arg("inspect").arg("--type").arg("container");
+    // Rather than an array, just the container's object
+    if runtime == Runtime::Docker {
+        command.arg("--format").arg("{{json .}}");
+    }
+    command.arg(container_id);
+    let (success, stdout) = output_with_timeout(&mut command, INSPECT_TIMEOUT)?;
+
+    if !success {
+        return Ok(None);
+    }
+
+    let mut container_info = match runtime {
+        Runtime::Podman => parse_inspect_output(container_id, &stdout),
+        Runtime::Docker => parse_docker_inspect_output(container_id, &stdout),
+    };
+    if let Some(container_info) = &mut container_info {
+        if !is_image_name(&container_info.image_name, &container_info.image_id) {
+            container_info.image_name = image_name(runtime, &container_info.image_id);
+        }
+    }
+
+    Ok(container_info)
+}
+
+// Whether the image name we got with the container is usable: depending on
+// the version, podman may not give one, and docker gives whatever the
+// container was created from, which might be the id
+fn is_image_name(image_name: &str, image_id: &str) -> bool {
+    let name = image_name.strip_prefix("sha256:").unwrap_or(image_name);
+    let id = image_id.strip_prefix("sha256:").unwrap_or(image_id);
+    !name.is_empty() && !id.starts_with(name)
+}
+
+// The image's first tag, like registry.fedoraproject.org/fedora-toolbox:34,
+// or its short id if it has no tags
+fn image_name(runtime: Runtime, image_id: &str) -> String {
+    let mut command = Command::new(runtime.command());
+    command.arg("image").arg("inspect").arg(image_id);
+    let tag = match output_with_timeout(&mut command, INSPECT_TIMEOUT) {
+        Ok((true, stdout)) => parse_image_inspect_output(&stdout),
+        Ok((false, _)) => None,
+        Err(e) => {
+            info!("Can't inspect image {}: {}", image_id, e);
+            None
+        }
+    };
+
+    tag.unwrap_or_else(|| short_image_id(image_id))
+}
+
+// podman and docker image inspect print a JSON array with an object for each
+// image; a tag of <none> is what an untagged image shows.
+fn parse_image_inspect_output(output: &[u8]) -> Option<String> {
+    let inspect: Value = serde_json::from_slice(output).ok()?;
+    inspect[0]["RepoTags"]
+        .as_array()?
+        .iter()
+        .filter_map(|tag| tag.as_str())
+        .find(|tag| !tag.contains("<none>"))
+        .map(String::from)
+}
+
+// Like the 12 character ids that podman and docker show
+pub fn short_image_id(image_id: &str) -> String {
+    let id = image_id.strip_prefix("sha256:").unwrap_or(image_id);
+    id.chars().take(12).collect()
+}
+
+// Like Command::output(), but kills the command if it hasn't finished writing
+// its output and exited within timeout. Returns whether it succeeded, and its
+// stdout.
+pub fn output_with_timeout(
+    command: &mut Command,
+    timeout: Duration,
+) -> io::Result<(bool, Vec<u8>)> {
+    let mut child = command
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()?;
+
+    // Read in a thread, so that a large output can't fill the pipe and
+    // block the command while we wait for it
+    let mut stdout = child.stdout.take().unwrap();
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        let mut buf = vec![];
+        let result = stdout.read_to_end(&mut buf).map(|_| buf);
+        let _ = sender.send(result);
+    });
+
+    let deadline = Instant::now() + timeout;
+    if let Ok(result) = receiver.recv_timeout(timeout) {
+        let stdout = result?;
+        // The command can close its stdout, or pass it on, and keep running
+        loop {
+            if let Some(status) = child.try_wait()? {
+                return Ok((status.success(), stdout));
+            }
+            if Instant::now() >= deadline {
+                break;
+            }
+            thread::sleep(EXIT_POLL_INTERVAL);
+        }
+    }
+
+    let _ = child.kill();
+    let _ = child.wait();
+    Err(io::Error::new(
+        io::ErrorKind::TimedOut,
+        "Timed out waiting for command",
+    ))
+}
+
+// Some versions of podman, like docker, give the name with a leading /
+fn container_name(name: &str) -> String {
+    String::from(name.strip_prefix('/').unwrap_or(name))
+}
+
+// podman inspect prints a JSON array with an object for each container
+fn parse_inspect_output(container_id: &str, output: &[u8]) -> Option<ContainerInfo> {
+    let inspect: Value = match serde_json::from_slice(output) {
+        Ok(inspect) => inspect,
+        Err(e) => {
+            info!("Can't parse podman inspect output: {}", e);
+            return None;
+        }
+    };
+    let container = &inspect[0];
+
+    Some(ContainerInfo {
+        container_id: String::from(container_id),
+        container_name: container_name(container["Name"].as_str()?),
+        image_id: String::from(container["Image"].as_str().unwrap_or("")),
+        image_name: String::from(container["ImageName"].as_str().unwrap_or("")),
+    })
+}
+
+// docker inspect --format '{{json .}}' prints the container's object. The name
+// has a leading /, and the image name is in the config.
+fn parse_docker_inspect_output(container_id: &str, output: &[u8]) -> Option<ContainerInfo> {
+    let container: Value = match serde_json::from_slice(output) {
+        Ok(container) => container,
+        Err(e) => {
+            info!("Can't parse docker inspect output: {}", e);
+            return None;
+        }
+    };
+
+    Some(ContainerInfo {
+        container_id: String::from(container_id),
+        container_name: container_name(container["Name"].as_str()?),
+        image_id: String::from(container["Image"].as_str().unwrap_or("")),
+        image_name: String::from(container["Config"]["Image"].as_str().unwrap_or("")),
+    })
 }
 
 fn get_container_info(conmon_pid: i32) -> io::Result<Option<ContainerInfo>> {
@@ -118,7 +439,8 @@ fn get_container_info(conmon_pid: i32) -> io::Result<Option<ContainerInfo>> {
         match arg_iter.next() {
             Some(b"-c") => {
                 if let Some(id) = arg_iter.next() {
-                    return get_container_info_for_id(id);
+                    let container_id = String::from_utf8_lossy(id);
+                    return get_container_info_for_id(Runtime::Podman, &container_id);
                 }
             }
             Some(_) => (),
@@ -126,5 +448,275 @@ fn get_container_info(conmon_pid: i32) -> io::Result<Option<ContainerInfo>> {
         }
     }
 
+    // With the systemd cgroup manager, the cgroup names the container too
+    if let Ok(cgroup) = process.cgroup() {
+        if let Some((runtime, container_id)) = find_container_id(&cgroup, true) {
+            return get_container_info_for_id(runtime, container_id);
+        }
+    }
+
     return Ok(None);
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ID: &str = "4d8a3e6f0b1c2d3e4f5a6b7c8d9e0f1a2b3c4d5e6f7a8b9c0d1e2f3a4b5c6d7e";
+
+    #[test]
+    fn test_conmon_container_id_from_cgroup() {
+        let cgroup = format!("0::/machine.slice/libpod-conmon-{}.scope\n", ID);
+        assert_eq!(
+            find_container_id(&cgroup, true),
+            Some((Runtime::Podman, ID))
+        );
+        // The container's own processes aren't conmon
+        let cgroup = format!("0::/machine.slice/libpod-{}.scope\n", ID);
+        assert_eq!(find_container_id(&cgroup, true), None);
+        assert_eq!(
+            find_container_id("0::/libpod-conmon-1234.scope\n", true),
+            None
+        );
+        assert_eq!(find_container_id("", true), None);
+    }
+
+    #[test]
+    fn test_container_id_from_cgroup() {
+        // cgroup v2, systemd
+        let cgroup = format!(
+            "0::/user.slice/user-1000.slice/user@1000.service/user.slice/libpod-{}.scope/container\n",
+            ID
+        );
+        assert_eq!(
+            container_id_from_cgroup(&cgroup),
+            Some((Runtime::Podman, ID))
+        );
+        let cgroup = format!("0::/machine.slice/libpod-{}.scope\n", ID);
+        assert_eq!(
+            container_id_from_cgroup(&cgroup),
+            Some((Runtime::Podman, ID))
+        );
+
+        // cgroup v1, cgroupfs
+        let cgroup = format!(
+            "12:pids:/libpod_parent/libpod-{}\n11:cpu,cpuacct:/libpod_parent/libpod-{}\n",
+            ID, ID
+        );
+        assert_eq!(
+            container_id_from_cgroup(&cgroup),
+            Some((Runtime::Podman, ID))
+        );
+
+        // conmon, outside the container
+        let cgroup = format!(
+            "0::/user.slice/user-1000.slice/user@1000.service/user.slice/libpod-conmon-{}.scope\n",
+            ID
+        );
+        assert_eq!(container_id_from_cgroup(&cgroup), None);
+
+        assert_eq!(
+            container_id_from_cgroup(
+                "0::/user.slice/user-1000.slice/user@1000.service/app.slice/app-gnome-terminal.scope\n"
+            ),
+            None
+        );
+        // docker, with systemd and cgroupfs
+        let cgroup = format!("0::/system.slice/docker-{}.scope\n", ID);
+        assert_eq!(
+            container_id_from_cgroup(&cgroup),
+            Some((Runtime::Docker, ID))
+        );
+        let cgroup = format!("12:pids:/docker/{}\n", ID);
+        assert_eq!(
+            container_id_from_cgroup(&cgroup),
+            Some((Runtime::Docker, ID))
+        );
+        assert_eq!(
+            container_id_from_cgroup("0::/system.slice/docker.service\n"),
+            None
+        );
+        assert_eq!(container_id_from_cgroup(&format!("0::/{}\n", ID)), None);
+
+        assert_eq!(container_id_from_cgroup("0::/libpod-1234.scope\n"), None);
+        assert_eq!(container_id_from_cgroup(""), None);
+    }
+
+    #[test]
+    fn test_parse_inspect_output() {
+        let output = br#"[
+            {
+                "Id": "0f3c5a",
+                "Created": "2021-05-04T10:11:12.345Z",
+                "Name": "fedora toolbox 34",
+                "Image": "8a7b6c",
+                "ImageName": "registry.fedoraproject.org/fedora-toolbox:34",
+                "Config": { "Labels": { "com.github.containers.toolbox": "true" } }
+            }
+        ]"#;
+        let info = parse_inspect_output("0f3c5a", output).unwrap();
+        assert_eq!(info.container_id, "0f3c5a");
+        assert_eq!(info.container_name, "fedora toolbox 34");
+        assert_eq!(info.image_id, "8a7b6c");
+        assert_eq!(
+            info.image_name,
+            "registry.fedoraproject.org/fedora-toolbox:34"
+        );
+
+        // Missing image fields are left empty
+        let info =
+            parse_inspect_output("0f3c5a", br#"[{"Name": "box", "ImageName": null}]"#).unwrap();
+        assert_eq!(info.container_name, "box");
+        assert_eq!(info.image_name, "");
+
+        let info = parse_inspect_output("0f3c5a", br#"[{"Name": "/box"}]"#).unwrap();
+        assert_eq!(info.container_name, "box");
+
+        assert!(parse_inspect_output("0f3c5a", b"[]").is_none());
+        assert!(parse_inspect_output("0f3c5a", b"Error: no such container").is_none());
+    }
+
+    #[test]
+    fn test_parse_docker_inspect_output() {
+        let output = br#"{
+            "Id": "0f3c5a",
+            "Name": "/quirky_turing",
+            "Image": "sha256:8a7b6c",
+            "Config": { "Image": "ubuntu:22.04" }
+        }"#;
+        let info = parse_docker_inspect_output("0f3c5a", output).unwrap();
+        assert_eq!(info.container_id, "0f3c5a");
+        assert_eq!(info.container_name, "quirky_turing");
+        assert_eq!(info.image_id, "sha256:8a7b6c");
+        assert_eq!(info.image_name, "ubuntu:22.04");
+
+        assert!(parse_docker_inspect_output("0f3c5a", b"{}").is_none());
+        assert!(parse_docker_inspect_output("0f3c5a", b"").is_none());
+    }
+
+    #[test]
+    fn test_image_name() {
+        let id = "sha256:8a7b6c5d4e3f2a1b0c9d8e7f";
+        assert!(is_image_name("ubuntu:22.04", id));
+        assert!(!is_image_name("", id));
+        assert!(!is_image_name("8a7b6c5d4e3f", id));
+        assert!(!is_image_name(id, id));
+
+        assert_eq!(short_image_id(id), "8a7b6c5d4e3f");
+        assert_eq!(short_image_id("8a7b"), "8a7b");
+
+        let output = br#"[
+            {
+                "Id": "8a7b6c5d4e3f2a1b0c9d8e7f",
+                "RepoTags": [
+                    "<none>:<none>",
+                    "registry.fedoraproject.org/fedora-toolbox:34",
+                    "localhost/toolbox:latest"
+                ]
+            }
+        ]"#;
+        assert_eq!(
+            parse_image_inspect_output(output).as_deref(),
+            Some("registry.fedoraproject.org/fedora-toolbox:34")
+        );
+        assert_eq!(parse_image_inspect_output(br#"[{"RepoTags": []}]"#), None);
+        assert_eq!(parse_image_inspect_output(br#"[{"RepoTags": null}]"#), None);
+        assert_eq!(parse_image_inspect_output(b"Error: no such image"), None);
+    }
+
+    #[test]
+    fn test_output_with_timeout() {
+        let (success, stdout) = output_with_timeout(
+            Command::new("/bin/sh").arg("-c").arg("echo hello"),
+            INSPECT_TIMEOUT,
+        )
+        .unwrap();
+        assert!(success);
+        assert_eq!(stdout, b"hello\n");
+
+        let (success, _) =
+            output_with_timeout(&mut Command::new("/bin/false"), INSPECT_TIMEOUT).unwrap();
+        assert!(!success);
+
+        let start = std::time::Instant::now();
+        let result = output_with_timeout(
+            Command::new("/bin/sh").arg("-c").arg("exec sleep 10"),
+            Duration::from_millis(100),
+        );
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::TimedOut);
+        assert!(start.elapsed() < Duration::from_secs(5));
+
+        // Done with its output, but not exiting
+        let start = std::time::Instant::now();
+        let result = output_with_timeout(
+            Command::new("/bin/sh").arg("-c").arg("exec >&-; sleep 10"),
+            Duration::from_millis(100),
+        );
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::TimedOut);
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn test_inspect_cache() {
+        let info = |name: &str| ContainerInfo {
+            container_id: String::from("0f3c5a"),
+            container_name: String::from(name),
+            image_id: String::new(),
+            image_name: String::new(),
+        };
+        let name = |result: io::Result<Option<ContainerInfo>>| {
+            result.unwrap().map(|info| info.container_name)
+        };
+
+        let mut cache = InspectCache::new();
+        let result = cache.get_or_inspect("0f3c5a", |_| Ok(Some(info("box"))));
+        assert_eq!(name(result).as_deref(), Some("box"));
+
+        // Found in the cache, without inspecting again
+        let result = cache.get_or_inspect("0f3c5a", |_| panic!("inspected twice"));
+        assert_eq!(name(result).as_deref(), Some("box"));
+
+        // A failed lookup of another container isn't cached
+        let result = cache.get_or_inspect("1a2b3c", |_| Ok(None));
+        assert_eq!(name(result), None);
+        let result = cache.get_or_inspect("1a2b3c", |_| Ok(Some(info("other"))));
+        assert_eq!(name(result).as_deref(), Some("other"));
+
+        // Nor is an error
+        let mut cache = InspectCache::new();
+        let result = cache.get_or_inspect("0f3c5a", |_| {
+            Err(io::Error::new(io::ErrorKind::NotFound, "no podman"))
+        });
+        assert!(result.is_err());
+        let result = cache.get_or_inspect("0f3c5a", |_| Ok(Some(info("box"))));
+        assert_eq!(name(result).as_deref(), Some("box"));
+    }
+
+    #[test]
+    fn test_inspect_cache_expire() {
+        let info = ContainerInfo {
+            container_id: String::from("0f3c5a"),
+            container_name: String::from("box"),
+            image_id: String::new(),
+            image_name: String::new(),
+        };
+
+        let mut cache = InspectCache::new();
+        cache
+            .get_or_inspect("0f3c5a", |_| Ok(Some(info.clone())))
+            .unwrap();
+
+        // Still found in the update after it was inspected...
+        cache.expire_unseen();
+        cache
+            .get_or_inspect("0f3c5a", |_| panic!("inspected twice"))
+            .unwrap();
+
+        // ...but not after an update where it wasn't, so it's inspected again,
+        // and if it's gone, that's what we find out
+        cache.expire_unseen();
+        cache.expire_unseen();
+        let result = cache.get_or_inspect("0f3c5a", |_| Ok(None)).unwrap();
+        assert!(result.is_none());
+    }
+}
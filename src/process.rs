@@ -1,4 +1,7 @@
+use nix::errno::Errno;
 use regex::Regex;
+use std::cell::RefCell;
+use std::collections::HashMap;
 use std::fs;
 use std::io::{self, Read};
 use std::path::{Path, PathBuf};
@@ -9,10 +12,26 @@ lazy_static! {
     static ref SOCKET_RE: Regex = Regex::new(r"^socket:\[(\d+)\]$").unwrap();
 }
 
+// Where everything is read from. The functions below that take a proc_root
+// instead are so tests can use a fake tree of processes.
+const PROC_ROOT: &str = "/proc";
+
+// (uid_t)-1, which the kernel reports when the loginuid isn't set
+const UNSET_LOGINUID: u32 = u32::MAX;
+
 #[derive(Debug)]
 pub struct Process {
     pid: i32,
     proc_path: std::path::PathBuf,
+    // The stat file is read once and kept, so the accessors that use it
+    // see a consistent view
+    stat: RefCell<Option<Stat>>,
+}
+
+// Whether an error reading /proc is because the process (or one of its fds)
+// went away while we were looking at it, which happens all the time
+fn is_vanished(e: &io::Error) -> bool {
+    e.kind() == io::ErrorKind::NotFound || e.raw_os_error() == Some(Errno::ESRCH as i32)
 }
 
 struct ProcessIterator {
@@ -20,9 +39,9 @@ struct ProcessIterator {
 }
 
 impl ProcessIterator {
-    fn new() -> io::Result<ProcessIterator> {
+    fn new(proc_root: &Path) -> io::Result<ProcessIterator> {
         Ok(ProcessIterator {
-            read_dir: fs::read_dir(Path::new("/proc"))?,
+            read_dir: fs::read_dir(proc_root)?,
         })
     }
 }
@@ -34,6 +53,7 @@ impl Iterator for ProcessIterator {
         loop {
             let entry = match self.read_dir.next() {
                 Some(Ok(x)) => x,
+                Some(Err(e)) if is_vanished(&e) => continue,
                 Some(Err(e)) => return Some(Err(e)),
                 None => {
                     return None;
@@ -42,16 +62,26 @@ impl Iterator for ProcessIterator {
 
             if let Some(file_name) = entry.file_name().to_str() {
                 if ALL_NUMBERS_RE.is_match(file_name) {
-                    return Some(Ok(Process {
-                        pid: file_name.parse().unwrap(),
-                        proc_path: entry.path(),
-                    }));
+                    if let Ok(pid) = file_name.parse() {
+                        return Some(Ok(Process::with_proc_path(pid, &entry.path())));
+                    }
                 }
             }
         }
     }
 }
 
+/// Normalizes argv[0] to a bare command name: programs may set argv[0] to a
+/// full path (/usr/bin/bash), a bare name (bash), or, for a login shell, the
+/// name prefixed with '-' (-bash). All of these give "bash".
+pub fn command_name(argv0: &str) -> &str {
+    let name = argv0.strip_prefix('-').unwrap_or(argv0);
+    match name.rfind('/') {
+        Some(pos) => &name[pos + 1..],
+        None => name,
+    }
+}
+
 pub struct Args(Vec<u8>);
 
 impl<'a> IntoIterator for &'a Args {
@@ -63,60 +93,90 @@ impl<'a> IntoIterator for &'a Args {
     }
 }
 
-struct StatParser(Vec<u8>);
-
-impl StatParser {
-    fn new(proc_path: &Path) -> io::Result<StatParser> {
-        let mut f = fs::File::open(proc_path.join("stat"))?;
-
-        let mut s = StatParser(vec![]);
-        f.read_to_end(&mut s.0)?;
-
-        return Ok(s);
-    }
+/// The fields of /proc/<pid>/stat that we use; see proc(5)
+#[allow(dead_code)]
+#[derive(Clone, Debug, PartialEq)]
+pub struct Stat {
+    pub pid: i32,
+    /// The command name, truncated by the kernel to 15 bytes
+    pub comm: String,
+    pub state: char,
+    pub ppid: i32,
+    pub pgrp: i32,
+    pub session: i32,
+    pub tty_nr: i32,
+    /// The foreground process group of the controlling terminal
+    pub tty_pgrp: i32,
+    /// In clock ticks since boot
+    pub start_time: u64,
+}
 
-    fn parse<'a>(&'a self) -> io::Result<Vec<&'a [u8]>> {
-        const NOT_FOUND: usize = (-1isize) as usize;
+fn parse_field<T: FromStr>(field: Option<&[u8]>, name: &str) -> io::Result<T> {
+    field
+        .and_then(|field| std::str::from_utf8(field).ok())
+        .and_then(|field| field.parse().ok())
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::Other,
+                format!("Can't parse {} from /proc/stat", name),
+            )
+        })
+}
 
-        let mut open_paren = NOT_FOUND;
-        for i in 1..self.0.len() {
-            if self.0[i - 1] == 0x20 && self.0[i] == 0x28 {
-                open_paren = i;
-                break;
+impl Stat {
+    /// Parses the contents of a /proc/<pid>/stat file. The command name can
+    /// contain anything, including spaces and parentheses, so it's found as
+    /// the text between the first '(' and the last ')'.
+    pub fn parse(data: &[u8]) -> io::Result<Stat> {
+        let open_paren = data.iter().position(|c| *c == b'(');
+        let close_paren = data.iter().rposition(|c| *c == b')');
+        let (open_paren, close_paren) = match (open_paren, close_paren) {
+            (Some(open_paren), Some(close_paren)) if open_paren < close_paren => {
+                (open_paren, close_paren)
             }
-        }
-        let mut close_paren = NOT_FOUND;
-        for i in (0..self.0.len() - 1).rev() {
-            if self.0[i] == 0x29 && self.0[i + 1] == 0x20 {
-                close_paren = i;
-                break;
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    "Can't parse /proc/stat",
+                ))
             }
-        }
-
-        if open_paren != NOT_FOUND && close_paren != NOT_FOUND {
-            let mut fields: Vec<&'a [u8]> = vec![];
-
-            fields.push(&self.0[0..open_paren - 1]);
-            fields.push(&self.0[open_paren + 1..close_paren]);
-            let mut remaining: Vec<&'a [u8]> =
-                self.0[close_paren + 2..].split(|x| *x == 0x20).collect();
-            fields.append(&mut remaining);
+        };
 
-            Ok(fields)
-        } else {
-            Err(io::Error::new(
-                io::ErrorKind::Other,
-                "Can't parse /proc/stat",
-            ))
-        }
+        let pid = parse_field(data[..open_paren].split(|c| *c == b' ').next(), "pid")?;
+        let comm = String::from_utf8_lossy(&data[open_paren + 1..close_paren]).into_owned();
+
+        // Fields after the command name, starting with the state (field 3 in
+        // proc(5), counting from 1)
+        let fields: Vec<&[u8]> = data[close_paren + 1..]
+            .split(|c| c.is_ascii_whitespace())
+            .filter(|field| !field.is_empty())
+            .collect();
+        let field = |number: usize| fields.get(number - 3).copied();
+
+        Ok(Stat {
+            pid,
+            comm,
+            state: parse_field(field(3), "state")?,
+            ppid: parse_field(field(4), "ppid")?,
+            pgrp: parse_field(field(5), "pgrp")?,
+            session: parse_field(field(6), "session")?,
+            tty_nr: parse_field(field(7), "tty_nr")?,
+            tty_pgrp: parse_field(field(8), "tty_pgrp")?,
+            start_time: parse_field(field(22), "starttime")?,
+        })
     }
 }
 
 impl Process {
     pub fn new(pid: i32) -> Self {
+        Process::with_proc_path(pid, &Path::new(PROC_ROOT).join(pid.to_string()))
+    }
+
+    fn with_proc_path(pid: i32, proc_path: &Path) -> Self {
         Process {
-            pid: pid,
-            proc_path: Path::new("/proc").join(pid.to_string()),
+            pid,
+            proc_path: proc_path.to_path_buf(),
+            stat: RefCell::new(None),
         }
     }
 
@@ -124,29 +184,23 @@ impl Process {
     where
         P: Fn(&Process) -> bool,
     {
-        for process in ProcessIterator::new()? {
-            let process = process?;
-            if pred(&process) {
-                return Ok(Some(process));
-            }
-        }
+        find_in(Path::new(PROC_ROOT), pred)
+    }
 
-        return Ok(None);
+    /// The processes whose parent is ppid, in order of pid
+    pub fn children(ppid: i32) -> io::Result<Vec<Process>> {
+        children_in(Path::new(PROC_ROOT), ppid)
     }
 
     pub fn list_process_group(pgrp: i32) -> io::Result<Vec<i32>> {
-        let mut result: Vec<i32> = vec![];
-
-        for process in ProcessIterator::new()? {
-            let process = process?;
-            if let Ok(process_pgrp) = process.process_group() {
-                if process_pgrp == pgrp {
-                    result.push(process.pid);
-                }
-            }
-        }
+        list_process_group_in(Path::new(PROC_ROOT), pgrp)
+    }
 
-        return Ok(result);
+    /// A live process to read the state of a process group from: the group
+    /// leader, unless it has exited while other members of the group are
+    /// still running.
+    pub fn group_representative(pgrp: i32) -> Process {
+        group_representative_in(Path::new(PROC_ROOT), pgrp)
     }
 
     pub fn cmdline(&self) -> io::Result<Args> {
@@ -169,16 +223,136 @@ impl Process {
         };
     }
 
+    /// All the arguments, argv[0] included; arguments that aren't UTF-8 are
+    /// converted lossily
+    pub fn argv(&self) -> io::Result<Vec<String>> {
+        let args = self.cmdline()?;
+        let mut argv: Vec<String> = args
+            .into_iter()
+            .map(|arg| String::from_utf8_lossy(arg).into_owned())
+            .collect();
+        // Each argument is NUL-terminated, giving an empty one at the end
+        if argv.last().map(String::as_str) == Some("") {
+            argv.pop();
+        }
+
+        Ok(argv)
+    }
+
+    /// The environment the process was started with; anything that isn't
+    /// UTF-8 is converted lossily. Changes the process made to its
+    /// environment after it started aren't seen. Only the owner of a process
+    /// can read its environment.
+    pub fn environ(&self) -> io::Result<HashMap<String, String>> {
+        let environ = match fs::read(self.proc_path.join("environ")) {
+            Ok(environ) => environ,
+            Err(e) if e.kind() == io::ErrorKind::PermissionDenied => {
+                return Err(io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    format!("Can't read the environment of process {}", self.pid),
+                ))
+            }
+            Err(e) => return Err(e),
+        };
+
+        let mut result = HashMap::new();
+        for var in environ.split(|x| *x == 0) {
+            if let Some(pos) = var.iter().position(|x| *x == b'=') {
+                // Like getenv(), the first definition wins
+                result
+                    .entry(String::from_utf8_lossy(&var[..pos]).into_owned())
+                    .or_insert_with(|| String::from_utf8_lossy(&var[pos + 1..]).into_owned());
+            }
+        }
+
+        Ok(result)
+    }
+
+    /// The command name from argv[0] - see command_name()
+    pub fn command(&self) -> io::Result<String> {
+        Ok(command_name(&self.argv0()?).to_string())
+    }
+
+    /// The effective uid of the process
+    pub fn euid(&self) -> io::Result<u32> {
+        let status = fs::read_to_string(self.proc_path.join("status"))?;
+        // Uid:	<real>	<effective>	<saved>	<filesystem>
+        for line in status.lines() {
+            if let Some(uids) = line.strip_prefix("Uid:") {
+                if let Some(Ok(euid)) = uids.split_whitespace().nth(1).map(|uid| uid.parse()) {
+                    return Ok(euid);
+                }
+            }
+        }
+
+        Err(io::Error::new(
+            io::ErrorKind::Other,
+            "Can't parse uid from /proc/status",
+        ))
+    }
+
+    /// The audit login uid - the user who originally logged in, which stays
+    /// the same across sudo and su. None if it was never set, as for
+    /// processes not descended from a login.
+    pub fn loginuid(&self) -> io::Result<Option<u32>> {
+        let loginuid = fs::read_to_string(self.proc_path.join("loginuid"))?;
+        match loginuid.trim().parse() {
+            Ok(UNSET_LOGINUID) => Ok(None),
+            Ok(uid) => Ok(Some(uid)),
+            Err(_) => Err(io::Error::new(
+                io::ErrorKind::Other,
+                "Can't parse /proc/loginuid",
+            )),
+        }
+    }
+
+    /// The terminal the process has as its stdin, if any
+    pub fn tty_path(&self) -> Option<PathBuf> {
+        let path = fs::read_link(self.proc_path.join("fd").join("0")).ok()?;
+        if path.starts_with("/dev/pts") || path.starts_with("/dev/tty") {
+            Some(path)
+        } else {
+            None
+        }
+    }
+
+    /// Whether the process has path open as one of its fds
+    pub fn has_open(&self, path: &Path) -> bool {
+        let entries = match fs::read_dir(self.proc_path.join("fd")) {
+            Ok(entries) => entries,
+            Err(_) => return false,
+        };
+        entries
+            .filter_map(|entry| fs::read_link(entry.ok()?.path()).ok())
+            .any(|link| link == path)
+    }
+
+    pub fn open_fd_count(&self) -> io::Result<usize> {
+        Ok(fs::read_dir(self.proc_path.join("fd"))?.count())
+    }
+
+    /// The inodes of the sockets the process has open. A process that has
+    /// exited has none.
     pub fn list_sockets(&self) -> io::Result<Vec<u32>> {
         let mut result = Vec::new();
 
-        for entry in fs::read_dir(self.proc_path.join("fd"))? {
-            let entry = entry?;
-            let link = fs::read_link(entry.path())?;
+        let entries = match fs::read_dir(self.proc_path.join("fd")) {
+            Ok(entries) => entries,
+            Err(e) if is_vanished(&e) => return Ok(result),
+            Err(e) => return Err(e),
+        };
+        for entry in entries {
+            // An fd that was closed since we listed the directory is skipped
+            let link = match entry.and_then(|entry| fs::read_link(entry.path())) {
+                Ok(link) => link,
+                Err(e) if is_vanished(&e) => continue,
+                Err(e) => return Err(e),
+            };
             if let Some(link_str) = link.to_str() {
                 if let Some(captures) = SOCKET_RE.captures(link_str) {
-                    let socket: u32 = captures.get(1).unwrap().as_str().parse().unwrap();
-                    result.push(socket);
+                    if let Ok(socket) = captures[1].parse() {
+                        result.push(socket);
+                    }
                 }
             }
         }
@@ -186,41 +360,485 @@ impl Process {
         return Ok(result);
     }
 
-    fn get_stat_field<T: FromStr>(&self, index: usize, name: &str) -> io::Result<T> {
-        let stat_parser = StatParser::new(&self.proc_path)?;
-        let fields = stat_parser.parse()?;
-
-        if fields.len() > index {
-            if let Ok(ppid_field) = std::str::from_utf8(fields[index]) {
-                if let Ok(ppid) = ppid_field.parse() {
-                    return Ok(ppid);
-                }
-            }
+    /// The parsed stat file. This is only read the first time; create a new
+    /// Process to see changes.
+    pub fn stat(&self) -> io::Result<Stat> {
+        if let Some(stat) = &*self.stat.borrow() {
+            return Ok(stat.clone());
         }
 
-        return Err(io::Error::new(
-            io::ErrorKind::Other,
-            format!("Can't parse {} from /proc/stat", name),
-        ));
+        let stat = Stat::parse(&fs::read(self.proc_path.join("stat"))?)?;
+        *self.stat.borrow_mut() = Some(stat.clone());
+
+        Ok(stat)
     }
 
     pub fn parent(&self) -> io::Result<i32> {
-        self.get_stat_field(3, "ppid")
+        self.stat().map(|stat| stat.ppid)
     }
 
     pub fn process_group(&self) -> io::Result<i32> {
-        self.get_stat_field(4, "pgrp")
+        self.stat().map(|stat| stat.pgrp)
+    }
+
+    /// The device number of the controlling terminal, 0 if there's none;
+    /// see tty_nr_from_rdev()
+    pub fn tty_nr(&self) -> io::Result<i32> {
+        self.stat().map(|stat| stat.tty_nr)
     }
 
     pub fn tty_process_group(&self) -> io::Result<i32> {
-        self.get_stat_field(7, "tty_pgrp")
+        self.stat().map(|stat| stat.tty_pgrp)
+    }
+
+    /// When the process started, in clock ticks since boot. Together with the
+    /// pid, this identifies a process even if the pid is later reused.
+    pub fn start_time(&self) -> io::Result<u64> {
+        self.stat().map(|stat| stat.start_time)
+    }
+
+    /// The process state: R (running), S (sleeping), Z (zombie), and so forth
+    pub fn state(&self) -> io::Result<char> {
+        self.stat().map(|stat| stat.state)
+    }
+
+    /// False if the process is gone or a zombie - in which case there's
+    /// nothing left to read but the stat file
+    pub fn is_alive(&self) -> bool {
+        match self.state() {
+            Ok(state) => state != 'Z' && state != 'X',
+            Err(_) => false,
+        }
     }
 
     pub fn cwd(&self) -> io::Result<PathBuf> {
         fs::read_link(self.proc_path.join("cwd"))
     }
 
+    /// The contents of /proc/<pid>/cgroup: a line for each hierarchy, or
+    /// just one (0::<path>) with cgroup v2
+    pub fn cgroup(&self) -> io::Result<String> {
+        fs::read_to_string(self.proc_path.join("cgroup"))
+    }
+
+    /// The root directory of the process, as seen from our root
+    pub fn root(&self) -> io::Result<PathBuf> {
+        fs::read_link(self.proc_path.join("root"))
+    }
+
+    /// Identifies the mount namespace of the process (like mnt:[4026531841]);
+    /// processes in the same namespace get the same value.
+    pub fn mount_namespace(&self) -> io::Result<PathBuf> {
+        fs::read_link(self.proc_path.join("ns/mnt"))
+    }
+
     pub fn pid(&self) -> i32 {
         self.pid
     }
 }
+
+fn find_in<P>(proc_root: &Path, pred: P) -> io::Result<Option<Process>>
+where
+    P: Fn(&Process) -> bool,
+{
+    for process in ProcessIterator::new(proc_root)? {
+        let process = process?;
+        if pred(&process) {
+            return Ok(Some(process));
+        }
+    }
+
+    return Ok(None);
+}
+
+// Zombies are left out, since there's nothing to find out about them
+fn list_process_group_in(proc_root: &Path, pgrp: i32) -> io::Result<Vec<i32>> {
+    Ok(process_group_members(proc_root, pgrp)?
+        .iter()
+        .filter(|process| process.is_alive())
+        .map(|process| process.pid)
+        .collect())
+}
+
+// The members of a process group, in order of pid
+fn process_group_members(proc_root: &Path, pgrp: i32) -> io::Result<Vec<Process>> {
+    let mut result: Vec<Process> = vec![];
+
+    for process in ProcessIterator::new(proc_root)? {
+        let process = process?;
+        if let Ok(process_pgrp) = process.process_group() {
+            if process_pgrp == pgrp {
+                result.push(process);
+            }
+        }
+    }
+    result.sort_by_key(|process| process.pid);
+
+    Ok(result)
+}
+
+fn children_in(proc_root: &Path, ppid: i32) -> io::Result<Vec<Process>> {
+    let mut result: Vec<Process> = vec![];
+
+    for process in ProcessIterator::new(proc_root)? {
+        let process = process?;
+        if let Ok(process_ppid) = process.parent() {
+            if process_ppid == ppid {
+                result.push(process);
+            }
+        }
+    }
+    result.sort_by_key(|process| process.pid);
+
+    Ok(result)
+}
+
+/// Converts a device number as stat() gives it (st_rdev) to how
+/// /proc/<pid>/stat shows the controlling terminal's; they only differ for
+/// major numbers too large for the kernel's 32-bit encoding
+pub fn tty_nr_from_rdev(rdev: u64) -> i32 {
+    let major = ((rdev >> 8) & 0xfff) | ((rdev >> 32) & 0xffff_f000);
+    let minor = (rdev & 0xff) | ((rdev >> 12) & 0xffff_ff00);
+    ((minor & 0xff) | (major << 8) | ((minor & !0xff) << 12)) as i32
+}
+
+fn group_representative_in(proc_root: &Path, pgrp: i32) -> Process {
+    let leader = Process::with_proc_path(pgrp, &proc_root.join(pgrp.to_string()));
+    if leader.is_alive() {
+        return leader;
+    }
+
+    process_group_members(proc_root, pgrp)
+        .ok()
+        .and_then(|members| members.into_iter().find(|process| process.is_alive()))
+        .unwrap_or(leader)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fixture(name: &str, status: &str) -> (Process, PathBuf) {
+        let dir = std::env::temp_dir().join(format!("ttymon-test-{}-{}", std::process::id(), name));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("status"), status).unwrap();
+
+        (Process::with_proc_path(1000, &dir), dir)
+    }
+
+    #[test]
+    fn test_euid() {
+        let (process, dir) = fixture(
+            "euid",
+            "Name:\tsudo\nUmask:\t0022\nState:\tS (sleeping)\n\
+             Uid:\t1000\t0\t0\t0\nGid:\t1000\t1000\t1000\t1000\n",
+        );
+        assert_eq!(process.euid().unwrap(), 0);
+        fs::remove_dir_all(dir).unwrap();
+
+        let (process, dir) = fixture("euid-missing", "Name:\tbash\n");
+        assert!(process.euid().is_err());
+        fs::remove_dir_all(dir).unwrap();
+
+        assert!(Process::with_proc_path(1, Path::new("/nonexistent"))
+            .euid()
+            .is_err());
+    }
+
+    #[test]
+    fn test_environ() {
+        let (process, dir) = fixture("environ", "");
+        fs::write(
+            dir.join("environ"),
+            b"HOME=/home/user\0X=a=b\0NOVALUE\0\xff=\xfe\0HOME=/root\0",
+        )
+        .unwrap();
+        let environ = process.environ().unwrap();
+        assert_eq!(environ.len(), 3);
+        assert_eq!(environ["HOME"], "/home/user");
+        assert_eq!(environ["X"], "a=b");
+        assert_eq!(environ["\u{fffd}"], "\u{fffd}");
+        fs::remove_dir_all(dir).unwrap();
+
+        assert_eq!(
+            Process::with_proc_path(1, Path::new("/nonexistent"))
+                .environ()
+                .unwrap_err()
+                .kind(),
+            io::ErrorKind::NotFound
+        );
+    }
+
+    // Writes a fake /proc/<pid> directory under proc_root
+    fn fake_process(proc_root: &Path, pid: i32, pgrp: i32, state: char, argv0: &str) {
+        fake_child_process(proc_root, pid, 1, pgrp, state, argv0);
+    }
+
+    fn fake_child_process(
+        proc_root: &Path,
+        pid: i32,
+        ppid: i32,
+        pgrp: i32,
+        state: char,
+        argv0: &str,
+    ) {
+        let dir = proc_root.join(pid.to_string());
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(
+            dir.join("stat"),
+            format!(
+                "{} ({}) {} {} {} {} 34816 {} 4194560 {}\n",
+                pid,
+                argv0,
+                state,
+                ppid,
+                pgrp,
+                pgrp,
+                pgrp,
+                "0 ".repeat(44)
+            ),
+        )
+        .unwrap();
+        fs::write(dir.join("cmdline"), format!("{}\0", argv0)).unwrap();
+    }
+
+    #[test]
+    fn test_tty_nr_from_rdev() {
+        // /dev/pts/3 is 136:3; /dev/pts/300 is 136:300, with the minor number
+        // split around the major
+        assert_eq!(tty_nr_from_rdev(0x8803), 0x8803);
+        assert_eq!(tty_nr_from_rdev(0x10882c), 0x10882c);
+        // glibc puts the high bits of a major number above bit 32 of st_rdev
+        assert_eq!(tty_nr_from_rdev((4096 << 32) | 1), 0x100001);
+    }
+
+    #[test]
+    fn test_group_representative() {
+        let proc_root =
+            std::env::temp_dir().join(format!("ttymon-test-{}-proc", std::process::id()));
+        let _ = fs::remove_dir_all(&proc_root);
+
+        // The leader of group 100 has exited, leaving a zombie; 103 is in
+        // the group and still running, 101 is in another group.
+        fake_process(&proc_root, 100, 100, 'Z', "sh");
+        fake_process(&proc_root, 101, 50, 'S', "bash");
+        fake_process(&proc_root, 102, 100, 'Z', "sleep");
+        fake_process(&proc_root, 103, 100, 'S', "vim");
+        fake_process(&proc_root, 104, 100, 'R', "cat");
+
+        let members = process_group_members(&proc_root, 100).unwrap();
+        let pids: Vec<i32> = members.iter().map(|p| p.pid()).collect();
+        assert_eq!(pids, vec![100, 102, 103, 104]);
+
+        let representative = group_representative_in(&proc_root, 100);
+        assert_eq!(representative.pid(), 103);
+        assert_eq!(representative.argv0().unwrap(), "vim");
+
+        // The leader can also be gone entirely; a live leader is always used
+        assert_eq!(group_representative_in(&proc_root, 50).pid(), 101);
+        fake_process(&proc_root, 50, 50, 'S', "bash");
+        assert_eq!(group_representative_in(&proc_root, 50).pid(), 50);
+        fake_process(&proc_root, 100, 100, 'S', "sh");
+        assert_eq!(group_representative_in(&proc_root, 100).pid(), 100);
+
+        // The stat file is only read once per Process
+        let process = Process::with_proc_path(104, &proc_root.join("104"));
+        assert!(process.is_alive());
+        fake_process(&proc_root, 104, 100, 'Z', "cat");
+        assert!(process.is_alive());
+        assert!(!Process::with_proc_path(104, &proc_root.join("104")).is_alive());
+
+        fs::remove_dir_all(&proc_root).unwrap();
+    }
+
+    #[test]
+    fn test_stat() {
+        let stat = Stat::parse(
+            b"1234 (bash) S 1233 1234 1234 34817 5678 4194304 5046 51593 0 6 8 3 \
+              52 27 20 0 1 0 9731420 233304064 1462 18446744073709551615 \
+              94571244716032 94571245630749 140724950628256 0 0 0 65536 \
+              3686404 1266761467 1 0 0 17 3 0 0 0 0 0 94571245878128 \
+              94571245925988 94571270811648 140724950636155 140724950636160 \
+              140724950636160 140724950638574 0\n",
+        )
+        .unwrap();
+        assert_eq!(
+            stat,
+            Stat {
+                pid: 1234,
+                comm: String::from("bash"),
+                state: 'S',
+                ppid: 1233,
+                pgrp: 1234,
+                session: 1234,
+                tty_nr: 34817,
+                tty_pgrp: 5678,
+                start_time: 9731420,
+            }
+        );
+
+        // No controlling terminal
+        let stat = Stat::parse(
+            b"2 (kthreadd) S 0 0 0 0 -1 2129984 0 0 0 0 0 0 0 0 20 0 1 0 5 0 0 \
+              18446744073709551615 0 0 0 0 0 0 0 2147483647 0 1 0 0 0 0 2 0 0 0 \
+              0 0 0 0 0 0 0 0 0\n",
+        )
+        .unwrap();
+        assert_eq!((stat.tty_nr, stat.tty_pgrp, stat.start_time), (0, -1, 5));
+
+        // The command name can contain spaces and parentheses
+        let adversarial = |comm: &str| {
+            let data = format!("77 ({}) R 1 77 77 0 -1 0 {}\n", comm, "0 ".repeat(14));
+            Stat::parse(data.as_bytes()).unwrap()
+        };
+        assert_eq!(adversarial("a b").comm, "a b");
+        assert_eq!(adversarial(") S 1 2 3 (").comm, ") S 1 2 3 (");
+        assert_eq!(adversarial("))").pgrp, 77);
+        assert_eq!(adversarial("").comm, "");
+        assert_eq!(adversarial("\u{fffd}\n").comm, "\u{fffd}\n");
+        assert_eq!(adversarial("weird ) name").comm, "weird ) name");
+        assert_eq!(adversarial("()").comm, "()");
+        assert_eq!(adversarial("(foo) (bar").comm, "(foo) (bar");
+        assert_eq!(adversarial("x) 1 2 3 4 5 6").comm, "x) 1 2 3 4 5 6");
+        // The kernel truncates names to 15 bytes, which can leave one ending
+        // in ')' right before the closing one
+        let truncated = adversarial("python3 (main))");
+        assert_eq!(truncated.comm, "python3 (main))");
+        assert_eq!(
+            (
+                truncated.pid,
+                truncated.state,
+                truncated.ppid,
+                truncated.pgrp
+            ),
+            (77, 'R', 1, 77)
+        );
+
+        assert!(Stat::parse(b"").is_err());
+        assert!(Stat::parse(b"77 (bash").is_err());
+        assert!(Stat::parse(b"77 )bash( R 1 77 77 0 -1").is_err());
+        assert!(Stat::parse(b"77 (bash) R 1 77 77 0 -1\n").is_err());
+        assert!(Stat::parse(b"x (bash) R 1 77 77 0 -1 0 0 0 0 0 0 0 0 0 0 0 0 0 0\n").is_err());
+    }
+
+    #[test]
+    fn test_fake_proc() {
+        let proc_root =
+            std::env::temp_dir().join(format!("ttymon-test-{}-fake", std::process::id()));
+        let _ = fs::remove_dir_all(&proc_root);
+
+        fake_process(&proc_root, 200, 200, 'S', "bash");
+        fake_process(&proc_root, 201, 200, 'S', "tmux: server (1) S 2");
+        fake_process(&proc_root, 202, 300, 'S', ") (");
+        fake_process(&proc_root, 203, 200, 'Z', "sleep");
+        let dir = proc_root.join("201");
+        std::os::unix::fs::symlink("/home/user/src", dir.join("cwd")).unwrap();
+        fs::create_dir(dir.join("fd")).unwrap();
+        std::os::unix::fs::symlink("socket:[5678]", dir.join("fd").join("0")).unwrap();
+        fs::write(dir.join("cmdline"), "tmux\0new-session\0").unwrap();
+
+        // The zombie isn't listed
+        assert_eq!(
+            list_process_group_in(&proc_root, 200).unwrap(),
+            vec![200, 201]
+        );
+        assert_eq!(list_process_group_in(&proc_root, 300).unwrap(), vec![202]);
+        assert_eq!(list_process_group_in(&proc_root, 400).unwrap(), vec![]);
+
+        // The command name doesn't confuse the fields after it
+        let process = find_in(&proc_root, |p| p.process_group().ok() == Some(300))
+            .unwrap()
+            .unwrap();
+        assert_eq!(process.pid(), 202);
+        assert_eq!(process.stat().unwrap().comm, ") (");
+
+        let process = find_in(&proc_root, |p| p.command().ok().as_deref() == Some("tmux"))
+            .unwrap()
+            .unwrap();
+        assert_eq!(process.pid(), 201);
+        assert_eq!(process.stat().unwrap().comm, "tmux: server (1) S 2");
+        assert_eq!(process.state().unwrap(), 'S');
+        assert_eq!(process.parent().unwrap(), 1);
+        assert_eq!(process.argv().unwrap(), vec!["tmux", "new-session"]);
+        assert_eq!(process.cwd().unwrap(), Path::new("/home/user/src"));
+        assert_eq!(process.list_sockets().unwrap(), vec![5678]);
+        assert!(process.has_open(Path::new("socket:[5678]")));
+        assert!(!process.has_open(Path::new("/dev/pts/0")));
+        assert_eq!(process.tty_path(), None);
+        std::os::unix::fs::symlink("/dev/pts/3", dir.join("fd").join("1")).unwrap();
+        assert_eq!(process.tty_path(), None);
+        fs::remove_file(dir.join("fd").join("0")).unwrap();
+        std::os::unix::fs::symlink("/dev/pts/3", dir.join("fd").join("0")).unwrap();
+        assert_eq!(process.tty_path(), Some(PathBuf::from("/dev/pts/3")));
+
+        assert!(find_in(&proc_root, |p| p.pid() == 999).unwrap().is_none());
+
+        fs::remove_dir_all(&proc_root).unwrap();
+    }
+
+    #[test]
+    fn test_children() {
+        let proc_root =
+            std::env::temp_dir().join(format!("ttymon-test-{}-children", std::process::id()));
+        let _ = fs::remove_dir_all(&proc_root);
+
+        fake_process(&proc_root, 100, 100, 'S', "conmon");
+        fake_child_process(&proc_root, 102, 100, 102, 'S', "bash");
+        fake_child_process(&proc_root, 101, 100, 101, 'S', "catatonit");
+        fake_child_process(&proc_root, 103, 102, 102, 'S', "vim");
+
+        let children = children_in(&proc_root, 100).unwrap();
+        let pids: Vec<i32> = children.iter().map(|p| p.pid()).collect();
+        assert_eq!(pids, vec![101, 102]);
+        assert_eq!(children_in(&proc_root, 103).unwrap().len(), 0);
+
+        fs::remove_dir_all(&proc_root).unwrap();
+    }
+
+    #[test]
+    fn test_vanished() {
+        let proc_root =
+            std::env::temp_dir().join(format!("ttymon-vanished-{}", std::process::id()));
+        fake_process(&proc_root, 100, 100, 'S', "bash");
+        // Too large to be a pid
+        fs::create_dir_all(proc_root.join("99999999999")).unwrap();
+
+        let members = process_group_members(&proc_root, 100).unwrap();
+        assert_eq!(members.iter().map(|p| p.pid).collect::<Vec<_>>(), vec![100]);
+
+        // No fd directory, as if the process exited
+        let process = Process::with_proc_path(100, &proc_root.join("100"));
+        assert_eq!(process.list_sockets().unwrap(), Vec::<u32>::new());
+
+        let fd_dir = proc_root.join("100").join("fd");
+        fs::create_dir_all(&fd_dir).unwrap();
+        std::os::unix::fs::symlink("socket:[1234]", fd_dir.join("3")).unwrap();
+        std::os::unix::fs::symlink("/dev/pts/0", fd_dir.join("4")).unwrap();
+        assert_eq!(process.list_sockets().unwrap(), vec![1234]);
+
+        fs::remove_dir_all(&proc_root).unwrap();
+    }
+
+    #[test]
+    fn test_argv() {
+        let (process, dir) = fixture("argv", "");
+        let argv = ["python", "train.py", "", "--epochs", "100"];
+        fs::write(dir.join("cmdline"), format!("{}\0", argv.join("\0"))).unwrap();
+        assert_eq!(process.argv().unwrap(), argv);
+
+        // A process that rewrote its arguments without the NULs
+        fs::write(dir.join("cmdline"), "sshd: user@pts/3").unwrap();
+        assert_eq!(process.argv().unwrap(), vec!["sshd: user@pts/3"]);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_command_name() {
+        assert_eq!(command_name("bash"), "bash");
+        assert_eq!(command_name("/bin/bash"), "bash");
+        assert_eq!(command_name("-bash"), "bash");
+        assert_eq!(command_name("-/bin/bash"), "bash");
+        assert_eq!(command_name("./run-tests"), "run-tests");
+        assert_eq!(command_name(""), "");
+    }
+}
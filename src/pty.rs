@@ -1,53 +1,255 @@
 use nix::errno::Errno;
-use nix::fcntl::{open, OFlag};
-use nix::pty::{grantpt, posix_openpt, ptsname, unlockpt, PtyMaster};
-use nix::sys::epoll::{epoll_create, epoll_ctl, epoll_wait, EpollEvent, EpollFlags, EpollOp};
+use nix::fcntl::{fcntl, open, FcntlArg, OFlag};
+use nix::pty::{grantpt, posix_openpt, ptsname, unlockpt, PtyMaster, Winsize};
+use nix::sys::epoll::{
+    epoll_create1, epoll_ctl, epoll_wait, EpollCreateFlags, EpollEvent, EpollFlags, EpollOp,
+};
+use nix::sys::signal::{killpg, Signal};
 use nix::sys::stat::Mode;
-use nix::sys::termios;
-use nix::unistd::{close, dup2, read, setsid, write};
+use nix::sys::termios::{self, SpecialCharacterIndices};
+use nix::unistd::{close, dup2, isatty, read, setsid, tcgetpgrp, write, Pid};
 use std::cmp::min;
 use std::convert::TryInto;
+use std::ffi::OsString;
 use std::io;
 use std::os::unix::io::AsRawFd;
 use std::os::unix::io::RawFd;
-use std::os::unix::process::CommandExt;
+use std::os::unix::process::{CommandExt, ExitStatusExt};
 use std::path::Path;
-use std::process::Command;
+use std::process::{Child, Command, ExitStatus};
 use std::time::{Duration, Instant};
 
-use crate::filter::Filter;
+use crate::filter::{Filter, TitleSink};
+use crate::recorder::Recorder;
+use crate::signals::SignalPipe;
 
-// Check at .1 / .5 / 2.5 / 12.5 / .... / 60 seconds
-const MIN_CHECK_INTERVAL: std::time::Duration = Duration::from_millis(100);
-const MAX_CHECK_INTERVAL: std::time::Duration = Duration::from_secs(60);
-const CHECK_INTERVAL_MULTIPLIER: u32 = 5;
+// Check at .1 / .5 / 2.5 / 12.5 / .... / 60 seconds, by default
+pub const DEFAULT_MIN_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+pub const DEFAULT_MAX_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+pub const DEFAULT_CHECK_INTERVAL_MULTIPLIER: u32 = 5;
+// Output from the child starts the checks over at the minimum interval, since
+// the state has likely changed - but only output after input, or after a gap
+// of this long; otherwise a long build would keep us checking at the
+// minimum interval.
+const OUTPUT_IDLE_GAP: std::time::Duration = Duration::from_secs(1);
 
 const STDIN: RawFd = 0;
 const STDOUT: RawFd = 1;
 
+nix::ioctl_read_bad!(tiocgwinsz, nix::libc::TIOCGWINSZ, Winsize);
+nix::ioctl_write_ptr_bad!(tiocswinsz, nix::libc::TIOCSWINSZ, Winsize);
+nix::ioctl_write_int_bad!(tiocsctty, nix::libc::TIOCSCTTY);
+
+// The size of the child's terminal when we aren't running in a terminal
+const DEFAULT_ROWS: u16 = 24;
+const DEFAULT_COLS: u16 = 80;
+
+// Output from the child that STDOUT hasn't accepted yet is kept in memory; once
+// there is this much of it, we stop reading from the child until it drains.
+pub const DEFAULT_MAX_OUTPUT_BUFFER: usize = 4 * 1024 * 1024;
+
+// Output from the child is read in chunks of this size to start with; when a
+// read fills the buffer, it's doubled, up to the maximum, so fast output takes
+// fewer reads. (On Linux, a read from a PTY master returns at most 4095 bytes,
+// so this mostly matters elsewhere.)
+const INITIAL_READ_BUFFER: usize = 4096;
+pub const DEFAULT_MAX_READ_BUFFER: usize = 64 * 1024;
+
+// What ends input at a terminal, unless it has been changed
+const CTRL_D: u8 = 4;
+
+// DECRST 1049 - switch back to the primary screen
+const EXIT_ALT_SCREEN: &[u8] = b"\x1b[?1049l";
+
+// XTWINOPS 21 - report the window title, as OSC l <title> ST
+const QUERY_TITLE: &[u8] = b"\x1b[21t";
+const TITLE_REPORT_START: &[u8] = b"\x1b]l";
+
+// How long to wait for the terminal to report its title; terminals that
+// don't support the query, or have it disabled, don't reply at all
+const QUERY_TITLE_TIMEOUT: Duration = Duration::from_millis(200);
+
 struct RawInput {
+    fd: RawFd,
     orig_attr: termios::Termios,
 }
 
 impl RawInput {
-    fn setup() -> nix::Result<RawInput> {
-        let orig_attr = termios::tcgetattr(0)?;
+    fn setup(fd: RawFd) -> nix::Result<RawInput> {
+        let orig_attr = termios::tcgetattr(fd)?;
         let mut new_attr = orig_attr.clone();
         termios::cfmakeraw(&mut new_attr);
-        termios::tcsetattr(0, termios::SetArg::TCSAFLUSH, &new_attr)?;
+        termios::tcsetattr(fd, termios::SetArg::TCSAFLUSH, &new_attr)?;
 
-        Ok(RawInput { orig_attr })
+        Ok(RawInput { fd, orig_attr })
     }
 }
 
 impl Drop for RawInput {
     fn drop(&mut self) {
-        if let Err(e) = termios::tcsetattr(0, termios::SetArg::TCSAFLUSH, &self.orig_attr) {
-            println!("Can't restore terminal settings: {}", e);
+        if let Err(e) = termios::tcsetattr(self.fd, termios::SetArg::TCSAFLUSH, &self.orig_attr) {
+            warn!("Can't restore terminal settings: {}", e);
+        }
+    }
+}
+
+// Writes to STDOUT are non-blocking, so that a terminal that isn't keeping up
+// doesn't stop us from passing input to the child. The original flags are
+// restored on drop, since the file description is shared with whatever
+// started us.
+struct NonBlockingOutput {
+    fd: RawFd,
+    orig_flags: OFlag,
+}
+
+impl NonBlockingOutput {
+    fn setup(fd: RawFd) -> nix::Result<NonBlockingOutput> {
+        let orig_flags = OFlag::from_bits_truncate(fcntl(fd, FcntlArg::F_GETFL)?);
+        fcntl(fd, FcntlArg::F_SETFL(orig_flags | OFlag::O_NONBLOCK))?;
+
+        Ok(NonBlockingOutput { fd, orig_flags })
+    }
+}
+
+impl Drop for NonBlockingOutput {
+    fn drop(&mut self) {
+        if let Err(e) = fcntl(self.fd, FcntlArg::F_SETFL(self.orig_flags)) {
+            warn!("Can't restore output flags: {}", e);
+        }
+    }
+}
+
+// Tracks how long it has been since the user last typed anything. Output
+// doesn't count - a program printing progress doesn't mean anybody is there.
+struct IdleTimer {
+    timeout: Duration,
+    last_input: Instant,
+}
+
+impl IdleTimer {
+    fn new(timeout: Duration, now: Instant) -> IdleTimer {
+        IdleTimer {
+            timeout,
+            last_input: now,
+        }
+    }
+
+    fn input(&mut self, now: Instant) {
+        self.last_input = now;
+    }
+
+    // Time left until the session is idle, or None if it already is
+    fn remaining(&self, now: Instant) -> Option<Duration> {
+        let deadline = self.last_input + self.timeout;
+        if deadline > now {
+            Some(deadline - now)
+        } else {
+            None
+        }
+    }
+}
+
+// Identifies what an epoll event is for
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Token {
+    Master,
+    Stdin,
+    Stdout,
+    Signal,
+    Actions,
+}
+
+impl Token {
+    const ALL: [Token; 5] = [
+        Token::Master,
+        Token::Stdin,
+        Token::Stdout,
+        Token::Signal,
+        Token::Actions,
+    ];
+
+    fn from_data(data: u64) -> Option<Token> {
+        Token::ALL.iter().copied().find(|t| *t as u64 == data)
+    }
+}
+
+// An epoll instance that keeps track of how many fds are registered, so that
+// epoll_wait() can always return an event for each of them.
+struct Poller {
+    epoll_fd: RawFd,
+    registered: usize,
+    events: Vec<EpollEvent>,
+}
+
+impl Poller {
+    fn new() -> nix::Result<Poller> {
+        Ok(Poller {
+            epoll_fd: epoll_create1(EpollCreateFlags::EPOLL_CLOEXEC)?,
+            registered: 0,
+            events: vec![],
+        })
+    }
+
+    fn add(&mut self, fd: RawFd, flags: EpollFlags, token: Token) -> nix::Result<()> {
+        let mut event = EpollEvent::new(flags, token as u64);
+        epoll_ctl(self.epoll_fd, EpollOp::EpollCtlAdd, fd, &mut event)?;
+        self.registered += 1;
+        Ok(())
+    }
+
+    fn modify(&mut self, fd: RawFd, flags: EpollFlags, token: Token) -> nix::Result<()> {
+        let mut event = EpollEvent::new(flags, token as u64);
+        epoll_ctl(self.epoll_fd, EpollOp::EpollCtlMod, fd, &mut event)
+    }
+
+    fn delete(&mut self, fd: RawFd) -> nix::Result<()> {
+        epoll_ctl(self.epoll_fd, EpollOp::EpollCtlDel, fd, None)?;
+        self.registered -= 1;
+        Ok(())
+    }
+
+    fn wait(&mut self, timeout: Duration) -> nix::Result<&[EpollEvent]> {
+        self.events
+            .resize(self.registered.max(1), EpollEvent::empty());
+        let event_count = match epoll_wait(
+            self.epoll_fd,
+            &mut self.events,
+            timeout.as_millis().try_into().unwrap(),
+        ) {
+            Ok(event_count) => event_count,
+            // Interrupted by a signal, which will show up as an event next time
+            Err(nix::Error::Sys(Errno::EINTR)) => 0,
+            Err(e) => return Err(e),
+        };
+        Ok(&self.events[0..event_count])
+    }
+}
+
+impl Drop for Poller {
+    fn drop(&mut self) {
+        let _ = close(self.epoll_fd);
+    }
+}
+
+// Writes as much of buf as fd accepts without blocking. The count of bytes
+// written comes back along with any error, so that the caller can keep the
+// rest to try again, rather than losing or repeating what got through.
+fn write_some(fd: RawFd, buf: &[u8]) -> (usize, nix::Result<()>) {
+    let mut written = 0;
+    while written < buf.len() {
+        match write(fd, &buf[written..]) {
+            Ok(write_count) => written += write_count,
+            Err(nix::Error::Sys(Errno::EINTR)) => {}
+            Err(nix::Error::Sys(Errno::EAGAIN)) => break,
+            Err(e) => return (written, Err(e)),
         }
     }
+
+    (written, Ok(()))
 }
 
+// For short control sequences, where there's nothing useful to do with
+// whatever part of them got written before an error
 fn write_all(fd: RawFd, buf: &[u8]) -> nix::Result<()> {
     let mut written = 0;
     while written < buf.len() {
@@ -68,40 +270,94 @@ struct Buffer {
 
 impl Buffer {
     fn new() -> Self {
+        Buffer::with_size(INITIAL_READ_BUFFER)
+    }
+
+    fn with_size(size: usize) -> Self {
         return Buffer {
-            buf: vec![0; 4096],
+            buf: vec![0; size],
             count: 0,
         };
     }
 
+    // Doubles the size of the buffer, up to max_size
+    fn grow(&mut self, max_size: usize) {
+        let size = min(self.buf.len() * 2, max_size);
+        if size > self.buf.len() {
+            self.buf.resize(size, 0);
+        }
+    }
+
     fn fill(&mut self, fd: RawFd) -> nix::Result<bool> {
-        match read(fd, &mut self.buf[self.count..]) {
+        // A zero-length read would return 0 and look like end-of-file; leave
+        // the data where it is until the buffer is flushed.
+        if self.count == self.buf.len() {
+            return Ok(true);
+        }
+
+        let result = loop {
+            match read(fd, &mut self.buf[self.count..]) {
+                Err(nix::Error::Sys(Errno::EINTR)) => {}
+                result => break result,
+            }
+        };
+
+        match result {
             Ok(0) => Ok(false),
+            // Reading a PTY master once the other side is closed gives EIO
+            Err(nix::Error::Sys(Errno::EIO)) => Ok(false),
             Ok(count) => {
                 self.count += count;
                 Ok(true)
             }
+            // STDIN may share a file description with the non-blocking STDOUT
+            Err(nix::Error::Sys(Errno::EAGAIN)) => Ok(true),
             Err(e) => Err(e),
         }
     }
 
+    // Writes as much of the buffer as fd accepts without blocking; the rest
+    // stays pending.
     fn flush(&mut self, fd: RawFd) -> nix::Result<()> {
-        write_all(fd, &self.buf[0..self.count])?;
-        self.count = 0;
-        Ok(())
+        let (written, result) = write_some(fd, &self.buf[..self.count]);
+        self.buf.copy_within(written..self.count, 0);
+        self.count -= written;
+        result
+    }
+
+    // Adds data to the buffer, if there's room for all of it
+    fn push(&mut self, data: &[u8]) -> bool {
+        if self.buf.len() - self.count < data.len() {
+            return false;
+        }
+        self.buf[self.count..self.count + data.len()].copy_from_slice(data);
+        self.count += data.len();
+        true
+    }
+
+    fn pending(&self) -> usize {
+        self.count
+    }
+
+    fn is_full(&self) -> bool {
+        self.count == self.buf.len()
     }
 }
 
 struct FilteredBuffer {
     raw: Buffer,
+    max_read_buffer: usize,
     filter: Filter,
+    recorder: Option<Recorder>,
 }
 
 impl FilteredBuffer {
-    fn new() -> Self {
+    fn new(max_read_buffer: usize) -> Self {
         return FilteredBuffer {
-            raw: Buffer::new(),
+            raw: Buffer::with_size(min(INITIAL_READ_BUFFER, max_read_buffer)),
+            max_read_buffer,
             filter: Filter::new(),
+            recorder: None,
         };
     }
 
@@ -110,32 +366,199 @@ impl FilteredBuffer {
             return Ok(false);
         }
 
-        self.filter.fill(&self.raw.buf[0..self.raw.count]);
+        let data = &self.raw.buf[0..self.raw.count];
+        if let Some(recorder) = &mut self.recorder {
+            recorder.record(data);
+        }
+        self.filter.fill(data);
+        if self.raw.is_full() {
+            self.raw.grow(self.max_read_buffer);
+        }
         self.raw.count = 0;
         Ok(true)
     }
 
+    // Writes as much of the filtered output as fd accepts without blocking;
+    // the rest stays pending.
     fn flush(&mut self, fd: RawFd) -> nix::Result<()> {
-        {
-            let buf = self.filter.buffer();
-            write_all(fd, buf)?;
+        let (written, result) = write_some(fd, self.filter.buffer());
+        self.filter.consume_buffer(written);
+        result
+    }
+
+    fn pending(&self) -> usize {
+        self.filter.buffer().len()
+    }
+}
+
+// The character that ends input to the child, as Ctrl-D would when typed at
+// a terminal
+fn eof_char(master_fd: RawFd) -> u8 {
+    match termios::tcgetattr(master_fd) {
+        Ok(attrs) => attrs.control_chars[SpecialCharacterIndices::VEOF as usize],
+        Err(_) => CTRL_D,
+    }
+}
+
+// Finds a title report in what we've read from the terminal, returning the
+// title and the rest of the input, which the user typed around it. None if
+// there's no complete report yet.
+fn parse_title_report(input: &[u8]) -> Option<(Vec<u8>, Vec<u8>)> {
+    let start = input
+        .windows(TITLE_REPORT_START.len())
+        .position(|w| w == TITLE_REPORT_START)?;
+    let title_start = start + TITLE_REPORT_START.len();
+    let (title_end, end) = input[title_start..]
+        .iter()
+        .enumerate()
+        .find_map(|(i, &c)| match c {
+            0x07 => Some((i, i + 1)),
+            0x1b if input.get(title_start + i + 1) == Some(&b'\\') => Some((i, i + 2)),
+            _ => None,
+        })
+        .map(|(title_end, end)| (title_start + title_end, title_start + end))?;
+
+    let title = input[title_start..title_end].to_vec();
+    let mut rest = input[..start].to_vec();
+    rest.extend_from_slice(&input[end..]);
+    Some((title, rest))
+}
+
+// Where we are in a title report that arrives after query_terminal_title()
+// has stopped waiting for it, and has to be kept from reaching the child
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum LateTitleReport {
+    // Nothing of the report has arrived
+    Expected,
+    // Part of the title has arrived
+    Title,
+    // The title has arrived, up to the ESC of the ST that ends it
+    Terminator,
+    // The report is over, or none is coming
+    Done,
+}
+
+impl LateTitleReport {
+    // Returns input without the part of the report that's in it
+    fn strip(&mut self, input: &[u8]) -> Vec<u8> {
+        let mut rest = Vec::with_capacity(input.len());
+        let mut i = 0;
+        while i < input.len() {
+            match self {
+                LateTitleReport::Expected => {
+                    if input[i..].starts_with(TITLE_REPORT_START) {
+                        *self = LateTitleReport::Title;
+                        i += TITLE_REPORT_START.len();
+                        continue;
+                    }
+                    rest.push(input[i]);
+                }
+                LateTitleReport::Title => match input[i] {
+                    0x07 => *self = LateTitleReport::Done,
+                    0x1b => *self = LateTitleReport::Terminator,
+                    _ => {}
+                },
+                LateTitleReport::Terminator => {
+                    *self = if input[i] == b'\\' {
+                        LateTitleReport::Done
+                    } else {
+                        LateTitleReport::Title
+                    }
+                }
+                LateTitleReport::Done => {
+                    rest.extend_from_slice(&input[i..]);
+                    break;
+                }
+            }
+            i += 1;
+        }
+
+        rest
+    }
+}
+
+// Asks the terminal for its title; input_fd must be in raw mode. Returns the
+// title, if the terminal reported a non-empty one, anything else that was
+// read, which should be passed on as input, and whether a report might
+// still arrive.
+fn query_terminal_title(
+    input_fd: RawFd,
+    output_fd: RawFd,
+) -> nix::Result<(Option<String>, Vec<u8>, LateTitleReport)> {
+    let mut poller = Poller::new()?;
+    poller.add(input_fd, EpollFlags::EPOLLIN, Token::Stdin)?;
+    write_all(output_fd, QUERY_TITLE)?;
+
+    let deadline = Instant::now() + QUERY_TITLE_TIMEOUT;
+    let mut input = vec![];
+    let mut buf = [0u8; 1024];
+    loop {
+        if let Some((title, rest)) = parse_title_report(&input) {
+            let title = Some(String::from_utf8_lossy(&title).into_owned())
+                .filter(|title| !title.is_empty());
+            return Ok((title, rest, LateTitleReport::Done));
+        }
+
+        let now = Instant::now();
+        if now >= deadline || poller.wait(deadline - now)?.is_empty() {
+            info!("No title reported by the terminal");
+            // Drop the start of a report that's still arriving
+            let mut late_report = LateTitleReport::Expected;
+            let input = late_report.strip(&input);
+            return Ok((None, input, late_report));
+        }
+        match read(input_fd, &mut buf) {
+            Ok(0) => return Ok((None, input, LateTitleReport::Done)),
+            Ok(count) => input.extend_from_slice(&buf[..count]),
+            Err(nix::Error::Sys(Errno::EINTR)) => {}
+            Err(e) => return Err(e),
         }
-        self.filter.clear_buffer();
-        Ok(())
     }
 }
 
+// The size of the terminal we are running in
+fn terminal_winsize(fd: RawFd) -> nix::Result<Winsize> {
+    let mut winsize = Winsize {
+        ws_row: 0,
+        ws_col: 0,
+        ws_xpixel: 0,
+        ws_ypixel: 0,
+    };
+    unsafe { tiocgwinsz(fd, &mut winsize) }?;
+
+    Ok(winsize)
+}
+
 pub struct Pty {
     master_fd: PtyMaster,
     peer_fd: RawFd,
+    peer_name: String,
+    // Our terminal: STDIN and STDOUT, except in tests
+    input_fd: RawFd,
+    output_fd: RawFd,
     check_interval: Duration,
+    min_check_interval: Duration,
+    max_check_interval: Duration,
+    check_interval_multiplier: u32,
     last_check_time: Option<Instant>,
+    // None if there has been input since
+    last_output_time: Option<Instant>,
+    reset_screen: bool,
+    max_output_buffer: usize,
+    max_read_buffer: usize,
+    title_sink: Option<Box<dyn TitleSink>>,
+    recorder: Option<Recorder>,
+    rewrite_titles: bool,
+    query_title: bool,
+    idle_timeout: Option<Duration>,
+    child: Option<Child>,
 }
 
 impl Pty {
     pub fn new() -> nix::Result<Pty> {
-        // Open a new PTY master
-        let master_fd = posix_openpt(OFlag::O_RDWR)?;
+        // Open a new PTY master. Neither side is inherited by the child: it
+        // gets the slave as its stdin, stdout and stderr, and nothing else.
+        let master_fd = posix_openpt(OFlag::O_RDWR | OFlag::O_CLOEXEC)?;
 
         // Allow a slave to be generated for it
         grantpt(&master_fd)?;
@@ -145,22 +568,139 @@ impl Pty {
         let peer_name = unsafe { ptsname(&master_fd) }?;
 
         // Try to open the slave
-        let peer_fd = open(Path::new(&peer_name), OFlag::O_RDWR, Mode::empty())?;
+        let peer_fd = open(
+            Path::new(&peer_name),
+            OFlag::O_RDWR | OFlag::O_CLOEXEC,
+            Mode::empty(),
+        )?;
 
         Ok(Pty {
             master_fd,
             peer_fd,
-            check_interval: MIN_CHECK_INTERVAL,
+            peer_name,
+            input_fd: STDIN,
+            output_fd: STDOUT,
+            check_interval: DEFAULT_MIN_CHECK_INTERVAL,
+            min_check_interval: DEFAULT_MIN_CHECK_INTERVAL,
+            max_check_interval: DEFAULT_MAX_CHECK_INTERVAL,
+            check_interval_multiplier: DEFAULT_CHECK_INTERVAL_MULTIPLIER,
             last_check_time: None,
+            last_output_time: None,
+            reset_screen: false,
+            max_output_buffer: DEFAULT_MAX_OUTPUT_BUFFER,
+            max_read_buffer: DEFAULT_MAX_READ_BUFFER,
+            title_sink: None,
+            recorder: None,
+            rewrite_titles: true,
+            query_title: false,
+            idle_timeout: None,
+            child: None,
+        })
+    }
+
+    /// The device path of the slave side of the PTY (/dev/pts/N)
+    pub fn peer_name(&self) -> &str {
+        &self.peer_name
+    }
+
+    /// If set, switch the terminal to the primary screen on startup. We can't
+    /// query whether whatever ran before us left the terminal on the alternate
+    /// screen, so this is unconditional and hence opt-in. (Leaving the alternate
+    /// screen on exit, if the child switched to it, happens regardless.)
+    pub fn set_reset_screen(&mut self, reset_screen: bool) {
+        self.reset_screen = reset_screen;
+    }
+
+    /// The amount of output from the child to hold in memory when STDOUT isn't
+    /// keeping up before we stop reading more. At least 1, or we'd never read
+    /// anything.
+    pub fn set_max_output_buffer(&mut self, max_output_buffer: usize) {
+        self.max_output_buffer = max_output_buffer.max(1);
+    }
+
+    /// The largest read buffer for output from the child; the buffer starts
+    /// smaller and grows when output is coming faster than we read it.
+    pub fn set_max_read_buffer(&mut self, max_read_buffer: usize) {
+        self.max_read_buffer = max_read_buffer;
+    }
+
+    /// How often we check what's running: first after min, then each
+    /// interval multiplier times the last, up to max. Checking starts over at
+    /// min when there's output after input or a pause.
+    pub fn set_check_intervals(&mut self, min: Duration, max: Duration, multiplier: u32) {
+        self.check_interval = min;
+        self.min_check_interval = min;
+        self.max_check_interval = max;
+        self.check_interval_multiplier = multiplier;
+    }
+
+    /// If false, the window title is left to the application
+    pub fn set_rewrite_titles(&mut self, rewrite_titles: bool) {
+        self.rewrite_titles = rewrite_titles;
+    }
+
+    /// If set, ask the terminal for its title on startup, and show that as
+    /// the application's title until it sets one
+    pub fn set_query_title(&mut self, query_title: bool) {
+        self.query_title = query_title;
+    }
+
+    /// Sends the window title through title_sink rather than as an xterm OSC
+    pub fn set_title_sink(&mut self, title_sink: Box<dyn TitleSink>) {
+        self.title_sink = Some(title_sink);
+    }
+
+    /// Records the child's output, as it wrote it
+    pub fn set_recorder(&mut self, recorder: Recorder) {
+        self.recorder = Some(recorder);
+    }
+
+    /// Sets the size of the terminal as seen by the child
+    #[allow(dead_code)]
+    pub fn set_winsize(&self, rows: u16, cols: u16) -> nix::Result<()> {
+        self.apply_winsize(&Winsize {
+            ws_row: rows,
+            ws_col: cols,
+            ws_xpixel: 0,
+            ws_ypixel: 0,
         })
     }
 
+    fn apply_winsize(&self, winsize: &Winsize) -> nix::Result<()> {
+        unsafe { tiocswinsz(self.master_fd.as_raw_fd(), winsize) }?;
+        Ok(())
+    }
+
+    // Copies the size of our terminal to the child's terminal, if we are
+    // running in a terminal
+    fn sync_winsize(&self) {
+        let winsize = match terminal_winsize(self.input_fd) {
+            Ok(winsize) => winsize,
+            Err(e) => {
+                debug!("Can't get terminal size: {}", e);
+                return;
+            }
+        };
+
+        if let Err(e) = self.apply_winsize(&winsize) {
+            warn!("Can't set terminal size: {}", e);
+        }
+    }
+
+    /// If set, hang up the session when there's been no input for this long
+    pub fn set_idle_timeout(&mut self, idle_timeout: Option<Duration>) {
+        self.idle_timeout = idle_timeout;
+    }
+
     fn child_setup(peer_fd: RawFd) -> nix::Result<()> {
         dup2(peer_fd, 0)?;
         dup2(peer_fd, 1)?;
         dup2(peer_fd, 2)?;
 
+        // A new session, with the PTY as its controlling terminal - without that,
+        // there is no foreground process group, and no job control
         setsid()?;
+        unsafe { tiocsctty(0, 0) }?;
 
         Ok(())
     }
@@ -175,8 +715,32 @@ impl Pty {
         }
     }
 
-    pub fn fork(&mut self) -> io::Result<u32> {
-        let mut proc = Command::new("/bin/bash");
+    /// Runs program in the pseudo-terminal. For a login shell, argv[0] is the
+    /// name of the program prefixed with '-', as login(1) does.
+    pub fn fork(&mut self, program: &Path, args: &[OsString], login: bool) -> io::Result<u32> {
+        let mut proc = Command::new(program);
+        proc.args(args);
+        if login {
+            let mut arg0 = OsString::from("-");
+            arg0.push(program.file_name().unwrap_or(program.as_os_str()));
+            proc.arg0(arg0);
+        }
+
+        self.spawn(proc)
+    }
+
+    fn spawn(&mut self, mut proc: Command) -> io::Result<u32> {
+        // Set the size before the child starts, so its first output is laid
+        // out right; without a terminal, use the traditional default.
+        let winsize = terminal_winsize(self.input_fd).unwrap_or(Winsize {
+            ws_row: DEFAULT_ROWS,
+            ws_col: DEFAULT_COLS,
+            ws_xpixel: 0,
+            ws_ypixel: 0,
+        });
+        if let Err(e) = self.apply_winsize(&winsize) {
+            warn!("Can't set terminal size: {}", e);
+        }
 
         let peer_fd = self.peer_fd;
         unsafe {
@@ -194,8 +758,52 @@ impl Pty {
 
         let child = proc.spawn()?;
         self.close_peer_fd().unwrap();
+        let pid = child.id();
+        self.child = Some(child);
+
+        Ok(pid)
+    }
+
+    // Sends a signal to the child's process group. (The child called setsid(),
+    // so its pid is also its process group.)
+    fn signal_child(&self, signal: Signal) {
+        if let Some(child) = &self.child {
+            if let Err(e) = killpg(Pid::from_raw(child.id() as i32), signal) {
+                warn!("Can't send {} to child: {}", signal, e);
+            }
+        }
+    }
 
-        Ok(child.id())
+    // Sends a signal to the foreground process group of the child's terminal,
+    // as the terminal driver does for Ctrl-C, or to the child if we can't
+    // tell what that is
+    fn signal_foreground(&self, signal: Signal) {
+        match tcgetpgrp(self.master_fd.as_raw_fd()) {
+            Ok(pgrp) => {
+                if let Err(e) = killpg(pgrp, signal) {
+                    warn!("Can't send {} to foreground process group: {}", signal, e);
+                }
+            }
+            Err(_) => self.signal_child(signal),
+        }
+    }
+
+    // Sends SIGHUP to the child, as the kernel does when a terminal is
+    // disconnected
+    fn hang_up(&self) {
+        self.signal_child(Signal::SIGHUP);
+    }
+
+    fn update_window_title<A>(actions: &A, from_child: &mut FilteredBuffer)
+    where
+        A: PtyActions,
+    {
+        if !from_child.filter.rewrite_titles() {
+            return;
+        }
+        let out_window_title = actions.make_window_title(&from_child.filter);
+        from_child.filter.clear_bell_pending();
+        from_child.filter.set_out_window_title(&out_window_title);
     }
 
     fn maybe_check<A>(&mut self, actions: &mut A, from_child: &mut FilteredBuffer) -> Duration
@@ -212,14 +820,12 @@ impl Pty {
         if next_check_time <= now {
             actions.check();
 
-            let in_window_title = from_child.filter.in_window_title();
-            let out_window_title = actions.make_window_title(in_window_title);
-            from_child.filter.set_out_window_title(&out_window_title);
-            let _ = from_child.flush(STDOUT);
+            Self::update_window_title(actions, from_child);
+            let _ = from_child.flush(self.output_fd);
 
             self.check_interval = min(
-                MAX_CHECK_INTERVAL,
-                self.check_interval * CHECK_INTERVAL_MULTIPLIER,
+                self.max_check_interval,
+                self.check_interval * self.check_interval_multiplier,
             );
             self.last_check_time = Some(now);
             self.check_interval
@@ -228,68 +834,366 @@ impl Pty {
         }
     }
 
-    pub fn handle<A>(&mut self, actions: &mut A) -> nix::Result<()>
+    fn output_received(&mut self, now: Instant) {
+        let after_gap = match self.last_output_time {
+            Some(last_output_time) => now.duration_since(last_output_time) >= OUTPUT_IDLE_GAP,
+            None => true,
+        };
+        if after_gap {
+            self.check_interval = self.min_check_interval;
+        }
+        self.last_output_time = Some(now);
+    }
+
+    fn input_received(&mut self) {
+        self.last_output_time = None;
+    }
+
+    // The exit code for ttymon once the child is done: if the child closed the
+    // terminal, we wait for it to exit, otherwise (stdin closed, idle timeout)
+    // we only use its status if it has already exited.
+    fn child_exit_code(&mut self, wait: bool) -> i32 {
+        let child = match &mut self.child {
+            Some(child) => child,
+            None => return 0,
+        };
+        let status = if wait {
+            child.wait().map(Some)
+        } else {
+            child.try_wait()
+        };
+        match status {
+            Ok(Some(status)) => exit_code(status),
+            Ok(None) => 0,
+            Err(e) => {
+                warn!("Can't get exit status of child: {}", e);
+                0
+            }
+        }
+    }
+
+    /// Passes input and output between the terminal and the child until one of
+    /// them is done, returning the exit code the child exited with
+    pub fn handle<A>(&mut self, actions: &mut A) -> nix::Result<i32>
     where
         A: PtyActions,
     {
-        let raw_input = RawInput::setup();
-        if let Err(e) = raw_input {
-            println!("Can't setup raw input: {}", e);
+        // When input isn't from a terminal, there's no terminal to put into
+        // raw mode; the input is passed on as it comes, and the end of it as
+        // an end-of-file character.
+        let input_fd = self.input_fd;
+        let output_fd = self.output_fd;
+        let input_is_tty = isatty(input_fd).unwrap_or(false);
+        let raw_input = if input_is_tty {
+            RawInput::setup(input_fd)
+        } else {
+            Err(nix::Error::Sys(Errno::ENOTTY))
+        };
+        if let Err(e) = &raw_input {
+            if input_is_tty {
+                warn!("Can't setup raw input: {}", e);
+            }
+        };
+
+        if self.reset_screen {
+            write_all(output_fd, EXIT_ALT_SCREEN)?;
+        }
+
+        // Before anything else is written, so the reply is the only thing
+        // from the terminal mixed in with what the user types
+        let (initial_title, typeahead, mut late_title_report) =
+            if self.query_title && raw_input.is_ok() {
+                match query_terminal_title(input_fd, output_fd) {
+                    Ok(result) => result,
+                    Err(e) => {
+                        warn!("Can't query the terminal's title: {}", e);
+                        (None, vec![], LateTitleReport::Done)
+                    }
+                }
+            } else {
+                (None, vec![], LateTitleReport::Done)
+            };
+
+        let nonblocking_output = NonBlockingOutput::setup(output_fd);
+        if let Err(e) = nonblocking_output {
+            warn!("Can't make output non-blocking: {}", e);
         };
 
         let master_fd = self.master_fd.as_raw_fd();
 
-        let epoll_fd = epoll_create()?;
+        // If the child isn't reading its input, we need to keep reading its
+        // output, rather than blocking on writing more input.
+        let master_flags = OFlag::from_bits_truncate(fcntl(master_fd, FcntlArg::F_GETFL)?);
+        fcntl(
+            master_fd,
+            FcntlArg::F_SETFL(master_flags | OFlag::O_NONBLOCK),
+        )?;
+
+        let mut poller = Poller::new()?;
 
-        let mut from_child = FilteredBuffer::new();
+        let mut from_child = FilteredBuffer::new(self.max_read_buffer);
+        if let Some(title_sink) = self.title_sink.take() {
+            from_child.filter.set_title_sink(title_sink);
+        }
+        from_child.filter.set_rewrite_titles(self.rewrite_titles);
+        from_child.recorder = self.recorder.take();
+        if let Some(title) = &initial_title {
+            from_child.filter.set_initial_window_title(title);
+        }
         let mut to_child = Buffer::new();
+        if !typeahead.is_empty() {
+            to_child.push(&typeahead);
+            to_child.flush(master_fd)?;
+        }
 
-        let mut event = EpollEvent::new(EpollFlags::EPOLLIN, 0);
-        epoll_ctl(epoll_fd, EpollOp::EpollCtlAdd, master_fd, &mut event)?;
-        let mut event = EpollEvent::new(EpollFlags::EPOLLIN, 1);
-        epoll_ctl(epoll_fd, EpollOp::EpollCtlAdd, STDIN, &mut event)?;
+        // Input has ended, so there's no more to read
+        let mut stdin_closed = false;
+        // STDIN is registered with the poller
+        let mut stdin_watched = true;
+        // The end of input still has to be passed on to the child
+        let mut eof_pending = false;
 
-        let mut events = vec![EpollEvent::empty(), EpollEvent::empty()];
+        poller.add(master_fd, EpollFlags::EPOLLIN, Token::Master)?;
+        match poller.add(input_fd, EpollFlags::EPOLLIN, Token::Stdin) {
+            Ok(()) => {}
+            // Input from a file, or /dev/null, which can't be polled
+            Err(nix::Error::Sys(Errno::EPERM)) if !input_is_tty => {
+                info!("Input can't be polled, ignoring it");
+                stdin_closed = true;
+                stdin_watched = false;
+                eof_pending = true;
+            }
+            Err(e) => return Err(e),
+        }
+
+        // SIGWINCH when our terminal is resized; on SIGCONT, the terminal
+        // may have been resized while we were stopped. SIGTERM and SIGHUP are
+        // passed on to the child before we exit, rather than leaving it behind.
+        // In raw mode, Ctrl-C reaches the child as input; a SIGINT sent to us
+        // is passed on as if it were that, rather than killing us.
+        let signal_pipe = match SignalPipe::new(&[
+            Signal::SIGWINCH,
+            Signal::SIGCONT,
+            Signal::SIGTERM,
+            Signal::SIGHUP,
+            Signal::SIGINT,
+        ]) {
+            Ok(signal_pipe) => {
+                poller.add(signal_pipe.fd(), EpollFlags::EPOLLIN, Token::Signal)?;
+                Some(signal_pipe)
+            }
+            Err(e) => {
+                warn!("Can't catch signals: {}", e);
+                None
+            }
+        };
+
+        if let Some(fd) = actions.wakeup_fd() {
+            poller.add(fd, EpollFlags::EPOLLIN, Token::Actions)?;
+        }
+
+        // In case of a resize since the child was started
+        self.sync_winsize();
+
+        let mut idle_timer = self
+            .idle_timeout
+            .map(|timeout| IdleTimer::new(timeout, Instant::now()));
+
+        // Waiting for STDOUT to accept pending output
+        let mut stdout_waiting = false;
+        // Not reading from the child because too much output is pending
+        let mut master_paused = false;
+        // Waiting for the child to accept pending input
+        let mut master_waiting = false;
+        // Not reading input because the child isn't accepting it
+        let mut stdin_paused = false;
+        // The child closed the terminal, so has exited or is about to
+        let mut child_done = false;
+        // The signal that told us to exit
+        let mut terminated_by = None;
         let mut done = false;
         while !done {
-            let remaining = self.maybe_check(actions, &mut from_child);
-
-            let event_count = epoll_wait(
-                epoll_fd,
-                &mut events,
-                remaining.as_millis().try_into().unwrap(),
-            )?;
-            for event in &events[0..event_count] {
-                match event.data() {
-                    0 => {
+            let mut remaining = self.maybe_check(actions, &mut from_child);
+
+            if let Some(idle_timer) = &idle_timer {
+                match idle_timer.remaining(Instant::now()) {
+                    Some(idle_remaining) => remaining = min(remaining, idle_remaining),
+                    None => {
+                        info!("Idle timeout reached, hanging up");
+                        self.hang_up();
+                        break;
+                    }
+                }
+            }
+
+            for event in poller.wait(remaining)? {
+                match Token::from_data(event.data()) {
+                    Some(Token::Master) => {
+                        if event.events().contains(EpollFlags::EPOLLOUT) {
+                            to_child.flush(master_fd)?;
+                        }
                         if event.events().contains(EpollFlags::EPOLLIN)
                             || event.events().contains(EpollFlags::EPOLLHUP)
                         {
                             if from_child.fill(master_fd)? {
-                                from_child.flush(STDOUT)?;
-                                self.check_interval = MIN_CHECK_INTERVAL;
+                                // If the application set a title or rang the bell,
+                                // our composed title would be stale until the next
+                                // check; recompose it now, at most once per read.
+                                let title_changed =
+                                    from_child.filter.take_in_window_title_changed();
+                                let bell_changed = from_child.filter.take_bell_changed();
+                                if title_changed || bell_changed {
+                                    Self::update_window_title(actions, &mut from_child);
+                                }
+                                from_child.flush(output_fd)?;
+                                self.output_received(Instant::now());
                             } else {
+                                child_done = true;
                                 done = true;
                             }
                         }
                     }
-                    1 => {
+                    Some(Token::Stdin) => {
                         if event.events().contains(EpollFlags::EPOLLIN)
                             || event.events().contains(EpollFlags::EPOLLHUP)
                         {
-                            if to_child.fill(STDIN)? {
+                            let start = to_child.pending();
+                            if to_child.fill(input_fd)? {
+                                if late_title_report != LateTitleReport::Done {
+                                    let input = late_title_report
+                                        .strip(&to_child.buf[start..to_child.count]);
+                                    to_child.count = start;
+                                    to_child.push(&input);
+                                }
+                                if let Some(idle_timer) = &mut idle_timer {
+                                    idle_timer.input(Instant::now());
+                                }
+                                self.input_received();
                                 to_child.flush(master_fd)?;
-                            } else {
+                            } else if input_is_tty {
+                                // Our terminal has gone away
                                 done = true;
+                            } else {
+                                // The end of piped input; the child
+                                // decides when we're done
+                                stdin_closed = true;
+                                eof_pending = true;
+                            }
+                        }
+                    }
+                    Some(Token::Stdout) => from_child.flush(output_fd)?,
+                    Some(Token::Signal) => {
+                        let signals = match &signal_pipe {
+                            Some(signal_pipe) => signal_pipe.read(),
+                            None => vec![],
+                        };
+                        for signal in signals {
+                            match signal {
+                                Signal::SIGWINCH | Signal::SIGCONT => self.sync_winsize(),
+                                Signal::SIGTERM | Signal::SIGHUP => {
+                                    info!("Received {}, exiting", signal);
+                                    self.signal_child(signal);
+                                    terminated_by = Some(signal);
+                                    done = true;
+                                }
+                                Signal::SIGINT => self.signal_foreground(signal),
+                                _ => {}
+                            }
+                        }
+                    }
+                    Some(Token::Actions) => {
+                        if actions.wakeup() {
+                            if let Some(recorder) = &mut from_child.recorder {
+                                if let Some(context) = actions.log_context() {
+                                    recorder.mark_context(&context);
+                                }
                             }
+                            Self::update_window_title(actions, &mut from_child);
+                            let _ = from_child.flush(output_fd);
                         }
                     }
-                    _ => (),
+                    None => (),
                 }
             }
+
+            if stdin_closed && stdin_watched {
+                poller.delete(input_fd)?;
+                stdin_watched = false;
+            }
+            if eof_pending && to_child.push(&[eof_char(master_fd)]) {
+                eof_pending = false;
+                to_child.flush(master_fd)?;
+            }
+
+            let pending = from_child.pending();
+
+            let want_stdout = pending > 0;
+            if want_stdout != stdout_waiting {
+                if want_stdout {
+                    poller.add(output_fd, EpollFlags::EPOLLOUT, Token::Stdout)?;
+                } else {
+                    poller.delete(output_fd)?;
+                }
+                stdout_waiting = want_stdout;
+            }
+
+            // We still get EPOLLHUP while paused, and read then; the child
+            // has exited, so what is left to read is bounded.
+            let want_pause = pending >= self.max_output_buffer;
+            let want_master = to_child.pending() > 0;
+            if want_pause != master_paused || want_master != master_waiting {
+                let mut flags = EpollFlags::empty();
+                if !want_pause {
+                    flags |= EpollFlags::EPOLLIN;
+                }
+                if want_master {
+                    flags |= EpollFlags::EPOLLOUT;
+                }
+                poller.modify(master_fd, flags, Token::Master)?;
+                master_paused = want_pause;
+                master_waiting = want_master;
+            }
+
+            let want_stdin_pause = to_child.is_full();
+            if want_stdin_pause != stdin_paused && stdin_watched {
+                let flags = if want_stdin_pause {
+                    EpollFlags::empty()
+                } else {
+                    EpollFlags::EPOLLIN
+                };
+                poller.modify(input_fd, flags, Token::Stdin)?;
+                stdin_paused = want_stdin_pause;
+            }
         }
 
-        return Ok(());
+        // Write out whatever output is still pending, blocking this time
+        drop(nonblocking_output);
+        from_child.flush(output_fd)?;
+        if let Some(recorder) = &mut from_child.recorder {
+            recorder.flush();
+        }
+
+        // If the child exited while on the alternate screen (a crashed full-screen
+        // program, for example), don't leave the user looking at it.
+        if from_child.filter.alt_screen() {
+            write_all(output_fd, EXIT_ALT_SCREEN)?;
+        }
+
+        // Don't leave the terminal showing a title describing a session that's
+        // gone; this is done before raw_input is dropped and the terminal
+        // settings are restored.
+        if isatty(output_fd).unwrap_or(false) {
+            from_child.filter.restore_window_title();
+            from_child.flush(output_fd)?;
+        }
+        drop(raw_input);
+
+        // Exit as if the signal had killed us
+        if let Some(signal) = terminated_by {
+            return Ok(128 + signal as i32);
+        }
+
+        Ok(self.child_exit_code(child_done))
     }
 }
 
@@ -299,9 +1203,656 @@ impl Drop for Pty {
     }
 }
 
+// Maps an exit status to an exit code the way shells do for $?: a child killed
+// by a signal gives 128 + the signal number.
+fn exit_code(status: ExitStatus) -> i32 {
+    match (status.code(), status.signal()) {
+        (Some(code), _) => code,
+        (None, Some(signal)) => 128 + signal,
+        (None, None) => 1,
+    }
+}
+
 pub trait PtyActions {
+    /// Called periodically, and more often when there's output, to look at
+    /// what's running in the terminal
     fn check(&mut self);
-    fn make_window_title(&self, in_window_title: &str) -> String {
-        return in_window_title.to_string();
+    fn make_window_title(&self, filter: &Filter) -> String {
+        return filter.in_window_title().to_string();
+    }
+    /// Where the session is, like "container=fedora cwd=/app", to mark in
+    /// the session log when it changes; None not to
+    fn log_context(&self) -> Option<String> {
+        None
+    }
+    /// If check() does its work in the background, an fd that becomes
+    /// readable when it's done
+    fn wakeup_fd(&self) -> Option<RawFd> {
+        None
+    }
+    /// Called when wakeup_fd() is readable; returns true if the window title
+    /// needs to be recomposed
+    fn wakeup(&mut self) -> bool {
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::TerminalState;
+    use nix::fcntl::FdFlag;
+    use nix::sys::signal::{sigaction, SaFlags, SigAction, SigHandler, SigSet};
+    use nix::unistd::pipe;
+
+    #[test]
+    fn test_set_winsize() {
+        let pty = Pty::new().unwrap();
+        pty.set_winsize(40, 100).unwrap();
+
+        let mut winsize = Winsize {
+            ws_row: 0,
+            ws_col: 0,
+            ws_xpixel: 0,
+            ws_ypixel: 0,
+        };
+        unsafe { tiocgwinsz(pty.peer_fd, &mut winsize) }.unwrap();
+        assert_eq!((winsize.ws_row, winsize.ws_col), (40, 100));
+    }
+
+    // Reads everything the child writes until it exits, then waits for it
+    fn read_output(pty: &mut Pty) -> String {
+        // Once the child exits, reading the master fails with EIO
+        let mut output = vec![];
+        let mut buf = [0u8; 1024];
+        while let Ok(n) = read(pty.master_fd.as_raw_fd(), &mut buf) {
+            if n == 0 {
+                break;
+            }
+            output.extend_from_slice(&buf[..n]);
+        }
+        pty.child.take().unwrap().wait().unwrap();
+
+        String::from_utf8_lossy(&output).trim().to_string()
+    }
+
+    #[test]
+    fn test_initial_winsize() {
+        let expected = match terminal_winsize(STDIN) {
+            Ok(winsize) => format!("{} {}", winsize.ws_row, winsize.ws_col),
+            Err(_) => format!("{} {}", DEFAULT_ROWS, DEFAULT_COLS),
+        };
+
+        let mut pty = Pty::new().unwrap();
+        let mut command = Command::new("stty");
+        command.arg("size");
+        pty.spawn(command).unwrap();
+
+        assert_eq!(read_output(&mut pty), expected);
+    }
+
+    #[test]
+    fn test_fork() {
+        // With -c and no further arguments, $0 is the shell's argv[0]
+        let args = [OsString::from("-c"), OsString::from("echo $0")];
+
+        let mut pty = Pty::new().unwrap();
+        pty.fork(Path::new("/bin/sh"), &args, false).unwrap();
+        assert_eq!(read_output(&mut pty), "/bin/sh");
+
+        let mut pty = Pty::new().unwrap();
+        pty.fork(Path::new("/bin/sh"), &args, true).unwrap();
+        assert_eq!(read_output(&mut pty), "-sh");
+    }
+
+    #[test]
+    fn test_cloexec() {
+        let cloexec = |fd| {
+            FdFlag::from_bits_truncate(fcntl(fd, FcntlArg::F_GETFD).unwrap())
+                .contains(FdFlag::FD_CLOEXEC)
+        };
+
+        let pty = Pty::new().unwrap();
+        assert!(cloexec(pty.master_fd.as_raw_fd()));
+        assert!(cloexec(pty.peer_fd));
+        assert!(cloexec(Poller::new().unwrap().epoll_fd));
+    }
+
+    #[test]
+    fn test_signal_foreground() {
+        let mut pty = Pty::new().unwrap();
+        let mut command = Command::new("/bin/sh");
+        command
+            .arg("-c")
+            .arg("trap 'echo interrupted; exit' INT; echo ready; while :; do sleep 0.1; done");
+        pty.spawn(command).unwrap();
+
+        let mut output = vec![];
+        let mut buf = [0u8; 1024];
+        while !String::from_utf8_lossy(&output).contains("ready") {
+            let count = read(pty.master_fd.as_raw_fd(), &mut buf).unwrap();
+            output.extend_from_slice(&buf[..count]);
+        }
+
+        // The child is in the foreground of its own terminal
+        let child_pid = pty.child.as_ref().unwrap().id() as i32;
+        assert_eq!(
+            tcgetpgrp(pty.master_fd.as_raw_fd()).unwrap(),
+            Pid::from_raw(child_pid)
+        );
+
+        pty.signal_foreground(Signal::SIGINT);
+        assert_eq!(read_output(&mut pty), "interrupted");
+    }
+
+    #[test]
+    fn test_full_buffer() {
+        let (read_fd, write_fd) = pipe().unwrap();
+        let mut buffer = Buffer::new();
+
+        let data = vec![b'x'; buffer.buf.len()];
+        write_all(write_fd, &data).unwrap();
+        assert!(buffer.fill(read_fd).unwrap());
+        assert_eq!(buffer.count, buffer.buf.len());
+
+        // More input while the buffer is full isn't end-of-file
+        write_all(write_fd, b"more").unwrap();
+        assert!(buffer.fill(read_fd).unwrap());
+        assert_eq!(buffer.count, buffer.buf.len());
+
+        let (out_read_fd, out_write_fd) = pipe().unwrap();
+        buffer.flush(out_write_fd).unwrap();
+        assert!(buffer.fill(read_fd).unwrap());
+        assert_eq!(&buffer.buf[..buffer.count], b"more");
+
+        close(write_fd).unwrap();
+        assert!(!buffer.fill(read_fd).unwrap());
+
+        for fd in &[read_fd, out_read_fd, out_write_fd] {
+            close(*fd).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_partial_flush() {
+        let (read_fd, write_fd) = pipe().unwrap();
+        fcntl(write_fd, FcntlArg::F_SETFL(OFlag::O_NONBLOCK)).unwrap();
+
+        // More than the pipe holds; what doesn't fit stays pending
+        let data: Vec<u8> = (0..(256 * 1024)).map(|i| (i % 251) as u8).collect();
+        let (written, result) = write_some(write_fd, &data);
+        assert!(result.is_ok());
+        assert!(written > 0 && written < data.len());
+
+        let mut from_child = FilteredBuffer::new(DEFAULT_MAX_READ_BUFFER);
+        from_child.filter.set_rewrite_titles(false);
+        from_child.filter.fill(b"hello");
+        from_child.flush(write_fd).unwrap();
+        assert_eq!(from_child.pending(), 5);
+
+        // Reading some makes room for some more, picking up where we stopped
+        let mut buf = vec![0u8; 4096];
+        let mut received = vec![];
+        while received.len() < 8192 {
+            let count = read(read_fd, &mut buf).unwrap();
+            received.extend_from_slice(&buf[..count]);
+        }
+        let (more, result) = write_some(write_fd, &data[written..]);
+        assert!(result.is_ok());
+        assert!(more > 0);
+
+        // An error after part of the data is written leaves the rest pending
+        close(read_fd).unwrap();
+        let mut to_child = Buffer::new();
+        assert!(to_child.push(b"lost"));
+        assert_eq!(to_child.flush(write_fd), Err(nix::Error::Sys(Errno::EPIPE)));
+        assert_eq!(to_child.pending(), 4);
+        assert_eq!(
+            from_child.flush(write_fd),
+            Err(nix::Error::Sys(Errno::EPIPE))
+        );
+        assert_eq!(from_child.pending(), 5);
+
+        // And nothing was repeated
+        assert_eq!(&received[..], &data[..received.len()]);
+
+        close(write_fd).unwrap();
+    }
+
+    #[test]
+    fn test_growing_buffer() {
+        let (read_fd, write_fd) = pipe().unwrap();
+        let mut buffer = FilteredBuffer::new(3 * INITIAL_READ_BUFFER);
+
+        write_all(write_fd, b"short").unwrap();
+        assert!(buffer.fill(read_fd).unwrap());
+        assert_eq!(buffer.raw.buf.len(), INITIAL_READ_BUFFER);
+
+        // Each read that fills the buffer grows it, up to the maximum
+        let data = vec![b'x'; 7 * INITIAL_READ_BUFFER];
+        write_all(write_fd, &data).unwrap();
+        assert!(buffer.fill(read_fd).unwrap());
+        assert_eq!(buffer.raw.buf.len(), 2 * INITIAL_READ_BUFFER);
+        assert!(buffer.fill(read_fd).unwrap());
+        assert_eq!(buffer.raw.buf.len(), 3 * INITIAL_READ_BUFFER);
+        assert!(buffer.fill(read_fd).unwrap());
+        assert_eq!(buffer.raw.buf.len(), 3 * INITIAL_READ_BUFFER);
+        assert_eq!(buffer.pending(), 5 + 6 * INITIAL_READ_BUFFER);
+
+        for fd in &[read_fd, write_fd] {
+            close(*fd).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_output_check_interval() {
+        let mut pty = Pty::new().unwrap();
+        let start = Instant::now();
+        let slow = DEFAULT_MIN_CHECK_INTERVAL * DEFAULT_CHECK_INTERVAL_MULTIPLIER;
+
+        pty.check_interval = slow;
+        pty.output_received(start);
+        assert_eq!(pty.check_interval, DEFAULT_MIN_CHECK_INTERVAL);
+
+        // Continuous output
+        pty.check_interval = slow;
+        pty.output_received(start + OUTPUT_IDLE_GAP / 2);
+        assert_eq!(pty.check_interval, slow);
+
+        // Output after a gap
+        pty.output_received(start + OUTPUT_IDLE_GAP * 2);
+        assert_eq!(pty.check_interval, DEFAULT_MIN_CHECK_INTERVAL);
+
+        // Output after input
+        pty.check_interval = slow;
+        pty.input_received();
+        pty.output_received(start + OUTPUT_IDLE_GAP * 2);
+        assert_eq!(pty.check_interval, DEFAULT_MIN_CHECK_INTERVAL);
+    }
+
+    #[test]
+    fn test_check_intervals() {
+        struct CountChecks(u32);
+        impl PtyActions for CountChecks {
+            fn check(&mut self) {
+                self.0 += 1;
+            }
+        }
+
+        let mut pty = Pty::new().unwrap();
+        pty.set_check_intervals(Duration::from_millis(10), Duration::from_millis(50), 2);
+        let mut actions = CountChecks(0);
+        let mut from_child = FilteredBuffer::new(DEFAULT_MAX_READ_BUFFER);
+        // Nothing to write to the terminal
+        from_child.filter.set_rewrite_titles(false);
+
+        let mut intervals = vec![];
+        for _ in 0..4 {
+            // As if the last check was long enough ago
+            pty.last_check_time = pty
+                .last_check_time
+                .map(|last_check_time| last_check_time - Duration::from_secs(1));
+            intervals.push(pty.maybe_check(&mut actions, &mut from_child));
+        }
+        assert_eq!(actions.0, 4);
+        assert_eq!(
+            intervals,
+            [20, 40, 50, 50]
+                .iter()
+                .map(|&ms| Duration::from_millis(ms))
+                .collect::<Vec<_>>()
+        );
+
+        pty.output_received(Instant::now());
+        assert_eq!(pty.check_interval, Duration::from_millis(10));
+    }
+
+    struct TitleActions;
+
+    impl PtyActions for TitleActions {
+        fn check(&mut self) {}
+
+        fn make_window_title(&self, filter: &Filter) -> String {
+            format!("ttymon: {}", filter.in_window_title())
+        }
+    }
+
+    #[test]
+    fn test_handle() {
+        let (input_read_fd, input_write_fd) = pipe().unwrap();
+        let (output_read_fd, output_write_fd) = pipe().unwrap();
+
+        let mut pty = Pty::new().unwrap();
+        pty.input_fd = input_read_fd;
+        pty.output_fd = output_write_fd;
+        let args = [
+            OsString::from("-c"),
+            OsString::from("printf '\\033]0;vim\\007hello\\n'"),
+        ];
+        pty.fork(Path::new("/bin/sh"), &args, false).unwrap();
+        assert_eq!(pty.handle(&mut TitleActions).unwrap(), 0);
+        close(output_write_fd).unwrap();
+
+        let mut output = vec![];
+        let mut buf = [0u8; 1024];
+        loop {
+            match read(output_read_fd, &mut buf).unwrap() {
+                0 => break,
+                count => output.extend_from_slice(&buf[..count]),
+            }
+        }
+
+        // Our title goes out before the child has set one. The child's title
+        // is dropped, and ours, with it in and terminated the same way, is
+        // recomposed once the output read along with it is filtered.
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "\x1b]0;ttymon: ttymon\x1b\\hello\r\n\x1b]0;ttymon: vim\x07"
+        );
+
+        for fd in &[input_read_fd, input_write_fd, output_read_fd] {
+            close(*fd).unwrap();
+        }
+    }
+
+    // Updates a real TerminalState on each check, as the monitor would
+    struct StateActions {
+        state: TerminalState,
+    }
+
+    impl PtyActions for StateActions {
+        fn check(&mut self) {
+            self.state.update();
+        }
+
+        fn make_window_title(&self, _filter: &Filter) -> String {
+            format!("ttymon: {}", self.state.foreground().command())
+        }
+    }
+
+    #[test]
+    fn test_handle_child_exits() {
+        // Another pty stands in for our terminal, so there are terminal
+        // settings to restore
+        let terminal = Pty::new().unwrap();
+        let terminal_fd = terminal.peer_fd;
+        let orig_attr = termios::tcgetattr(terminal_fd).unwrap();
+        let orig_flags = fcntl(terminal_fd, FcntlArg::F_GETFL).unwrap();
+
+        for &(program, expected) in &[("/bin/true", 0), ("/bin/false", 1)] {
+            let mut pty = Pty::new().unwrap();
+            pty.input_fd = terminal_fd;
+            pty.output_fd = terminal_fd;
+            let pid = pty.fork(Path::new(program), &[], false).unwrap();
+
+            // The first check may well find the child already gone
+            let mut actions = StateActions {
+                state: TerminalState::new(pid as i32),
+            };
+            assert_eq!(pty.handle(&mut actions).unwrap(), expected);
+
+            // And now it certainly is, and has been reaped
+            actions.state.update();
+            assert_eq!(actions.state.foreground().command(), "");
+
+            let attr = termios::tcgetattr(terminal_fd).unwrap();
+            assert_eq!(attr.local_flags, orig_attr.local_flags);
+            assert_eq!(attr.input_flags, orig_attr.input_flags);
+            assert_eq!(fcntl(terminal_fd, FcntlArg::F_GETFL).unwrap(), orig_flags);
+        }
+    }
+
+    #[test]
+    fn test_parse_title_report() {
+        let parse = |input: &[u8]| {
+            parse_title_report(input).map(|(title, rest)| {
+                (
+                    String::from_utf8(title).unwrap(),
+                    String::from_utf8(rest).unwrap(),
+                )
+            })
+        };
+        let result = |title: &str, rest: &str| Some((String::from(title), String::from(rest)));
+
+        assert_eq!(parse(b"\x1b]lTerminal\x1b\\"), result("Terminal", ""));
+        assert_eq!(parse(b"\x1b]l~ - bash\x07"), result("~ - bash", ""));
+        assert_eq!(parse(b"\x1b]l\x1b\\"), result("", ""));
+        // Typed before and after the reply arrived
+        assert_eq!(parse(b"ls\x1b]lvim\x1b\\ -l\r"), result("vim", "ls -l\r"));
+
+        assert_eq!(parse(b""), None);
+        assert_eq!(parse(b"ls"), None);
+        assert_eq!(parse(b"\x1b]lTermi"), None);
+        assert_eq!(parse(b"\x1b]lTerminal\x1b"), None);
+    }
+
+    #[test]
+    fn test_late_title_report() {
+        let strip = |late_report: &mut LateTitleReport, input: &[u8]| {
+            String::from_utf8(late_report.strip(input)).unwrap()
+        };
+
+        let mut late_report = LateTitleReport::Expected;
+        assert_eq!(strip(&mut late_report, b"ls"), "ls");
+        assert_eq!(late_report, LateTitleReport::Expected);
+        assert_eq!(
+            strip(&mut late_report, b" -l\x1b]lTerminal\x1b\\\r"),
+            " -l\r"
+        );
+        assert_eq!(late_report, LateTitleReport::Done);
+        // Only one report is dropped
+        assert_eq!(strip(&mut late_report, b"\x1b]lvim\x07"), "\x1b]lvim\x07");
+
+        // Split across reads
+        let mut late_report = LateTitleReport::Expected;
+        assert_eq!(strip(&mut late_report, b"ls\x1b]lTermi"), "ls");
+        assert_eq!(late_report, LateTitleReport::Title);
+        assert_eq!(strip(&mut late_report, b"nal\x1b"), "");
+        assert_eq!(late_report, LateTitleReport::Terminator);
+        assert_eq!(strip(&mut late_report, b"\\ -l"), " -l");
+        assert_eq!(late_report, LateTitleReport::Done);
+
+        let mut late_report = LateTitleReport::Expected;
+        assert_eq!(strip(&mut late_report, b"\x1b]l~ - bash\x07ls"), "ls");
+        assert_eq!(late_report, LateTitleReport::Done);
+    }
+
+    #[test]
+    fn test_eof_char() {
+        let pty = Pty::new().unwrap();
+        assert_eq!(eof_char(pty.master_fd.as_raw_fd()), CTRL_D);
+
+        let mut buffer = Buffer::new();
+        assert!(buffer.push(b"hello"));
+        let data = vec![b'x'; buffer.buf.len() - 6];
+        assert!(buffer.push(&data));
+        assert!(!buffer.push(b"xx"));
+        assert!(buffer.push(&[CTRL_D]));
+        assert!(buffer.is_full());
+    }
+
+    #[test]
+    fn test_slow_input() {
+        let (read_fd, write_fd) = pipe().unwrap();
+        fcntl(write_fd, FcntlArg::F_SETFL(OFlag::O_NONBLOCK)).unwrap();
+
+        // Fill the pipe, as a child that isn't reading its input would
+        let mut filled = 0;
+        while let Ok(count) = write(write_fd, &[b'x'; 4096]) {
+            filled += count;
+        }
+
+        let (input_read_fd, input_write_fd) = pipe().unwrap();
+        write_all(input_write_fd, b"hello").unwrap();
+        let mut to_child = Buffer::new();
+        assert!(to_child.fill(input_read_fd).unwrap());
+
+        // Doesn't block
+        to_child.flush(write_fd).unwrap();
+        assert_eq!(to_child.pending(), 5);
+
+        let mut buf = vec![0u8; filled];
+        let mut drained = 0;
+        while drained < filled {
+            drained += read(read_fd, &mut buf[drained..]).unwrap();
+        }
+
+        to_child.flush(write_fd).unwrap();
+        assert_eq!(to_child.pending(), 0);
+        let count = read(read_fd, &mut buf).unwrap();
+        assert_eq!(&buf[..count], b"hello");
+
+        for fd in &[read_fd, write_fd, input_read_fd, input_write_fd] {
+            close(*fd).unwrap();
+        }
+    }
+
+    extern "C" fn ignore_signal(_: nix::libc::c_int) {}
+
+    #[test]
+    fn test_interrupted() {
+        // Without SA_RESTART, a blocking read or epoll_wait fails with EINTR
+        // when a signal arrives
+        let action = SigAction::new(
+            SigHandler::Handler(ignore_signal),
+            SaFlags::empty(),
+            SigSet::empty(),
+        );
+        unsafe { sigaction(Signal::SIGUSR1, &action) }.unwrap();
+
+        let (read_fd, write_fd) = pipe().unwrap();
+        let thread = unsafe { nix::libc::pthread_self() };
+        let interrupter = std::thread::spawn(move || {
+            for _ in 0..2 {
+                std::thread::sleep(Duration::from_millis(100));
+                unsafe { nix::libc::pthread_kill(thread, Signal::SIGUSR1 as nix::libc::c_int) };
+            }
+            std::thread::sleep(Duration::from_millis(100));
+            write_all(write_fd, b"x").unwrap();
+        });
+
+        let mut poller = Poller::new().unwrap();
+        poller
+            .add(read_fd, EpollFlags::EPOLLIN, Token::Master)
+            .unwrap();
+        // The first signal interrupts waiting, the second reading
+        assert!(poller.wait(Duration::from_secs(10)).unwrap().is_empty());
+
+        let mut buffer = Buffer::new();
+        assert!(buffer.fill(read_fd).unwrap());
+        assert_eq!(&buffer.buf[..buffer.count], b"x");
+        interrupter.join().unwrap();
+
+        let action = SigAction::new(SigHandler::SigDfl, SaFlags::empty(), SigSet::empty());
+        unsafe { sigaction(Signal::SIGUSR1, &action) }.unwrap();
+        close(read_fd).unwrap();
+        close(write_fd).unwrap();
+    }
+
+    #[test]
+    fn test_idle_timer() {
+        let start = Instant::now();
+        let mut idle_timer = IdleTimer::new(Duration::from_secs(60), start);
+        assert_eq!(idle_timer.remaining(start), Some(Duration::from_secs(60)));
+        assert_eq!(
+            idle_timer.remaining(start + Duration::from_secs(45)),
+            Some(Duration::from_secs(15))
+        );
+
+        // Input resets the timer
+        idle_timer.input(start + Duration::from_secs(45));
+        assert_eq!(
+            idle_timer.remaining(start + Duration::from_secs(60)),
+            Some(Duration::from_secs(45))
+        );
+
+        assert_eq!(idle_timer.remaining(start + Duration::from_secs(105)), None);
+        assert_eq!(idle_timer.remaining(start + Duration::from_secs(200)), None);
+    }
+
+    #[test]
+    fn test_poller() {
+        let mut poller = Poller::new().unwrap();
+        let pipes: Vec<(RawFd, RawFd)> = Token::ALL.iter().map(|_| pipe().unwrap()).collect();
+        for ((read_fd, write_fd), token) in pipes.iter().zip(Token::ALL.iter()) {
+            write_all(*write_fd, b"x").unwrap();
+            poller.add(*read_fd, EpollFlags::EPOLLIN, *token).unwrap();
+        }
+
+        // An event for every registered fd, even beyond the original two
+        let events = poller.wait(Duration::from_secs(0)).unwrap();
+        let mut tokens: Vec<Token> = events
+            .iter()
+            .map(|e| Token::from_data(e.data()).unwrap())
+            .collect();
+        tokens.sort_by_key(|t| *t as u64);
+        assert_eq!(tokens, Token::ALL);
+
+        poller.delete(pipes[2].0).unwrap();
+        assert_eq!(
+            poller.wait(Duration::from_secs(0)).unwrap().len(),
+            Token::ALL.len() - 1
+        );
+
+        for (read_fd, write_fd) in pipes {
+            close(read_fd).unwrap();
+            close(write_fd).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_slow_output() {
+        let (read_fd, write_fd) = pipe().unwrap();
+        fcntl(write_fd, FcntlArg::F_SETFL(OFlag::O_NONBLOCK)).unwrap();
+
+        // More than the pipe buffer holds
+        let output: Vec<u8> = (0..256 * 1024).map(|i| b'a' + (i % 26) as u8).collect();
+        let mut from_child = FilteredBuffer::new(DEFAULT_MAX_READ_BUFFER);
+        from_child.filter.fill(&output);
+
+        from_child.flush(write_fd).unwrap();
+        assert!(from_child.pending() > 0);
+        assert!(from_child.pending() < output.len());
+
+        // Drain as a slow consumer would, in small pieces
+        let mut received = vec![];
+        let mut buf = [0u8; 1000];
+        while received.len() < output.len() {
+            let count = read(read_fd, &mut buf).unwrap();
+            received.extend_from_slice(&buf[..count]);
+            from_child.flush(write_fd).unwrap();
+        }
+        assert_eq!(from_child.pending(), 0);
+        assert!(received == output);
+
+        close(read_fd).unwrap();
+        close(write_fd).unwrap();
+    }
+
+    #[test]
+    fn test_exit_code() {
+        let run = |script: &str| {
+            let status = Command::new("/bin/sh")
+                .arg("-c")
+                .arg(script)
+                .status()
+                .unwrap();
+            exit_code(status)
+        };
+
+        assert_eq!(run("exit 0"), 0);
+        assert_eq!(run("exit 3"), 3);
+        assert_eq!(run("kill -TERM $$"), 128 + 15);
+        assert_eq!(run("kill -KILL $$"), 128 + 9);
+    }
+
+    #[test]
+    fn test_fill_closed_pty() {
+        let mut pty = Pty::new().unwrap();
+        pty.close_peer_fd().unwrap();
+
+        // EIO, which means the same as end-of-file here
+        let mut buffer = Buffer::new();
+        assert!(!buffer.fill(pty.master_fd.as_raw_fd()).unwrap());
     }
 }
@@ -0,0 +1,187 @@
+// Recording the output of the session, like script(1)
+//
+// The output is recorded as the child wrote it, before filtering, so the
+// recording is faithful to what the program emitted. Optionally, a timing
+// file is written alongside it, in the format scriptreplay(1) reads: a line
+// for each chunk of output, with the seconds since the previous chunk and the
+// number of bytes. Like script(1), the log starts with a "Script started"
+// line, which scriptreplay skips.
+//
+// With log_context, a line like "--- container=fedora cwd=/app ---" is
+// recorded whenever the foreground's container or working directory changes,
+// so a long recording can be followed.
+
+use crate::command_log::format_utc;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::time::{Instant, SystemTime};
+
+pub struct Recorder {
+    log: BufWriter<File>,
+    timing: Option<BufWriter<File>>,
+    last_time: Instant,
+    failed: bool,
+    // What mark_context() last recorded
+    last_context: Option<String>,
+}
+
+fn open_append(path: &Path) -> io::Result<BufWriter<File>> {
+    let file = OpenOptions::new().append(true).create(true).open(path)?;
+    Ok(BufWriter::new(file))
+}
+
+impl Recorder {
+    /// Opens the log file, and the timing file if given, appending to them
+    /// if they already exist
+    pub fn open(log_path: &Path, timing_path: Option<&Path>) -> io::Result<Recorder> {
+        let log = open_append(log_path)?;
+        let appending = log.get_ref().metadata()?.len() > 0;
+        let mut recorder = Recorder {
+            log,
+            timing: match timing_path {
+                Some(timing_path) => Some(open_append(timing_path)?),
+                None => None,
+            },
+            last_time: Instant::now(),
+            failed: false,
+            last_context: None,
+        };
+
+        // scriptreplay only skips the first line of the log, so after an
+        // earlier recording, our header is replayed like any other output
+        let header = format!("Script started on {}\n", format_utc(SystemTime::now()));
+        if appending {
+            recorder.write(header.as_bytes(), recorder.last_time)?;
+        } else {
+            recorder.log.write_all(header.as_bytes())?;
+        }
+
+        Ok(recorder)
+    }
+
+    /// Records output from the child. If writing fails, a warning is logged
+    /// and the rest of the session isn't recorded.
+    pub fn record(&mut self, data: &[u8]) {
+        if self.failed || data.is_empty() {
+            return;
+        }
+
+        if let Err(e) = self.write(data, Instant::now()) {
+            warn!("Can't write to session log, stopping recording: {}", e);
+            self.failed = true;
+        }
+    }
+
+    /// Records a line describing where the session is, if it's changed
+    /// since the last one. The line is recorded as output, so it shows up in
+    /// a replay too.
+    pub fn mark_context(&mut self, context: &str) {
+        if self.last_context.as_deref() == Some(context) {
+            return;
+        }
+        self.last_context = Some(context.to_string());
+        self.record(format!("\r\n--- {} ---\r\n", context).as_bytes());
+    }
+
+    fn write(&mut self, data: &[u8], now: Instant) -> io::Result<()> {
+        self.log.write_all(data)?;
+        if let Some(timing) = &mut self.timing {
+            let elapsed = now.duration_since(self.last_time);
+            writeln!(
+                timing,
+                "{}.{:06} {}",
+                elapsed.as_secs(),
+                elapsed.subsec_micros(),
+                data.len()
+            )?;
+        }
+        self.last_time = now;
+
+        Ok(())
+    }
+
+    /// Writes out anything buffered
+    pub fn flush(&mut self) {
+        let result = self.log.flush().and_then(|_| match &mut self.timing {
+            Some(timing) => timing.flush(),
+            None => Ok(()),
+        });
+        if let Err(e) = result {
+            warn!("Can't write to session log: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::time::Duration;
+
+    #[test]
+    fn test_recorder() {
+        let dir = std::env::temp_dir().join(format!("ttymon-test-{}-recorder", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let log_path = dir.join("typescript");
+        let timing_path = dir.join("timing");
+        fs::write(&log_path, b"before\n").unwrap();
+
+        let mut recorder = Recorder::open(&log_path, Some(&timing_path)).unwrap();
+        let start = recorder.last_time;
+        recorder.flush();
+        let log = fs::read_to_string(&log_path).unwrap();
+        let header = log.strip_prefix("before\n").unwrap();
+        assert!(header.starts_with("Script started on "));
+        assert!(header.ends_with('\n'));
+        recorder
+            .write(
+                b"\x1b]0;title\x07hello",
+                start + Duration::from_millis(1500),
+            )
+            .unwrap();
+        recorder.record(b"");
+        recorder
+            .write(b"\r\n", start + Duration::from_millis(1502))
+            .unwrap();
+        recorder.flush();
+
+        assert_eq!(
+            fs::read_to_string(&log_path).unwrap(),
+            format!("before\n{}\x1b]0;title\x07hello\r\n", header)
+        );
+        // Appending, the header is in the timing too
+        assert_eq!(
+            fs::read_to_string(&timing_path).unwrap(),
+            format!("0.000000 {}\n1.500000 15\n0.002000 2\n", header.len())
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_mark_context() {
+        let dir = std::env::temp_dir().join(format!("ttymon-test-{}-context", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let log_path = dir.join("typescript");
+
+        let mut recorder = Recorder::open(&log_path, None).unwrap();
+        recorder.mark_context("cwd=/home/user");
+        recorder.record(b"$ cd /app\r\n");
+        recorder.mark_context("cwd=/home/user");
+        recorder.mark_context("container=fedora cwd=/app");
+        recorder.flush();
+
+        let log = fs::read_to_string(&log_path).unwrap();
+        let (header, rest) = log.split_at(log.find('\n').unwrap() + 1);
+        assert!(header.starts_with("Script started on "));
+        assert_eq!(
+            rest,
+            "\r\n--- cwd=/home/user ---\r\n$ cd /app\r\n\r\n--- container=fedora cwd=/app ---\r\n"
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
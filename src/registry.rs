@@ -0,0 +1,346 @@
+// This module implements a registry of running ttymon instances, so that
+// something like a status bar or a terminal switcher can see the state of all
+// terminals at once.
+//
+// The registry lives in $XDG_RUNTIME_DIR/ttymon/. Whichever ttymon holds the
+// lock on registry.lock serves the registry socket; every other instance keeps
+// a connection to that socket open and sends a line describing its state
+// whenever that changes:
+//
+//   update {"pid":1234,"tty":"pts/3","container":null,"cwd":"/home/user","command":"vim"}
+//
+// An instance's entry is dropped when its connection closes - since the kernel
+// closes the connection when a process exits, this also cleans up after an
+// instance that crashed or was killed. If the serving instance exits, the lock
+// is released, one of the remaining instances takes over serving, and the
+// others reconnect to it and send their current state again.
+//
+// To query the registry, connect to the socket and send "list"; the response
+// is a JSON array of the states of all registered instances.
+
+use crate::state::StateSnapshot;
+use nix::fcntl::{flock, FlockArg};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fs::{self, DirBuilder, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::os::unix::fs::DirBuilderExt;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+const LOCK_NAME: &str = "registry.lock";
+const SOCKET_NAME: &str = "registry";
+
+// How long to wait before trying again if we can neither serve the registry
+// nor connect to it - typically because another instance just took the lock
+// and hasn't bound the socket yet.
+const RETRY_INTERVAL: Duration = Duration::from_millis(500);
+
+// Don't let a wedged server block our main loop
+const WRITE_TIMEOUT: Duration = Duration::from_millis(100);
+
+// The key for our own entry when we are serving; connected instances get
+// keys counting up from 1.
+const SELF_ID: u64 = 0;
+
+type Entries = Arc<Mutex<BTreeMap<u64, String>>>;
+
+// What we register for this instance
+#[derive(Serialize)]
+struct Entry<'a> {
+    pid: i32,
+    tty: Option<&'a str>,
+    container: Option<&'a str>,
+    cwd: &'a str,
+    command: &'a str,
+}
+
+struct Shared {
+    // Our most recent state, as JSON
+    state: Option<String>,
+    // When connected to another instance serving the registry
+    connection: Option<UnixStream>,
+    // When we are serving the registry
+    entries: Option<Entries>,
+}
+
+pub struct Registry {
+    shared: Arc<Mutex<Shared>>,
+}
+
+impl Registry {
+    /// Registers this instance in the default registry location; the connection
+    /// (or serving the registry) is handled on a separate thread.
+    pub fn start() -> Registry {
+        Registry::start_in(registry_dir())
+    }
+
+    fn start_in(dir: PathBuf) -> Registry {
+        let shared = Arc::new(Mutex::new(Shared {
+            state: None,
+            connection: None,
+            entries: None,
+        }));
+
+        let thread_shared = shared.clone();
+        thread::spawn(move || run(&dir, &thread_shared));
+
+        Registry { shared }
+    }
+
+    /// Updates the state registered for this instance. It's fine to call this
+    /// with an unchanged state - nothing will be sent.
+    pub fn update(&self, tty: Option<&str>, snapshot: &StateSnapshot) {
+        let state = entry_json(nix::unistd::getpid().as_raw(), tty, snapshot);
+
+        let mut shared = self.shared.lock().unwrap();
+        if shared.state.as_ref() == Some(&state) {
+            return;
+        }
+
+        if let Some(entries) = &shared.entries {
+            entries.lock().unwrap().insert(SELF_ID, state.clone());
+        }
+
+        if let Some(connection) = &mut shared.connection {
+            if let Err(e) = writeln!(connection, "update {}", state) {
+                // The thread waiting on the connection will notice it closing
+                // and reconnect.
+                debug!("Failed to send state to registry: {}", e);
+            }
+        }
+
+        shared.state = Some(state);
+    }
+}
+
+/// Gets the states of all registered instances, as a JSON array
+pub fn query() -> io::Result<String> {
+    query_in(&registry_dir())
+}
+
+fn query_in(dir: &Path) -> io::Result<String> {
+    let mut stream = UnixStream::connect(dir.join(SOCKET_NAME))?;
+    stream.write_all(b"list\n")?;
+
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+
+    Ok(response)
+}
+
+fn registry_dir() -> PathBuf {
+    match dirs::runtime_dir() {
+        Some(dir) => dir.join("ttymon"),
+        None => std::env::temp_dir().join(format!("ttymon-{}", nix::unistd::getuid())),
+    }
+}
+
+fn run(dir: &Path, shared: &Mutex<Shared>) {
+    if let Err(e) = DirBuilder::new().recursive(true).mode(0o700).create(dir) {
+        warn!("Can't create registry directory {}: {}", dir.display(), e);
+        return;
+    }
+
+    let socket_path = dir.join(SOCKET_NAME);
+
+    loop {
+        match try_lock(&dir.join(LOCK_NAME)) {
+            Ok(Some(lock)) => {
+                // Any existing socket was left behind by an instance that
+                // exited while holding the lock.
+                let _ = fs::remove_file(&socket_path);
+                match UnixListener::bind(&socket_path) {
+                    Ok(listener) => serve(listener, shared),
+                    Err(e) => warn!("Can't create registry socket: {}", e),
+                }
+                drop(lock);
+                return;
+            }
+            Ok(None) => {}
+            Err(e) => {
+                warn!("Can't lock registry: {}", e);
+                return;
+            }
+        }
+
+        match UnixStream::connect(&socket_path) {
+            Ok(stream) => connected(stream, shared),
+            Err(e) => debug!("Can't connect to registry: {}", e),
+        }
+
+        thread::sleep(RETRY_INTERVAL);
+    }
+}
+
+// Returns the open lock file if we got the lock, None if another instance holds it
+fn try_lock(path: &Path) -> io::Result<Option<File>> {
+    let file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)?;
+    match flock(file.as_raw_fd(), FlockArg::LockExclusiveNonblock) {
+        Ok(()) => Ok(Some(file)),
+        Err(nix::Error::Sys(nix::errno::Errno::EWOULDBLOCK)) => Ok(None),
+        Err(e) => Err(io::Error::new(io::ErrorKind::Other, e)),
+    }
+}
+
+// Handles a connection to the instance serving the registry, returning when
+// the connection closes.
+fn connected(mut stream: UnixStream, shared: &Mutex<Shared>) {
+    let mut writer = match stream.try_clone() {
+        Ok(writer) => writer,
+        Err(_) => return,
+    };
+    let _ = writer.set_write_timeout(Some(WRITE_TIMEOUT));
+
+    {
+        let mut shared = shared.lock().unwrap();
+        if let Some(state) = &shared.state {
+            if writeln!(writer, "update {}", state).is_err() {
+                return;
+            }
+        }
+        shared.connection = Some(writer);
+    }
+
+    // The server never sends us anything, so this only returns once the
+    // connection is closed.
+    let mut buf = [0u8; 64];
+    while let Ok(n) = stream.read(&mut buf) {
+        if n == 0 {
+            break;
+        }
+    }
+
+    shared.lock().unwrap().connection = None;
+}
+
+fn serve(listener: UnixListener, shared: &Mutex<Shared>) {
+    let entries: Entries = Arc::new(Mutex::new(BTreeMap::new()));
+
+    {
+        let mut shared = shared.lock().unwrap();
+        if let Some(state) = &shared.state {
+            entries.lock().unwrap().insert(SELF_ID, state.clone());
+        }
+        shared.entries = Some(entries.clone());
+    }
+
+    let mut next_id = SELF_ID + 1;
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => {
+                let id = next_id;
+                next_id += 1;
+
+                let entries = entries.clone();
+                thread::spawn(move || handle_client(id, stream, &entries));
+            }
+            Err(e) => warn!("Failed to accept registry connection: {}", e),
+        }
+    }
+}
+
+fn handle_client(id: u64, stream: UnixStream, entries: &Entries) {
+    let mut writer = match stream.try_clone() {
+        Ok(writer) => writer,
+        Err(_) => return,
+    };
+
+    for line in BufReader::new(stream).lines() {
+        let line = match line {
+            Ok(line) => line,
+            Err(_) => break,
+        };
+
+        if let Some(state) = line.strip_prefix("update ") {
+            entries.lock().unwrap().insert(id, state.to_string());
+        } else if line == "list" {
+            let list = {
+                let entries = entries.lock().unwrap();
+                let states: Vec<&str> = entries.values().map(|s| s.as_str()).collect();
+                format!("[{}]\n", states.join(","))
+            };
+            let _ = writer.write_all(list.as_bytes());
+            break;
+        } else {
+            debug!("Unknown registry command: {}", line);
+        }
+    }
+
+    entries.lock().unwrap().remove(&id);
+}
+
+fn entry_json(pid: i32, tty: Option<&str>, snapshot: &StateSnapshot) -> String {
+    let entry = Entry {
+        pid,
+        tty,
+        container: snapshot.container_name.as_deref(),
+        cwd: &snapshot.cwd.to_string_lossy(),
+        command: &snapshot.command,
+    };
+    // Nothing in an entry can fail to serialize
+    serde_json::to_string(&entry).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+
+    #[test]
+    fn test_entry_json() {
+        let snapshot = StateSnapshot {
+            container_name: None,
+            cwd: PathBuf::from("/home/user/a \"quoted\"\\dir"),
+            argv0: String::from("/usr/bin/vim"),
+            command: String::from("vim\t\x1b"),
+        };
+        assert_eq!(
+            entry_json(42, Some("pts/3"), &snapshot),
+            "{\"pid\":42,\"tty\":\"pts/3\",\"container\":null,\
+             \"cwd\":\"/home/user/a \\\"quoted\\\"\\\\dir\",\"command\":\"vim\\t\\u001b\"}"
+        );
+    }
+
+    #[test]
+    fn test_registry() {
+        let dir = std::env::temp_dir().join(format!("ttymon-test-{}", std::process::id()));
+        let snapshot = |command: &str| StateSnapshot {
+            container_name: Some(String::from("fedora")),
+            cwd: PathBuf::from("/"),
+            argv0: String::from(command),
+            command: String::from(command),
+        };
+
+        // The first registry serves, the second connects to it
+        let first = Registry::start_in(dir.clone());
+        first.update(Some("pts/1"), &snapshot("first"));
+        let second = Registry::start_in(dir.clone());
+        second.update(Some("pts/2"), &snapshot("second"));
+
+        let deadline = Instant::now() + Duration::from_secs(10);
+        loop {
+            if let Ok(list) = query_in(&dir) {
+                if list.contains("\"first\"") && list.contains("\"second\"") {
+                    assert!(list.starts_with("[{\"pid\""));
+                    break;
+                }
+            }
+            assert!(
+                Instant::now() < deadline,
+                "registry never listed both instances"
+            );
+            thread::sleep(Duration::from_millis(50));
+        }
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,159 @@
+// Detection of sandboxes that aren't containers: processes running under
+// chroot, in a flatpak, or in a bubblewrap (bwrap) sandbox. These are
+// reported through ContainerInfo, with the name of the sandbox - for a
+// flatpak, the app id - as the container name.
+
+use crate::podman::ContainerInfo;
+use crate::process::Process;
+use std::path::Path;
+
+// How far up the process tree to look for a sandbox launcher
+const MAX_ANCESTRY_DEPTH: usize = 32;
+
+// Commands that set up a new mount namespace for their children
+const SANDBOX_LAUNCHERS: &[&str] = &["bwrap", "flatpak"];
+
+fn sandbox_info(name: &str) -> ContainerInfo {
+    ContainerInfo {
+        container_id: String::new(),
+        container_name: String::from(name),
+        image_id: String::new(),
+        image_name: String::new(),
+    }
+}
+
+// A chroot is labeled by the last component of its root, so /srv/chroot/debian
+// is shown as "debian"
+fn root_label(root: &Path) -> &str {
+    root.file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or("chroot")
+}
+
+// Flatpak apps are run in a systemd scope named like
+// app-flatpak-org.gnome.Builder-12345.scope
+fn app_id_from_cgroup(cgroup: &str) -> Option<&str> {
+    cgroup
+        .lines()
+        .filter_map(|line| line.splitn(3, ':').nth(2))
+        .flat_map(|path| path.split('/'))
+        .filter_map(|name| name.strip_prefix("app-flatpak-")?.strip_suffix(".scope"))
+        .filter_map(|scope| {
+            let pos = scope.rfind('-')?;
+            let (app_id, instance) = (&scope[..pos], &scope[pos + 1..]);
+            if !app_id.is_empty() && instance.bytes().all(|c| c.is_ascii_digit()) {
+                Some(app_id)
+            } else {
+                None
+            }
+        })
+        .next()
+}
+
+// The id of the flatpak app that the process belongs to; when the cgroup
+// doesn't tell, flatpak sets FLATPAK_ID in the environment of the app
+fn flatpak_app_id(process: &Process) -> Option<String> {
+    if let Ok(cgroup) = process.cgroup() {
+        if let Some(app_id) = app_id_from_cgroup(&cgroup) {
+            return Some(String::from(app_id));
+        }
+    }
+
+    process
+        .environ()
+        .ok()?
+        .remove("FLATPAK_ID")
+        .filter(|value| !value.is_empty())
+}
+
+fn find_launcher(pid: i32) -> Option<&'static str> {
+    let mut pid = pid;
+    for _ in 0..MAX_ANCESTRY_DEPTH {
+        let process = Process::new(pid);
+        if let Ok(command) = process.command() {
+            if let Some(launcher) = SANDBOX_LAUNCHERS.iter().find(|l| **l == command) {
+                return Some(launcher);
+            }
+        }
+
+        pid = match process.parent() {
+            Ok(ppid) if ppid > 1 => ppid,
+            _ => break,
+        };
+    }
+
+    None
+}
+
+/// Finds the sandbox that pid is running in, if any. When the information to
+/// tell isn't available - /proc/<pid>/root is only readable for our own
+/// processes, for example - returns None.
+pub fn find_sandbox(pid: i32) -> Option<ContainerInfo> {
+    let process = Process::new(pid);
+    let ourselves = Process::new(std::process::id() as i32);
+
+    let new_namespace = match (process.mount_namespace(), ourselves.mount_namespace()) {
+        (Ok(namespace), Ok(our_namespace)) => namespace != our_namespace,
+        _ => false,
+    };
+
+    if new_namespace {
+        if let Some(app_id) = flatpak_app_id(&process) {
+            return Some(sandbox_info(&app_id));
+        }
+    }
+
+    if let (Ok(root), Ok(our_root)) = (process.root(), ourselves.root()) {
+        if root != our_root {
+            return Some(sandbox_info(root_label(&root)));
+        }
+    }
+
+    // A new mount namespace, with a root that looks the same from outside
+    if new_namespace {
+        return find_launcher(pid).map(sandbox_info);
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_root_label() {
+        assert_eq!(root_label(Path::new("/srv/chroot/debian")), "debian");
+        assert_eq!(root_label(Path::new("/srv/chroot/debian/")), "debian");
+        assert_eq!(root_label(Path::new("/")), "chroot");
+    }
+
+    #[test]
+    fn test_app_id_from_cgroup() {
+        assert_eq!(
+            app_id_from_cgroup(
+                "0::/user.slice/user-1000.slice/user@1000.service/app.slice/\
+                 app-flatpak-org.gnome.Builder-12345.scope\n"
+            ),
+            Some("org.gnome.Builder")
+        );
+        assert_eq!(
+            app_id_from_cgroup(
+                "1:name=systemd:/user.slice/app-flatpak-com.example.my-app-7.scope\n\
+                 0::/user.slice/app-flatpak-com.example.my-app-7.scope\n"
+            ),
+            Some("com.example.my-app")
+        );
+        assert_eq!(
+            app_id_from_cgroup("0::/user.slice/app-gnome-org.gnome.Terminal-1234.scope\n"),
+            None
+        );
+        assert_eq!(app_id_from_cgroup("0::/app-flatpak-x.scope\n"), None);
+        assert_eq!(app_id_from_cgroup(""), None);
+    }
+
+    #[test]
+    fn test_not_sandboxed() {
+        assert!(find_sandbox(std::process::id() as i32).is_none());
+    }
+}
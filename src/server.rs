@@ -0,0 +1,154 @@
+// Serving our state to other programs
+//
+// Something like a tmux status line can connect to our socket to find out
+// what's running in the terminal without parsing the title. On each
+// connection, we write a line of JSON and close it:
+//
+//   {"container":null,"cwd":"/home/user","argv0":"vim","argv":["vim","x.rs"],"title":"x.rs - vim"}
+//
+// The state is kept up to date by the main loop, as the monitor reports
+// changes and titles are composed, and served from a separate thread, so a
+// client that doesn't read can't get in the way.
+
+use crate::state::ForegroundInfo;
+use serde::Serialize;
+use std::fs;
+use std::io::{self, Write};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::UnixListener;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+// Don't let a client that doesn't read tie up the thread
+const WRITE_TIMEOUT: Duration = Duration::from_millis(100);
+
+#[derive(Default, Serialize)]
+struct ServedState {
+    container: Option<String>,
+    cwd: String,
+    argv0: String,
+    argv: Vec<String>,
+    title: String,
+}
+
+pub struct StateServer {
+    path: PathBuf,
+    state: Arc<Mutex<ServedState>>,
+}
+
+/// $XDG_RUNTIME_DIR/ttymon-<pid>.sock
+pub fn default_path() -> PathBuf {
+    let dir = dirs::runtime_dir().unwrap_or_else(std::env::temp_dir);
+    dir.join(format!("ttymon-{}.sock", std::process::id()))
+}
+
+impl StateServer {
+    /// Starts serving on a socket at path; the socket is removed when the
+    /// StateServer is dropped
+    pub fn start(path: &Path) -> io::Result<StateServer> {
+        // Left behind by a previous instance that didn't exit cleanly
+        if let Ok(metadata) = fs::symlink_metadata(path) {
+            if metadata.file_type().is_socket() {
+                fs::remove_file(path)?;
+            }
+        }
+
+        let listener = UnixListener::bind(path)?;
+        let state = Arc::new(Mutex::new(ServedState::default()));
+
+        let thread_state = state.clone();
+        thread::spawn(move || serve(&listener, &thread_state));
+
+        Ok(StateServer {
+            path: path.to_path_buf(),
+            state,
+        })
+    }
+
+    /// Updates what's served from the monitor's latest result
+    pub fn update(&self, foreground: &ForegroundInfo) {
+        let mut state = self.state.lock().unwrap();
+        state.container = foreground
+            .container_info()
+            .map(|ci| ci.container_name.clone());
+        state.cwd = foreground.cwd().to_string_lossy().into_owned();
+        state.argv0 = foreground.argv0().to_string();
+        state.argv = foreground.argv().to_vec();
+    }
+
+    /// Updates the title served; when titles aren't rewritten, it stays empty
+    pub fn set_title(&self, title: &str) {
+        self.state.lock().unwrap().title = title.to_string();
+    }
+}
+
+impl Drop for StateServer {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+fn serve(listener: &UnixListener, state: &Mutex<ServedState>) {
+    for stream in listener.incoming() {
+        let mut stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                debug!("Failed to accept connection: {}", e);
+                continue;
+            }
+        };
+        let state = match serde_json::to_string(&*state.lock().unwrap()) {
+            Ok(state) => state,
+            Err(e) => {
+                warn!("Can't serialize state: {}", e);
+                continue;
+            }
+        };
+        let _ = stream.set_write_timeout(Some(WRITE_TIMEOUT));
+        if let Err(e) = writeln!(stream, "{}", state) {
+            debug!("Failed to send state: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+    use std::os::unix::net::UnixStream;
+
+    #[test]
+    fn test_state_server() {
+        let path = std::env::temp_dir().join(format!("ttymon-test-{}.sock", std::process::id()));
+        let query = || {
+            let mut response = String::new();
+            UnixStream::connect(&path)
+                .unwrap()
+                .read_to_string(&mut response)
+                .unwrap();
+            response
+        };
+
+        let server = StateServer::start(&path).unwrap();
+        assert_eq!(
+            query(),
+            "{\"container\":null,\"cwd\":\"\",\"argv0\":\"\",\"argv\":[],\"title\":\"\"}\n"
+        );
+
+        server.set_title("\"x\".rs - vim");
+        assert_eq!(
+            query(),
+            "{\"container\":null,\"cwd\":\"\",\"argv0\":\"\",\"argv\":[],\
+             \"title\":\"\\\"x\\\".rs - vim\"}\n"
+        );
+
+        // An update from the monitor leaves the title alone
+        server.update(&ForegroundInfo::default());
+        assert!(query().contains("\"title\":\"\\\"x\\\".rs - vim\""));
+
+        drop(server);
+        assert!(!path.exists());
+    }
+}
@@ -0,0 +1,96 @@
+// Delivery of signals to the main loop
+//
+// Not much can be done safely in a signal handler, so the handler just writes
+// the signal number to a pipe. The read end of the pipe is watched in the
+// epoll loop, which then handles the signal like any other event.
+
+use nix::errno::Errno;
+use nix::fcntl::OFlag;
+use nix::sys::signal::{sigaction, SaFlags, SigAction, SigHandler, SigSet, Signal};
+use nix::unistd::{close, pipe2, read, write};
+use std::convert::TryFrom;
+use std::os::unix::io::RawFd;
+use std::sync::atomic::{AtomicI32, Ordering};
+
+static SIGNAL_PIPE_WRITE_FD: AtomicI32 = AtomicI32::new(-1);
+
+extern "C" fn handle_signal(signal: nix::libc::c_int) {
+    let fd = SIGNAL_PIPE_WRITE_FD.load(Ordering::Relaxed);
+    if fd != -1 {
+        // If the pipe is full, the main loop has plenty to wake up for already
+        let _ = write(fd, &[signal as u8]);
+    }
+}
+
+pub struct SignalPipe {
+    read_fd: RawFd,
+    write_fd: RawFd,
+    signals: Vec<Signal>,
+}
+
+impl SignalPipe {
+    /// Starts catching signals; there can only be one SignalPipe at a time.
+    pub fn new(signals: &[Signal]) -> nix::Result<SignalPipe> {
+        let (read_fd, write_fd) = pipe2(OFlag::O_NONBLOCK | OFlag::O_CLOEXEC)?;
+        SIGNAL_PIPE_WRITE_FD.store(write_fd, Ordering::SeqCst);
+
+        let signal_pipe = SignalPipe {
+            read_fd,
+            write_fd,
+            signals: signals.to_vec(),
+        };
+
+        let action = SigAction::new(
+            SigHandler::Handler(handle_signal),
+            SaFlags::SA_RESTART,
+            SigSet::empty(),
+        );
+        for signal in signals {
+            unsafe { sigaction(*signal, &action) }?;
+        }
+
+        Ok(signal_pipe)
+    }
+
+    /// The fd to watch for reading
+    pub fn fd(&self) -> RawFd {
+        self.read_fd
+    }
+
+    /// Returns the signals received since the last call
+    pub fn read(&self) -> Vec<Signal> {
+        let mut result = vec![];
+        let mut buf = [0u8; 64];
+        loop {
+            match read(self.read_fd, &mut buf) {
+                Ok(0) => break,
+                Ok(count) => {
+                    for byte in &buf[..count] {
+                        if let Ok(signal) = Signal::try_from(*byte as nix::libc::c_int) {
+                            if !result.contains(&signal) {
+                                result.push(signal);
+                            }
+                        }
+                    }
+                }
+                Err(nix::Error::Sys(Errno::EINTR)) => {}
+                Err(_) => break,
+            }
+        }
+
+        result
+    }
+}
+
+impl Drop for SignalPipe {
+    fn drop(&mut self) {
+        let action = SigAction::new(SigHandler::SigDfl, SaFlags::empty(), SigSet::empty());
+        for signal in &self.signals {
+            let _ = unsafe { sigaction(*signal, &action) };
+        }
+
+        SIGNAL_PIPE_WRITE_FD.store(-1, Ordering::SeqCst);
+        let _ = close(self.read_fd);
+        let _ = close(self.write_fd);
+    }
+}
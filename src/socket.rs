@@ -4,20 +4,127 @@ use netlink_packet_sock_diag::{
     NetlinkHeader, NetlinkMessage, NetlinkPayload, SockDiagMessage,
 };
 use netlink_sys::{protocols::NETLINK_SOCK_DIAG, Socket, SocketAddr};
+use nix::sys::socket::{setsockopt, sockopt::ReceiveTimeout};
+use nix::sys::time::{TimeVal, TimeValLike};
+use std::collections::HashMap;
 use std::io;
+use std::os::unix::io::AsRawFd;
 
+// How long to wait for the kernel to respond; this is called from the monitor
+// thread, which would stall, and stop updating the state, if we waited
+// forever for a response that never comes.
+const RECV_TIMEOUT_MS: i64 = 500;
+
+// The netlink socket as seen by query_socket_peer(), so that the handling of
+// responses can be tested without the kernel
+trait NetlinkTransport {
+    fn send(&self, buf: &[u8]) -> io::Result<usize>;
+    fn recv(&self, buf: &mut [u8]) -> io::Result<usize>;
+}
+
+impl NetlinkTransport for Socket {
+    fn send(&self, buf: &[u8]) -> io::Result<usize> {
+        Socket::send(self, buf, 0)
+    }
+
+    fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        Socket::recv(self, buf, 0)
+    }
+}
+
+// Netlink messages in a buffer are each padded to a multiple of 4 bytes
+fn netlink_align(length: usize) -> usize {
+    (length + 3) & !3
+}
+
+/// The peer of a single Unix socket, or 0 if it has none
+#[allow(dead_code)]
 pub fn get_socket_peer(socket_ino: u32) -> io::Result<u32> {
+    match SockDiag::new()
+        .get_socket_peers(&[socket_ino])?
+        .get(&socket_ino)
+    {
+        Some(peer) => Ok(*peer),
+        None => Err(io::Error::new(io::ErrorKind::NotFound, "No such socket")),
+    }
+}
+
+fn open_socket() -> io::Result<Socket> {
     let socket = Socket::new(NETLINK_SOCK_DIAG)?;
     socket.connect(&SocketAddr::new(0, 0))?;
+    setsockopt(
+        socket.as_raw_fd(),
+        ReceiveTimeout,
+        &TimeVal::milliseconds(RECV_TIMEOUT_MS),
+    )
+    .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+
+    Ok(socket)
+}
+
+/// A netlink socket for socket diagnostics, opened on first use and kept
+/// open between queries, since we query on every check.
+pub struct SockDiag {
+    socket: Option<Socket>,
+}
+
+impl SockDiag {
+    pub fn new() -> SockDiag {
+        SockDiag { socket: None }
+    }
+
+    /// The peers of Unix sockets, by inode, with 0 for sockets without a peer.
+    /// Sockets that don't exist are missing from the result.
+    pub fn get_socket_peers(&mut self, socket_inos: &[u32]) -> io::Result<HashMap<u32, u32>> {
+        if socket_inos.is_empty() {
+            return Ok(HashMap::new());
+        }
+
+        let reused = self.socket.is_some();
+        let result = self.query_socket_peers(socket_inos);
+        match result {
+            // The socket we kept might be what's broken, so try a new one
+            Err(e) if reused => {
+                debug!("Netlink query failed, reopening socket: {}", e);
+                self.query_socket_peers(socket_inos)
+            }
+            result => result,
+        }
+    }
+
+    fn query_socket_peers(&mut self, socket_inos: &[u32]) -> io::Result<HashMap<u32, u32>> {
+        let socket = match self.socket.take() {
+            Some(socket) => socket,
+            None => open_socket()?,
+        };
+        let result = query_socket_peers(&socket, socket_inos);
+        // After a failure, there may be a partial response left unread that
+        // would confuse the next query
+        if result.is_ok() {
+            self.socket = Some(socket);
+        }
+
+        result
+    }
+}
+
+// Dumps all Unix sockets in one request, picking out the ones we want from
+// the responses - much cheaper than a request for each. The dump is always
+// read to the end, so the socket is ready for the next request.
+fn query_socket_peers<T: NetlinkTransport>(
+    transport: &T,
+    socket_inos: &[u32],
+) -> io::Result<HashMap<u32, u32>> {
+    let mut peers = HashMap::new();
 
     let mut packet = NetlinkMessage {
         header: NetlinkHeader {
-            flags: NLM_F_REQUEST,
+            flags: NLM_F_REQUEST | NLM_F_DUMP,
             ..Default::default()
         },
         payload: SockDiagMessage::UnixRequest(UnixRequest {
             state_flags: StateFlags::all(),
-            inode: socket_ino,
+            inode: 0,
             show_flags: ShowFlags::PEER,
             cookie: [0xff; 8],
         })
@@ -33,30 +140,54 @@ pub fn get_socket_peer(socket_ino: u32) -> io::Result<u32> {
     assert_eq!(buf.len(), packet.buffer_len());
 
     packet.serialize(&mut buf[..]);
-    socket.send(&buf[..], 0)?;
+    transport.send(&buf[..])?;
 
-    let mut receive_buffer = vec![0; 4096];
-    let mut offset = 0;
-    while let Ok(size) = socket.recv(&mut receive_buffer[..], 0) {
-        loop {
-            let bytes = &receive_buffer[offset..];
-            let rx_packet = <NetlinkMessage<SockDiagMessage>>::deserialize(bytes).unwrap();
+    // Big enough that the kernel doesn't truncate dump messages
+    let mut receive_buffer = vec![0; 32768];
+    loop {
+        let size = match transport.recv(&mut receive_buffer[..]) {
+            Ok(size) => size,
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "Timed out waiting for netlink response",
+                ))
+            }
+            Err(e) => return Err(e),
+        };
+        if size == 0 {
+            break;
+        }
+
+        let mut offset = 0;
+        while offset < size {
+            let bytes = &receive_buffer[offset..size];
+            let rx_packet = match <NetlinkMessage<SockDiagMessage>>::deserialize(bytes) {
+                Ok(rx_packet) => rx_packet,
+                Err(e) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::Other,
+                        format!("Can't parse netlink response: {}", e),
+                    ))
+                }
+            };
 
             match rx_packet.payload {
                 NetlinkPayload::Noop | NetlinkPayload::Ack(_) => {}
                 NetlinkPayload::InnerMessage(SockDiagMessage::UnixResponse(response)) => {
-                    let mut port: u32 = 0;
-                    for nla in response.nlas {
-                        match nla {
-                            Nla::Peer(x) => {
+                    let inode = response.header.inode;
+                    if socket_inos.contains(&inode) {
+                        let mut port: u32 = 0;
+                        for nla in response.nlas {
+                            if let Nla::Peer(x) = nla {
                                 port = x;
                             }
-                            _ => (),
                         }
+                        peers.insert(inode, port);
                     }
-                    return Ok(port);
                 }
-                NetlinkPayload::InnerMessage(_) | NetlinkPayload::Done => {
+                NetlinkPayload::Done => return Ok(peers),
+                NetlinkPayload::InnerMessage(_) => {
                     return Err(io::Error::new(
                         io::ErrorKind::Other,
                         "Unexpected response from netlink",
@@ -73,16 +204,194 @@ pub fn get_socket_peer(socket_ino: u32) -> io::Result<u32> {
                 }
             }
 
-            offset += rx_packet.header.length as usize;
-            if offset == size || rx_packet.header.length == 0 {
-                offset = 0;
-                break;
-            }
+            // deserialize() checks that the length is at least a header
+            offset += netlink_align(rx_packet.header.length as usize);
         }
     }
 
-    return Err(io::Error::new(
+    Err(io::Error::new(
         io::ErrorKind::Other,
-        "Didn't get a response from netlink",
-    ));
+        "Netlink response ended early",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::collections::VecDeque;
+
+    const NLMSG_NOOP: u16 = 1;
+    const NLMSG_ERROR: u16 = 2;
+    const NLMSG_DONE: u16 = 3;
+    const SOCK_DIAG_BY_FAMILY: u16 = 20;
+    const UNIX_DIAG_PEER: u16 = 2;
+    const UNIX_DIAG_RQLEN: u16 = 4;
+
+    // Replays canned responses, one per recv()
+    struct MockTransport {
+        responses: RefCell<VecDeque<Vec<u8>>>,
+    }
+
+    impl MockTransport {
+        fn new(responses: Vec<Vec<u8>>) -> Self {
+            MockTransport {
+                responses: RefCell::new(responses.into()),
+            }
+        }
+    }
+
+    impl NetlinkTransport for MockTransport {
+        fn send(&self, buf: &[u8]) -> io::Result<usize> {
+            Ok(buf.len())
+        }
+
+        fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+            match self.responses.borrow_mut().pop_front() {
+                Some(response) => {
+                    buf[..response.len()].copy_from_slice(&response);
+                    Ok(response.len())
+                }
+                None => Err(io::Error::new(io::ErrorKind::WouldBlock, "No more data")),
+            }
+        }
+    }
+
+    fn message(message_type: u16, payload: &[u8]) -> Vec<u8> {
+        let mut result = vec![];
+        result.extend_from_slice(&(16 + payload.len() as u32).to_ne_bytes());
+        result.extend_from_slice(&message_type.to_ne_bytes());
+        result.extend_from_slice(&0u16.to_ne_bytes());
+        result.extend_from_slice(&1u32.to_ne_bytes());
+        result.extend_from_slice(&0u32.to_ne_bytes());
+        result.extend_from_slice(payload);
+        result.resize(netlink_align(result.len()), 0);
+
+        result
+    }
+
+    fn nla(nla_type: u16, value: &[u8]) -> Vec<u8> {
+        let mut result = vec![];
+        result.extend_from_slice(&(4 + value.len() as u16).to_ne_bytes());
+        result.extend_from_slice(&nla_type.to_ne_bytes());
+        result.extend_from_slice(value);
+        result.resize(netlink_align(result.len()), 0);
+
+        result
+    }
+
+    // struct unix_diag_msg, followed by attributes
+    fn unix_response(inode: u32, nlas: &[Vec<u8>]) -> Vec<u8> {
+        let mut payload = vec![AF_UNIX, 1 /* SOCK_STREAM */, 1, 0];
+        payload.extend_from_slice(&inode.to_ne_bytes());
+        payload.extend_from_slice(&[0xff; 8]);
+        for nla in nlas {
+            payload.extend_from_slice(nla);
+        }
+
+        message(SOCK_DIAG_BY_FAMILY, &payload)
+    }
+
+    fn error(code: i32) -> Vec<u8> {
+        // The error code, followed by the header of the failed request
+        let mut payload = code.to_ne_bytes().to_vec();
+        payload.extend_from_slice(&[0; 16]);
+
+        message(NLMSG_ERROR, &payload)
+    }
+
+    fn done() -> Vec<u8> {
+        message(NLMSG_DONE, &[0; 4])
+    }
+
+    fn peer(inode: u32, peer: u32) -> Vec<u8> {
+        unix_response(inode, &[nla(UNIX_DIAG_PEER, &peer.to_ne_bytes())])
+    }
+
+    #[test]
+    fn test_peers() {
+        let transport = MockTransport::new(vec![
+            peer(1, 2),
+            unix_response(
+                1234,
+                &[
+                    nla(UNIX_DIAG_RQLEN, &[0; 8]),
+                    nla(UNIX_DIAG_PEER, &5678u32.to_ne_bytes()),
+                ],
+            ),
+            // No peer attribute
+            unix_response(4321, &[]),
+            done(),
+        ]);
+        let peers = query_socket_peers(&transport, &[1234, 4321, 9999]).unwrap();
+        assert_eq!(peers.len(), 2);
+        assert_eq!(peers[&1234], 5678);
+        assert_eq!(peers[&4321], 0);
+
+        // The dump is read to the end, even once we have everything
+        let transport = MockTransport::new(vec![peer(1234, 5678), peer(1, 2), done()]);
+        let peers = query_socket_peers(&transport, &[1234]).unwrap();
+        assert_eq!(peers[&1234], 5678);
+        assert!(transport.responses.borrow().is_empty());
+    }
+
+    #[test]
+    fn test_multiple_messages() {
+        // Skipped messages in the same buffer as the responses
+        let mut buffer = message(NLMSG_NOOP, &[]);
+        buffer.extend(error(0)); // An ACK
+        buffer.extend(peer(1, 2));
+        buffer.extend(peer(1234, 42));
+        buffer.extend(done());
+        let transport = MockTransport::new(vec![buffer]);
+        let peers = query_socket_peers(&transport, &[1234, 4321]).unwrap();
+        assert_eq!(peers.len(), 1);
+        assert_eq!(peers[&1234], 42);
+
+        // And in separate recv() calls
+        let transport = MockTransport::new(vec![
+            message(NLMSG_NOOP, &[]),
+            peer(1234, 42),
+            peer(4321, 24),
+            done(),
+        ]);
+        let peers = query_socket_peers(&transport, &[1234, 4321]).unwrap();
+        assert_eq!(peers[&1234], 42);
+        assert_eq!(peers[&4321], 24);
+    }
+
+    #[test]
+    fn test_errors() {
+        let transport = MockTransport::new(vec![error(-2)]);
+        let err = query_socket_peers(&transport, &[1234]).unwrap_err();
+        assert_eq!(err.to_string(), "Netlink error: -2");
+
+        // The receive timing out rather than returning data
+        let transport = MockTransport::new(vec![peer(1, 2)]);
+        let err = query_socket_peers(&transport, &[1234]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+
+        // End of stream before the end of the dump
+        let transport = MockTransport::new(vec![vec![]]);
+        assert!(query_socket_peers(&transport, &[1234]).is_err());
+    }
+
+    #[test]
+    fn test_short_reads() {
+        // Shorter than a header
+        let transport = MockTransport::new(vec![vec![0; 8]]);
+        assert!(query_socket_peers(&transport, &[1234]).is_err());
+
+        // Shorter than the length in the header
+        let mut response = peer(1234, 42);
+        response.truncate(response.len() - 8);
+        let transport = MockTransport::new(vec![response]);
+        assert!(query_socket_peers(&transport, &[1234]).is_err());
+
+        // A length in the header that's nonsense
+        let mut response = message(NLMSG_NOOP, &[]);
+        response[..4].copy_from_slice(&u32::MAX.to_ne_bytes());
+        let transport = MockTransport::new(vec![response]);
+        assert!(query_socket_peers(&transport, &[1234]).is_err());
+    }
 }
@@ -0,0 +1,94 @@
+// Finding where an ssh command line connects to
+//
+// The destination is the first argument that isn't an option or an option's
+// value, and is either [user@]host or ssh://[user@]host[:port].
+
+// Options of ssh(1) that take a value, either attached (-p22) or as the next
+// argument (-p 22)
+const OPTIONS_WITH_VALUE: &str = "BbcDEeFIiJLlmOoPpQRSWw";
+
+/// The host that ssh, run with args (not including argv[0]), connects to
+pub fn destination_host<'a>(args: &[&'a str]) -> Option<&'a str> {
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if *arg == "--" {
+            return args.next().and_then(|arg| parse_destination(arg));
+        }
+
+        match arg.strip_prefix('-').filter(|flags| !flags.is_empty()) {
+            Some(flags) => {
+                for (i, flag) in flags.char_indices() {
+                    if OPTIONS_WITH_VALUE.contains(flag) {
+                        // The rest of this argument, or if that's empty, the
+                        // next argument is the value
+                        if i + flag.len_utf8() == flags.len() {
+                            args.next();
+                        }
+                        break;
+                    }
+                }
+            }
+            None => return parse_destination(arg),
+        }
+    }
+
+    None
+}
+
+fn parse_destination(destination: &str) -> Option<&str> {
+    let host = match destination.strip_prefix("ssh://") {
+        Some(rest) => {
+            let authority = rest.split('/').next().unwrap_or(rest);
+            let host = strip_user(authority);
+            match host.strip_prefix('[') {
+                // An IPv6 address, [addr]:port
+                Some(bracketed) => bracketed.split(']').next().unwrap_or(bracketed),
+                None => host.split(':').next().unwrap_or(host),
+            }
+        }
+        None => strip_user(destination),
+    };
+
+    if host.is_empty() {
+        None
+    } else {
+        Some(host)
+    }
+}
+
+fn strip_user(destination: &str) -> &str {
+    match destination.rfind('@') {
+        Some(at) => &destination[at + 1..],
+        None => destination,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn host(command_line: &str) -> Option<&str> {
+        let args: Vec<&str> = command_line.split_whitespace().collect();
+        destination_host(&args)
+    }
+
+    #[test]
+    fn test_destination_host() {
+        assert_eq!(host("example.com"), Some("example.com"));
+        assert_eq!(host("user@example.com"), Some("example.com"));
+        assert_eq!(host("-l user example.com ls -l"), Some("example.com"));
+        assert_eq!(host("-p 2222 example.com"), Some("example.com"));
+        assert_eq!(host("-p2222 -v example.com"), Some("example.com"));
+        assert_eq!(host("-4vA -i ~/.ssh/id example.com"), Some("example.com"));
+        assert_eq!(host("-vi ~/.ssh/id example.com"), Some("example.com"));
+        assert_eq!(host("-o Port=22 -- user@example.com"), Some("example.com"));
+        assert_eq!(host("ssh://user@example.com:2222"), Some("example.com"));
+        assert_eq!(host("ssh://[::1]:2222"), Some("::1"));
+        assert_eq!(host("ssh://example.com/"), Some("example.com"));
+
+        assert_eq!(host(""), None);
+        assert_eq!(host("-p 22"), None);
+        assert_eq!(host("-l user"), None);
+        assert_eq!(host("user@"), None);
+    }
+}
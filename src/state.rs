@@ -15,34 +15,143 @@
 //   * A GroupNode can change from having no known SessionNode to having a known
 //     SessionNode, and (less likely) vice-versa.
 
-use crate::podman::{find_podman_peer, ContainerInfo};
-use crate::process::Process;
+use crate::multiplexer::Multiplexer;
+use crate::podman::{
+    container_id_from_cgroup, expire_container_info, find_podman_peer, get_container_info_for_id,
+    ContainerInfo, DEFAULT_MAX_SCAN_FDS,
+};
+use crate::process::{command_name, Process};
+use crate::sandbox::find_sandbox;
+use crate::socket::SockDiag;
+use crate::ssh;
+use nix::unistd::{Uid, User};
 use std::fmt;
 use std::path::{Path, PathBuf};
 
+// Whether process is still the one that had start_time when we first saw it,
+// rather than an unrelated process that reused the pid. If either is unknown,
+// we can't tell, and assume it is.
+fn is_same_process(process: &Process, start_time: Option<u64>) -> bool {
+    match (start_time, process.start_time()) {
+        (Some(start_time), Ok(current)) => start_time == current,
+        _ => true,
+    }
+}
+
+// Commands that forward their terminal to a session in a podman container
+const PODMAN_FORWARDERS: &[&str] = &["toolbox", "toolbox-exec", "distrobox", "distrobox-enter"];
+
+// extra_forwarders are more commands to treat the same way, from the
+// configuration
+fn is_podman_forwarder(process: &Process, extra_forwarders: &[String]) -> bool {
+    let is_forwarder = |command: &str| {
+        PODMAN_FORWARDERS.contains(&command) || extra_forwarders.iter().any(|f| f == command)
+    };
+
+    if let Ok(command) = process.command() {
+        if is_forwarder(&command) {
+            return true;
+        }
+    }
+
+    // distrobox (and older versions of toolbox) are shell scripts; argv[0]
+    // is then the shell, but the kernel names the process after the script.
+    match process.stat() {
+        Ok(stat) => is_forwarder(&stat.comm),
+        Err(_) => false,
+    }
+}
+
+// The host that an ssh process is connected to
+fn ssh_remote_host(process: &Process) -> Option<String> {
+    let argv = process.argv().ok()?;
+    let args: Vec<&str> = argv.iter().skip(1).map(String::as_str).collect();
+
+    ssh::destination_host(&args).map(String::from)
+}
+
+// What finding forwarded sessions needs, kept between updates
+struct ForwardingContext {
+    sock_diag: SockDiag,
+    extra_podman_forwarders: Vec<String>,
+    container_detection: bool,
+    max_scan_fds: usize,
+}
+
+// The kinds of TTY-forwarding process we know how to follow to the session
+// they forward to. Every level of the tree is checked for all of them, so
+// they can be nested in any combination.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Forwarder {
+    Podman,
+    // The session is on another machine, so can't be followed; we just know
+    // which machine.
+    Ssh,
+    Multiplexer(Multiplexer),
+}
+
+impl Forwarder {
+    fn detect(process: &Process, ctx: &ForwardingContext) -> Option<Forwarder> {
+        if ctx.container_detection && is_podman_forwarder(process, &ctx.extra_podman_forwarders) {
+            return Some(Forwarder::Podman);
+        }
+        if process.command().ok().as_deref() == Some("ssh") {
+            return Some(Forwarder::Ssh);
+        }
+        if let Some(multiplexer) = Multiplexer::detect(process) {
+            return Some(Forwarder::Multiplexer(multiplexer));
+        }
+
+        None
+    }
+
+    // The session leader that the forwarder in pgrp forwards to, and the
+    // container that session is in
+    fn find_session(
+        self,
+        pgrp: i32,
+        ctx: &mut ForwardingContext,
+    ) -> Option<(i32, Option<ContainerInfo>)> {
+        match self {
+            Forwarder::Podman => find_podman_peer(pgrp, &mut ctx.sock_diag, ctx.max_scan_fds).ok(),
+            Forwarder::Ssh => None,
+            Forwarder::Multiplexer(multiplexer) => {
+                multiplexer.find_session(pgrp).map(|pid| (pid, None))
+            }
+        }
+    }
+}
+
 struct SessionNode {
     pid: i32,
+    start_time: Option<u64>,
     container_info: Option<ContainerInfo>,
     child: Option<Box<GroupNode>>,
 }
 
+// The nodes are given the Process for their pid, so that within an update,
+// its stat file is only read once
 impl SessionNode {
-    fn new(pid: i32, container_info: Option<ContainerInfo>) -> Self {
+    fn new(process: &Process, container_info: Option<ContainerInfo>) -> Self {
         Self {
-            pid,
+            pid: process.pid(),
+            start_time: process.start_time().ok(),
             container_info,
             child: None,
         }
     }
 
-    fn update(&mut self) {
-        if let Ok(tty_pgrp) = Process::new(self.pid).tty_process_group() {
+    fn update(&mut self, process: &Process) {
+        if let Ok(tty_pgrp) = process.tty_process_group() {
+            let leader = Process::new(tty_pgrp);
             let changed = match &self.child {
-                Some(group) => tty_pgrp != group.pgrp,
+                Some(group) => {
+                    tty_pgrp != group.pgrp || !is_same_process(&leader, group.start_time)
+                }
                 None => true,
             };
             if changed {
-                self.child = Some(Box::new(GroupNode::new(tty_pgrp)));
+                self.child = Some(Box::new(GroupNode::new(&leader)));
             }
         } else {
             self.child = None
@@ -60,36 +169,57 @@ impl SessionNode {
 
 struct GroupNode {
     pgrp: i32,
+    // Of the process group leader
+    start_time: Option<u64>,
+    // If the group is an ssh session, the host it's connected to
+    remote_host: Option<String>,
     child: Option<Box<SessionNode>>,
 }
 
 impl GroupNode {
-    fn new(pgrp: i32) -> Self {
-        Self { pgrp, child: None }
+    fn new(leader: &Process) -> Self {
+        Self {
+            pgrp: leader.pid(),
+            start_time: leader.start_time().ok(),
+            remote_host: None,
+            child: None,
+        }
     }
 
-    fn update(&mut self) {
+    // Returns the Process for the child session, if there is one
+    fn update(&mut self, ctx: &mut ForwardingContext) -> Option<Process> {
         let mut child_pid = -1;
         let mut container_info: Option<ContainerInfo> = None;
-        if let Ok(argv0) = Process::new(self.pgrp).argv0() {
-            if argv0 == "/home/otaylor/bin/toolbox" {
-                if let Ok(peer) = find_podman_peer(self.pgrp) {
-                    child_pid = peer.0;
-                    container_info = peer.1;
-                }
+        // Like the foreground state, so that in `echo | ssh host`, ssh is
+        // still found after echo, the group leader, has exited
+        let process = Process::group_representative(self.pgrp);
+        let forwarder = Forwarder::detect(&process, ctx);
+        self.remote_host = match forwarder {
+            Some(Forwarder::Ssh) => ssh_remote_host(&process),
+            _ => None,
+        };
+        if let Some(forwarder) = forwarder {
+            if let Some(peer) = forwarder.find_session(self.pgrp, ctx) {
+                child_pid = peer.0;
+                container_info = peer.1;
             }
         }
 
         if child_pid != -1 {
+            let child = Process::new(child_pid);
             let changed = match &self.child {
-                Some(session) => child_pid != session.pid,
+                Some(session) => {
+                    child_pid != session.pid || !is_same_process(&child, session.start_time)
+                }
                 None => true,
             };
             if changed {
-                self.child = Some(Box::new(SessionNode::new(child_pid, container_info)));
+                self.child = Some(Box::new(SessionNode::new(&child, container_info)));
             }
+            Some(child)
         } else {
-            self.child = None
+            self.child = None;
+            None
         }
     }
 
@@ -102,31 +232,97 @@ impl GroupNode {
     }
 }
 
+/// A copy of the externally interesting parts of a TerminalState
+#[derive(Clone, Debug, PartialEq)]
+pub struct StateSnapshot {
+    pub container_name: Option<String>,
+    pub cwd: PathBuf,
+    pub argv0: String,
+    pub command: String,
+}
+
+/// What we know about the foreground process. This is separate from
+/// TerminalState so it can be passed from the thread that updates the state
+/// (see monitor.rs).
+#[derive(Clone, Default)]
+pub struct ForegroundInfo {
+    container_info: Option<ContainerInfo>,
+    // The host the foreground process is connected to, if it's ssh
+    remote_host: Option<String>,
+    argv: Vec<String>,
+    argv0: String,
+    command: String,
+    cwd: PathBuf,
+    login_user: Option<String>,
+    euid: Option<u32>,
+    // The foreground is the session we started, not something it runs
+    at_shell: bool,
+}
+
 pub struct TerminalState {
     root: SessionNode,
-    container_info: Option<ContainerInfo>,
-    foreground_argv0: String,
-    foreground_cwd: PathBuf,
+    // The container id from the foreground process's cgroup, and what podman
+    // told us about it, so we only ask again when the id changes
+    cgroup_container: Option<(String, Option<ContainerInfo>)>,
+    forwarding: ForwardingContext,
+    foreground: ForegroundInfo,
+    // The login uid that foreground.login_user was looked up for
+    foreground_loginuid: Option<u32>,
+    // The terminal the root session should be on, as tty_nr
+    tty_nr: Option<i32>,
 }
 
 impl TerminalState {
     pub fn new(root_pid: i32) -> Self {
         return TerminalState {
-            root: SessionNode::new(root_pid, None),
-            container_info: None,
-            foreground_argv0: String::from(""),
-            foreground_cwd: PathBuf::new(),
+            root: SessionNode::new(&Process::new(root_pid), None),
+            cgroup_container: None,
+            forwarding: ForwardingContext {
+                sock_diag: SockDiag::new(),
+                extra_podman_forwarders: vec![],
+                container_detection: true,
+                max_scan_fds: DEFAULT_MAX_SCAN_FDS,
+            },
+            foreground: ForegroundInfo::default(),
+            foreground_loginuid: None,
+            tty_nr: None,
         };
     }
 
+    /// The terminal the root process should have as its controlling terminal
+    /// (see tty_nr_from_rdev()); if it doesn't, we're looking at the wrong
+    /// processes, and don't report anything
+    pub fn set_tty_nr(&mut self, tty_nr: i32) {
+        self.tty_nr = Some(tty_nr);
+    }
+
+    // If we can't tell, we go ahead; a process that can't be read won't
+    // tell us anything anyway
+    fn root_on_tty(&self, root: &Process) -> bool {
+        match (self.tty_nr, root.tty_nr()) {
+            (Some(expected), Ok(tty_nr)) if tty_nr != expected => {
+                debug!("Session {} isn't on our terminal", self.root.pid);
+                false
+            }
+            _ => true,
+        }
+    }
+
     pub fn update(&mut self) {
-        self.root.update();
+        // Containers that the last update didn't find may be gone
+        expire_container_info();
+
+        let root = Process::new(self.root.pid);
+        if self.root_on_tty(&root) {
+            self.root.update(&root);
+        } else {
+            self.root.child = None;
+        }
         let mut group = match self.root.child_mut() {
             Some(group) => group,
             None => {
-                self.container_info = None;
-                self.foreground_argv0 = String::new();
-                self.foreground_cwd = PathBuf::new();
+                self.foreground = ForegroundInfo::default();
+                self.foreground_loginuid = None;
 
                 return;
             }
@@ -137,36 +333,174 @@ impl TerminalState {
 
         loop {
             group_pgrp = group.pgrp;
-            group.update();
-            let session = match group.child_mut() {
-                Some(session) => session,
-                None => break,
+            let session_process = group.update(&mut self.forwarding);
+            self.foreground.remote_host = group.remote_host.clone();
+            let (session, session_process) = match (group.child_mut(), session_process) {
+                (Some(session), Some(session_process)) => (session, session_process),
+                _ => break,
             };
 
-            session.update();
-            container_info = session.container_info.clone();
+            session.update(&session_process);
+            // A forwarder that doesn't enter a container (or one we couldn't
+            // identify) leaves us in the container we were in
+            if session.container_info.is_some() {
+                container_info = session.container_info.clone();
+            }
             group = match session.child_mut() {
                 Some(group) => group,
                 None => break,
             };
         }
 
-        let proc = Process::new(group_pgrp);
-        self.foreground_argv0 = proc.argv0().unwrap_or(String::new());
-        self.foreground_cwd = proc.cwd().unwrap_or(PathBuf::new());
-        self.container_info = container_info;
+        // The group leader may have exited while the rest of the group is
+        // still in the foreground
+        let proc = Process::group_representative(group_pgrp);
+        self.foreground.at_shell = group_pgrp == self.root.pid;
+
+        // The cgroup is the most direct way to tell that we're in a container;
+        // the socket peers found along the way are a fallback for when it
+        // isn't informative
+        if self.forwarding.container_detection {
+            if let Some(cgroup_container_info) = self.update_cgroup_container(&proc) {
+                container_info = Some(cgroup_container_info);
+            } else if container_info.is_none() {
+                container_info = find_sandbox(group_pgrp);
+            }
+        }
+        let foreground = &mut self.foreground;
+        foreground.argv = proc.argv().unwrap_or_default();
+        foreground.argv0 = proc.argv0().unwrap_or(String::new());
+        foreground.command = command_name(&foreground.argv0).to_string();
+        foreground.cwd = proc.cwd().unwrap_or(PathBuf::new());
+
+        // None if the process went away before we could read it
+        foreground.euid = proc.euid().ok();
+
+        // Unreadable is treated the same as unset
+        let loginuid = proc.loginuid().unwrap_or(None);
+        if loginuid != self.foreground_loginuid {
+            self.foreground_loginuid = loginuid;
+            foreground.login_user = loginuid.and_then(user_name);
+        }
+
+        foreground.container_info = container_info;
+    }
+
+    /// Commands to recognize as forwarding to a podman container, like toolbox
+    pub fn set_extra_podman_forwarders(&mut self, forwarders: Vec<String>) {
+        self.forwarding.extra_podman_forwarders = forwarders;
+    }
+
+    /// Whether to look for containers at all; if not, we don't need to scan
+    /// sockets or ask podman about anything
+    pub fn set_container_detection(&mut self, container_detection: bool) {
+        self.forwarding.container_detection = container_detection;
     }
 
+    /// Processes with more open files than this are skipped when looking for
+    /// the sockets that podman forwards over; 0 means no limit
+    pub fn set_max_scan_fds(&mut self, max_scan_fds: usize) {
+        self.forwarding.max_scan_fds = max_scan_fds;
+    }
+
+    fn update_cgroup_container(&mut self, proc: &Process) -> Option<ContainerInfo> {
+        let cgroup = proc.cgroup().ok();
+        let (runtime, id) = match cgroup.as_deref().and_then(container_id_from_cgroup) {
+            Some(found) => found,
+            None => {
+                self.cgroup_container = None;
+                return None;
+            }
+        };
+
+        let changed = match &self.cgroup_container {
+            Some((cached_id, _)) => cached_id != id,
+            None => true,
+        };
+        if changed {
+            let container_info = match get_container_info_for_id(runtime, id) {
+                Ok(container_info) => container_info,
+                Err(e) => {
+                    info!("Can't get information for container {}: {}", id, e);
+                    None
+                }
+            };
+            self.cgroup_container = Some((id.to_string(), container_info));
+        }
+
+        self.cgroup_container
+            .as_ref()
+            .and_then(|(_, container_info)| container_info.clone())
+    }
+
+    /// What the last update found out about the foreground process
+    pub fn foreground(&self) -> &ForegroundInfo {
+        &self.foreground
+    }
+}
+
+impl ForegroundInfo {
     pub fn container_info(&self) -> Option<&ContainerInfo> {
         self.container_info.as_ref()
     }
 
-    pub fn foreground_argv0(&self) -> &str {
-        self.foreground_argv0.as_str()
+    /// The remote host, when the foreground process is ssh
+    pub fn remote_host(&self) -> Option<&str> {
+        self.remote_host.as_deref()
+    }
+
+    /// The foreground process's full command line
+    pub fn argv(&self) -> &[String] {
+        &self.argv
     }
 
-    pub fn foreground_cwd(&self) -> &Path {
-        self.foreground_cwd.as_path()
+    pub fn argv0(&self) -> &str {
+        self.argv0.as_str()
+    }
+
+    /// The foreground command name, normalized from argv[0]
+    pub fn command(&self) -> &str {
+        self.command.as_str()
+    }
+
+    /// Whether the foreground process is known to be running as root
+    pub fn is_root(&self) -> bool {
+        self.euid == Some(0)
+    }
+
+    /// The name of the user who originally logged in to the foreground
+    /// process's session, regardless of sudo or su
+    pub fn login_user(&self) -> Option<&str> {
+        self.login_user.as_deref()
+    }
+
+    pub fn cwd(&self) -> &Path {
+        self.cwd.as_path()
+    }
+
+    /// Whether the process we started, usually the shell, is itself in the
+    /// foreground, as at a prompt
+    pub fn at_shell(&self) -> bool {
+        self.at_shell
+    }
+
+    pub fn snapshot(&self) -> StateSnapshot {
+        StateSnapshot {
+            container_name: self
+                .container_info
+                .as_ref()
+                .map(|ci| ci.container_name.clone()),
+            cwd: self.cwd.clone(),
+            argv0: self.argv0.clone(),
+            command: self.command.clone(),
+        }
+    }
+}
+
+fn user_name(uid: u32) -> Option<String> {
+    match User::from_uid(Uid::from_raw(uid)) {
+        Ok(Some(user)) => Some(user.name),
+        _ => None,
     }
 }
 
@@ -176,10 +510,17 @@ impl fmt::Display for TerminalState {
         let mut session = &self.root;
         loop {
             write!(f, " S-{}", session.pid)?;
+            if let Some(container_info) = &session.container_info {
+                write!(f, "({})", container_info.container_name)?;
+            }
             let group = match session.child() {
                 Some(group) => group,
                 None => break,
             };
+            write!(f, " G-{}", group.pgrp)?;
+            if let Some(remote_host) = &group.remote_host {
+                write!(f, "(ssh {})", remote_host)?;
+            }
             session = match group.child() {
                 Some(session) => session,
                 None => break,
@@ -188,3 +529,147 @@ impl fmt::Display for TerminalState {
         write!(f, " ]")
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+    use std::os::unix::process::CommandExt;
+    use std::process::{Child, Command, Stdio};
+    use std::time::{Duration, Instant};
+
+    // Runs command, waiting until it has replaced the forked copy of ourselves
+    fn spawn(command: &mut Command) -> (Child, Process) {
+        let child = command.stdin(Stdio::piped()).spawn().unwrap();
+        let pid = child.id() as i32;
+        let process = Process::new(pid);
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while process.argv0().map_or(true, |argv0| argv0.is_empty()) {
+            assert!(Instant::now() < deadline);
+            std::thread::sleep(Duration::from_millis(10));
+        }
+
+        (child, Process::new(pid))
+    }
+
+    #[test]
+    fn test_is_podman_forwarder() {
+        assert!(!is_podman_forwarder(
+            &Process::new(std::process::id() as i32),
+            &[]
+        ));
+
+        let (mut child, process) = spawn(Command::new("sleep").arg0("/usr/bin/toolbox").arg("10"));
+        assert!(is_podman_forwarder(&process, &[]));
+        child.kill().unwrap();
+        child.wait().unwrap();
+
+        // Configured as a forwarder
+        let (mut child, process) = spawn(Command::new("sleep").arg0("mybox").arg("10"));
+        assert!(!is_podman_forwarder(&process, &[]));
+        assert!(is_podman_forwarder(&process, &[String::from("mybox")]));
+        child.kill().unwrap();
+        child.wait().unwrap();
+
+        // A script is named after the script, not the interpreter
+        let dir =
+            std::env::temp_dir().join(format!("ttymon-test-{}-forwarder", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let script = dir.join("distrobox-enter");
+        fs::write(&script, "#!/bin/sh\nread line\n").unwrap();
+        fs::set_permissions(&script, fs::Permissions::from_mode(0o755)).unwrap();
+
+        let (mut child, process) = spawn(&mut Command::new(&script));
+        assert!(is_podman_forwarder(&process, &[]));
+        child.kill().unwrap();
+        child.wait().unwrap();
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    fn container(name: &str) -> Option<ContainerInfo> {
+        Some(ContainerInfo {
+            container_id: String::new(),
+            container_name: name.to_string(),
+            image_id: String::new(),
+            image_name: String::new(),
+        })
+    }
+
+    fn group(pgrp: i32, child: Option<SessionNode>) -> Option<Box<GroupNode>> {
+        Some(Box::new(GroupNode {
+            pgrp,
+            start_time: None,
+            remote_host: None,
+            child: child.map(Box::new),
+        }))
+    }
+
+    fn session(
+        pid: i32,
+        container_info: Option<ContainerInfo>,
+        child: Option<Box<GroupNode>>,
+    ) -> SessionNode {
+        SessionNode {
+            pid,
+            start_time: None,
+            container_info,
+            child,
+        }
+    }
+
+    #[test]
+    fn test_display() {
+        let mut state = TerminalState::new(std::process::id() as i32);
+        state.root = session(10, None, None);
+        assert_eq!(state.to_string(), "TerminalState[ S-10 ]");
+
+        // toolbox, running distrobox inside the container
+        state.root = session(
+            10,
+            None,
+            group(
+                11,
+                Some(session(
+                    20,
+                    container("fedora-toolbox"),
+                    group(21, Some(session(30, container("ubuntu"), group(31, None)))),
+                )),
+            ),
+        );
+        assert_eq!(
+            state.to_string(),
+            "TerminalState[ S-10 G-11 S-20(fedora-toolbox) G-21 S-30(ubuntu) G-31 ]"
+        );
+    }
+
+    #[test]
+    fn test_root_on_tty() {
+        let pid = std::process::id() as i32;
+        let tty_nr = Process::new(pid).tty_nr().unwrap();
+
+        let mut state = TerminalState::new(pid);
+        let root = Process::new(pid);
+        assert!(state.root_on_tty(&root));
+        state.set_tty_nr(tty_nr);
+        assert!(state.root_on_tty(&root));
+
+        state.set_tty_nr(tty_nr + 1);
+        state.root = session(pid, None, group(pid, None));
+        state.update();
+        assert!(state.root.child().is_none());
+        assert_eq!(state.foreground().command(), "");
+    }
+
+    #[test]
+    fn test_is_same_process() {
+        let pid = std::process::id() as i32;
+        let start_time = Process::new(pid).start_time().unwrap();
+
+        let process = Process::new(pid);
+        assert!(is_same_process(&process, Some(start_time)));
+        assert!(!is_same_process(&process, Some(start_time + 1)));
+        assert!(is_same_process(&process, None));
+    }
+}
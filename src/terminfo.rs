@@ -0,0 +1,271 @@
+// This module reads the compiled terminfo entry for the terminal we are running in
+//
+// Only what we need is supported: looking up string capabilities by index, and
+// expanding the simple parameterized strings used for status lines. See
+// term(5) for the file format.
+
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+const MAGIC: i16 = 0o432;
+// ncurses 6.1 format with 32-bit numbers
+const MAGIC_32BIT: i16 = 0o1036;
+
+// Indices into the string capabilities, from <term.h>
+const CLR_EOL: usize = 6;
+const FROM_STATUS_LINE: usize = 47;
+const TO_STATUS_LINE: usize = 135;
+
+pub struct Terminfo {
+    strings: Vec<Option<Vec<u8>>>,
+}
+
+fn format_error(message: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Other,
+        format!("Bad terminfo file: {}", message),
+    )
+}
+
+fn read_i16(data: &[u8], offset: usize) -> io::Result<i16> {
+    match data.get(offset..offset + 2) {
+        Some(bytes) => Ok(i16::from_le_bytes([bytes[0], bytes[1]])),
+        None => Err(format_error("truncated")),
+    }
+}
+
+fn search_dirs() -> Vec<PathBuf> {
+    let mut dirs = vec![];
+    if let Some(dir) = std::env::var_os("TERMINFO") {
+        dirs.push(PathBuf::from(dir));
+    }
+    if let Some(home) = dirs::home_dir() {
+        dirs.push(home.join(".terminfo"));
+    }
+    if let Some(terminfo_dirs) = std::env::var_os("TERMINFO_DIRS") {
+        dirs.extend(std::env::split_paths(&terminfo_dirs));
+    }
+    for dir in &["/etc/terminfo", "/lib/terminfo", "/usr/share/terminfo"] {
+        dirs.push(PathBuf::from(dir));
+    }
+
+    dirs
+}
+
+impl Terminfo {
+    /// Loads the entry for $TERM
+    pub fn from_env() -> io::Result<Terminfo> {
+        match std::env::var("TERM") {
+            Ok(term) => Terminfo::load(&term),
+            Err(_) => Err(io::Error::new(io::ErrorKind::NotFound, "TERM not set")),
+        }
+    }
+
+    pub fn load(term: &str) -> io::Result<Terminfo> {
+        let first = match term.chars().next() {
+            Some(first) if !term.contains('/') => first,
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "Bad terminal name",
+                ))
+            }
+        };
+
+        for dir in search_dirs() {
+            // Directories are named by the first letter, or on some systems
+            // by its hex value
+            for subdir in &[first.to_string(), format!("{:x}", first as u32)] {
+                if let Ok(data) = fs::read(dir.join(subdir).join(term)) {
+                    return Terminfo::parse(&data);
+                }
+            }
+        }
+
+        Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("No terminfo entry for {}", term),
+        ))
+    }
+
+    pub fn parse(data: &[u8]) -> io::Result<Terminfo> {
+        let number_size = match read_i16(data, 0)? {
+            MAGIC => 2,
+            MAGIC_32BIT => 4,
+            _ => return Err(format_error("bad magic number")),
+        };
+
+        let mut header = [0usize; 5];
+        for (i, value) in header.iter_mut().enumerate() {
+            let field = read_i16(data, 2 + 2 * i)?;
+            if field < 0 {
+                return Err(format_error("bad header"));
+            }
+            *value = field as usize;
+        }
+        let [names_size, bool_count, num_count, str_count, str_table_size] = header;
+
+        let mut offset = 12 + names_size + bool_count;
+        // Numbers start on an even byte
+        offset += offset % 2;
+        offset += num_count * number_size;
+
+        let table_start = offset + str_count * 2;
+        let table = match data.get(table_start..table_start + str_table_size) {
+            Some(table) => table,
+            None => return Err(format_error("truncated")),
+        };
+
+        let mut strings = Vec::with_capacity(str_count);
+        for i in 0..str_count {
+            // Negative offsets are absent or cancelled capabilities
+            let string_offset = read_i16(data, offset + 2 * i)?;
+            let string = if string_offset >= 0 {
+                let start = string_offset as usize;
+                table
+                    .get(start..)
+                    .and_then(|rest| rest.iter().position(|b| *b == 0))
+                    .map(|len| table[start..start + len].to_vec())
+            } else {
+                None
+            };
+            strings.push(string);
+        }
+
+        Ok(Terminfo { strings })
+    }
+
+    fn string(&self, index: usize) -> Option<&[u8]> {
+        self.strings.get(index).and_then(|s| s.as_deref())
+    }
+
+    /// el: the sequence to clear to the end of the line
+    pub fn el(&self) -> Option<Vec<u8>> {
+        self.string(CLR_EOL).and_then(|el| expand(el, 0))
+    }
+
+    /// tsl: the sequence to move to the status line, at the given column
+    pub fn tsl(&self, column: u32) -> Option<Vec<u8>> {
+        self.string(TO_STATUS_LINE)
+            .and_then(|tsl| expand(tsl, column))
+    }
+
+    /// fsl: the sequence to return from the status line
+    pub fn fsl(&self) -> Option<Vec<u8>> {
+        self.string(FROM_STATUS_LINE).and_then(|fsl| expand(fsl, 0))
+    }
+}
+
+// Expands a parameterized string with a single parameter; only %p1, %d, %i,
+// and %% are supported, which covers status line capabilities. Padding
+// ($<5>, for example) is dropped.
+fn expand(capability: &[u8], param: u32) -> Option<Vec<u8>> {
+    let mut result = vec![];
+    let mut param = param;
+    let mut pushed = None;
+
+    let mut iter = capability.iter().peekable();
+    while let Some(&c) = iter.next() {
+        if c == b'$' && iter.peek() == Some(&&b'<') {
+            if !iter.any(|c| *c == b'>') {
+                return None;
+            }
+            continue;
+        }
+
+        if c != b'%' {
+            result.push(c);
+            continue;
+        }
+
+        match iter.next() {
+            Some(b'%') => result.push(b'%'),
+            Some(b'i') => param += 1,
+            Some(b'p') => match iter.next() {
+                Some(b'1') => pushed = Some(param),
+                _ => return None,
+            },
+            Some(b'd') => match pushed.take() {
+                Some(value) => result.extend_from_slice(value.to_string().as_bytes()),
+                None => return None,
+            },
+            _ => return None,
+        }
+    }
+
+    Some(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Builds a terminfo file with the given string capabilities
+    fn build(strings: &[(usize, &[u8])]) -> Vec<u8> {
+        let names = b"test|Test terminal\0";
+        let bool_count = 3;
+        let num_count = 2;
+        let str_count = strings.iter().map(|(i, _)| i + 1).max().unwrap_or(0);
+
+        let mut offsets = vec![-1i16; str_count];
+        let mut table = vec![];
+        for (i, s) in strings {
+            offsets[*i] = table.len() as i16;
+            table.extend_from_slice(s);
+            table.push(0);
+        }
+
+        let mut data = vec![];
+        for value in &[
+            MAGIC,
+            names.len() as i16,
+            bool_count,
+            num_count,
+            str_count as i16,
+            table.len() as i16,
+        ] {
+            data.extend_from_slice(&value.to_le_bytes());
+        }
+        data.extend_from_slice(names);
+        data.extend_from_slice(&[1, 0, 1]);
+        if data.len() % 2 == 1 {
+            data.push(0);
+        }
+        data.extend_from_slice(&80i16.to_le_bytes());
+        data.extend_from_slice(&24i16.to_le_bytes());
+        for offset in offsets {
+            data.extend_from_slice(&offset.to_le_bytes());
+        }
+        data.extend_from_slice(&table);
+
+        data
+    }
+
+    #[test]
+    fn test_parse() {
+        let data = build(&[
+            (FROM_STATUS_LINE, b"\x1b[0$}"),
+            (TO_STATUS_LINE, b"\x1b[2$~\x1b[1$}\x1b[%i%p1%d`"),
+        ]);
+        let terminfo = Terminfo::parse(&data).unwrap();
+        assert_eq!(terminfo.tsl(0).unwrap(), b"\x1b[2$~\x1b[1$}\x1b[1`");
+        assert_eq!(terminfo.fsl().unwrap(), b"\x1b[0$}");
+
+        let terminfo = Terminfo::parse(&build(&[(5, b"\x1b[H\x1b[2J")])).unwrap();
+        assert_eq!(terminfo.tsl(0), None);
+
+        assert!(Terminfo::parse(&data[..data.len() - 4]).is_err());
+        assert!(Terminfo::parse(b"\x1a\x02").is_err());
+    }
+
+    #[test]
+    fn test_expand() {
+        assert_eq!(expand(b"\x1b]0;", 0).unwrap(), b"\x1b]0;");
+        assert_eq!(expand(b"%p1%d%%", 7).unwrap(), b"7%");
+        assert_eq!(expand(b"%i%p1%d", 0).unwrap(), b"1");
+        assert_eq!(expand(b"%p1%c", 65), None);
+        assert_eq!(expand(b"\x1b[K$<3>", 0).unwrap(), b"\x1b[K");
+        assert_eq!(expand(b"\x1b[K$<3", 0), None);
+    }
+}
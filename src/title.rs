@@ -0,0 +1,670 @@
+// This module composes the window title from the tracked state
+//
+// The title is produced from a format string such as:
+//
+//   {container} - {cwd} - {command} {jobs} - {title}
+//
+// When a field is empty, the literal text separating it from the previous
+// field is dropped, so the above becomes "~ - bash - Terminal" rather than
+// " - ~ - bash  - Terminal" outside of a container with no jobs. '{{' and '}}'
+// produce literal braces.
+//
+// Fields:
+//
+//   {container}  - the name of the container the foreground process is in
+//   {image}      - the image of that container, like fedora-toolbox:34
+//   {image_id}   - the short id of the image, like 8a7b6c5d4e3f
+//   {cwd}        - the working directory of the foreground process, with
+//                  the home directory as ~; with cwd_style = "short",
+//                  directories above the last are shortened to their first
+//                  letter, like ~/s/ttymon, and with cwd_max_components,
+//                  only that many components are shown, like …/src/ttymon
+//   {command}    - the foreground command name
+//   {argv0}      - the foreground process's argv[0], unmodified
+//   {argv}       - the foreground process's full command line, quoted like
+//                  a shell would need it, and shortened if very long
+//   {host}       - the host name, or the remote host when the foreground
+//                  process is ssh
+//   {hostname}   - the name of the host ttymon is running on, always
+//   {jobs}       - the shell's job count, as reported by shell integration
+//   {bell}       - an indicator ("[bell]") shown when the application has rung
+//                  the bell since the title was last composed
+//   {loginuser}  - the user who originally logged in, across sudo and su
+//   {rootwarn}   - an indicator (by default "[root]") shown only when the
+//                  foreground process is running as root
+//   {tty}        - the name of our pseudo-terminal, like pts/3
+//   {title}      - the title set by the application
+
+use crate::podman::short_image_id;
+use std::path::Path;
+
+pub const DEFAULT_ROOT_INDICATOR: &str = "[root]";
+
+const BELL_INDICATOR: &str = "[bell]";
+
+pub const DEFAULT_TITLE_FORMAT: &str = "{container} - {cwd} - {command} {jobs} - {title}";
+
+// The most characters of the command line {argv} shows
+const MAX_ARGV_LENGTH: usize = 80;
+
+pub struct TitleContext<'a> {
+    pub container_name: Option<&'a str>,
+    pub image_name: Option<&'a str>,
+    pub image_id: Option<&'a str>,
+    pub cwd: &'a Path,
+    pub home: Option<&'a Path>,
+    pub cwd_style: CwdStyle,
+    pub cwd_max_components: Option<usize>,
+    pub argv0: &'a str,
+    pub argv: &'a [String],
+    pub command: &'a str,
+    pub host: Option<&'a str>,
+    pub local_host: Option<&'a str>,
+    pub jobs: Option<u32>,
+    pub bell: bool,
+    pub login_user: Option<&'a str>,
+    pub root: bool,
+    pub root_indicator: &'a str,
+    pub tty: Option<&'a str>,
+    pub in_window_title: &'a str,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Field {
+    Container,
+    Image,
+    ImageId,
+    Cwd,
+    Argv0,
+    Argv,
+    Command,
+    Host,
+    HostName,
+    Jobs,
+    Bell,
+    LoginUser,
+    RootWarn,
+    Tty,
+    Title,
+}
+
+impl Field {
+    fn from_name(name: &str) -> Option<Field> {
+        match name {
+            "container" => Some(Field::Container),
+            "image" => Some(Field::Image),
+            "image_id" => Some(Field::ImageId),
+            "cwd" => Some(Field::Cwd),
+            "argv0" => Some(Field::Argv0),
+            "argv" => Some(Field::Argv),
+            "command" => Some(Field::Command),
+            "host" => Some(Field::Host),
+            "hostname" => Some(Field::HostName),
+            "jobs" => Some(Field::Jobs),
+            "bell" => Some(Field::Bell),
+            "loginuser" => Some(Field::LoginUser),
+            "rootwarn" => Some(Field::RootWarn),
+            "tty" => Some(Field::Tty),
+            "title" => Some(Field::Title),
+            _ => None,
+        }
+    }
+
+    fn value(self, ctx: &TitleContext) -> String {
+        match self {
+            Field::Container => ctx.container_name.unwrap_or("").to_string(),
+            Field::Image => ctx.image_name.unwrap_or("").to_string(),
+            Field::ImageId => short_image_id(ctx.image_id.unwrap_or("")),
+            Field::Cwd => abbreviate_path(
+                &abbreviate_home(ctx.cwd, ctx.home),
+                ctx.cwd_style,
+                ctx.cwd_max_components,
+            ),
+            Field::Argv0 => ctx.argv0.to_string(),
+            Field::Argv => format_argv(ctx.argv),
+            Field::Command => ctx.command.to_string(),
+            Field::Host => ctx.host.unwrap_or("").to_string(),
+            Field::HostName => ctx.local_host.unwrap_or("").to_string(),
+            Field::Jobs => match ctx.jobs {
+                Some(0) | None => String::from(""),
+                Some(1) => String::from("[1 job]"),
+                Some(n) => format!("[{} jobs]", n),
+            },
+            Field::Bell if ctx.bell => String::from(BELL_INDICATOR),
+            Field::Bell => String::from(""),
+            Field::LoginUser => ctx.login_user.unwrap_or("").to_string(),
+            Field::RootWarn if ctx.root => ctx.root_indicator.to_string(),
+            Field::RootWarn => String::from(""),
+            Field::Tty => ctx.tty.unwrap_or("").to_string(),
+            Field::Title => ctx.in_window_title.to_string(),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Segment {
+    Literal(String),
+    Field(Field),
+}
+
+pub struct TitleFormat {
+    segments: Vec<Segment>,
+}
+
+impl TitleFormat {
+    pub fn parse(format: &str) -> TitleFormat {
+        let mut segments = vec![];
+        let mut literal = String::new();
+        let mut chars = format.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.peek() == Some(&'{') => {
+                    chars.next();
+                    literal.push('{');
+                }
+                '}' if chars.peek() == Some(&'}') => {
+                    chars.next();
+                    literal.push('}');
+                }
+                '{' => {
+                    let mut name = String::new();
+                    let mut closed = false;
+                    for c in &mut chars {
+                        if c == '}' {
+                            closed = true;
+                            break;
+                        }
+                        name.push(c);
+                    }
+
+                    match Field::from_name(&name) {
+                        Some(field) if closed => {
+                            if !literal.is_empty() {
+                                segments.push(Segment::Literal(literal));
+                                literal = String::new();
+                            }
+                            segments.push(Segment::Field(field));
+                        }
+                        _ => {
+                            warn!("Unknown field in title format: {{{}", name);
+                            literal.push('{');
+                            literal.push_str(&name);
+                            if closed {
+                                literal.push('}');
+                            }
+                        }
+                    }
+                }
+                _ => literal.push(c),
+            }
+        }
+        if !literal.is_empty() {
+            segments.push(Segment::Literal(literal));
+        }
+
+        TitleFormat { segments }
+    }
+
+    pub fn compose(&self, ctx: &TitleContext) -> String {
+        let mut result = String::new();
+        // Literal text since the last field
+        let mut separator = String::new();
+        let mut seen_field = false;
+        let mut have_content = false;
+
+        for segment in &self.segments {
+            match segment {
+                Segment::Literal(text) => separator.push_str(text),
+                Segment::Field(field) => {
+                    let value = field.value(ctx);
+                    // Text before the first field is always kept, text between
+                    // fields only if there is something on either side of it.
+                    if !seen_field || (have_content && !value.is_empty()) {
+                        result.push_str(&separator);
+                    }
+                    separator.clear();
+                    seen_field = true;
+
+                    if !value.is_empty() {
+                        result.push_str(&value);
+                        have_content = true;
+                    }
+                }
+            }
+        }
+        result.push_str(&separator);
+
+        result
+    }
+}
+
+#[cfg(feature = "title-script")]
+pub fn is_field(name: &str) -> bool {
+    Field::from_name(name).is_some()
+}
+
+/// The value of the named field, as it would appear in a composed title
+#[cfg(feature = "title-script")]
+pub fn field_value(name: &str, ctx: &TitleContext) -> Option<String> {
+    Field::from_name(name).map(|field| field.value(ctx))
+}
+
+impl Default for TitleFormat {
+    fn default() -> Self {
+        TitleFormat::parse(DEFAULT_TITLE_FORMAT)
+    }
+}
+
+/// Where truncate_title() cuts out text
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Ellipsis {
+    Middle,
+    End,
+}
+
+impl Ellipsis {
+    pub fn from_name(name: &str) -> Option<Ellipsis> {
+        match name {
+            "middle" => Some(Ellipsis::Middle),
+            "end" => Some(Ellipsis::End),
+            _ => None,
+        }
+    }
+}
+
+/// How {cwd} shows the directories leading to the working directory
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CwdStyle {
+    Full,
+    Short,
+}
+
+impl CwdStyle {
+    pub fn from_name(name: &str) -> Option<CwdStyle> {
+        match name {
+            "full" => Some(CwdStyle::Full),
+            "short" => Some(CwdStyle::Short),
+            _ => None,
+        }
+    }
+}
+
+// Combining marks, joiners, and variation selectors: characters that belong
+// with the character before them, and mustn't be separated from it. (A
+// rough approximation to grapheme clusters.)
+fn is_combining(c: char) -> bool {
+    matches!(c,
+        '\u{300}'..='\u{36f}'
+        | '\u{1ab0}'..='\u{1aff}'
+        | '\u{1dc0}'..='\u{1dff}'
+        | '\u{200c}'..='\u{200d}'
+        | '\u{20d0}'..='\u{20ff}'
+        | '\u{fe00}'..='\u{fe0f}'
+        | '\u{fe20}'..='\u{fe2f}'
+        | '\u{e0100}'..='\u{e01ef}')
+}
+
+// Splits text into characters, each with any combining characters after it
+fn clusters(text: &str) -> Vec<&str> {
+    let mut result = vec![];
+    let mut start = 0;
+    for (i, c) in text.char_indices() {
+        if i > start && !is_combining(c) {
+            result.push(&text[start..i]);
+            start = i;
+        }
+    }
+    if start < text.len() {
+        result.push(&text[start..]);
+    }
+
+    result
+}
+
+/// Shortens title to at most max_length characters (counting a character
+/// and the combining characters after it as one), replacing what is cut out
+/// with an ellipsis
+pub fn truncate_title(title: &str, max_length: usize, ellipsis: Ellipsis) -> String {
+    let clusters = clusters(title);
+    if clusters.len() <= max_length {
+        return title.to_string();
+    }
+
+    let keep = max_length.saturating_sub(1);
+    let (head, tail) = match ellipsis {
+        Ellipsis::End => (keep, 0),
+        Ellipsis::Middle => (keep - keep / 2, keep / 2),
+    };
+
+    let mut result: String = clusters[..head].concat();
+    result.push('\u{2026}');
+    result.push_str(&clusters[clusters.len() - tail..].concat());
+    result
+}
+
+// Quotes an argument if it has characters a shell would treat specially
+pub fn quote_arg(arg: &str) -> String {
+    let is_plain = |c: char| c.is_alphanumeric() || "-_./:=@%+,".contains(c);
+    if !arg.is_empty() && arg.chars().all(is_plain) {
+        arg.to_string()
+    } else {
+        format!("'{}'", arg.replace('\'', "'\\''"))
+    }
+}
+
+fn format_argv(argv: &[String]) -> String {
+    let command_line = argv
+        .iter()
+        .map(|arg| quote_arg(arg))
+        .collect::<Vec<_>>()
+        .join(" ");
+
+    if command_line.chars().count() <= MAX_ARGV_LENGTH {
+        command_line
+    } else {
+        let mut shortened: String = command_line.chars().take(MAX_ARGV_LENGTH - 1).collect();
+        shortened.push('\u{2026}');
+        shortened
+    }
+}
+
+// Replace the home directory prefix of a path with ~. A home directory of / (or
+// an empty one) isn't abbreviated, since that would abbreviate everything.
+fn abbreviate_home(path: &Path, home: Option<&Path>) -> String {
+    if let Some(home) = home {
+        if home.parent().is_some() {
+            if let Ok(home_suffix) = path.strip_prefix(home) {
+                return if home_suffix.as_os_str().is_empty() {
+                    String::from("~")
+                } else {
+                    format!("~/{}", home_suffix.to_string_lossy())
+                };
+            }
+        }
+    }
+
+    path.to_string_lossy().into_owned()
+}
+
+// Shortens a path that has already had the home directory replaced: with
+// CwdStyle::Short, each component but the last is cut to its first character
+// (two for a hidden directory), and with max_components, leading components
+// beyond that many are replaced by an ellipsis. The last component is always
+// kept whole.
+fn abbreviate_path(path: &str, style: CwdStyle, max_components: Option<usize>) -> String {
+    let (root, relative) = match path.strip_prefix('/') {
+        Some(relative) => ("/", relative),
+        None => ("", path),
+    };
+    let mut components: Vec<&str> = relative.split('/').filter(|c| !c.is_empty()).collect();
+
+    let mut elided = false;
+    if let Some(max_components) = max_components.filter(|&max| max > 0) {
+        if components.len() > max_components {
+            components.drain(..components.len() - max_components);
+            elided = true;
+        }
+    }
+
+    let last = match components.pop() {
+        Some(last) => last,
+        None => return path.to_string(),
+    };
+    let mut result = String::from(if elided { "\u{2026}/" } else { root });
+    for component in components {
+        if style == CwdStyle::Short && component != "~" {
+            let len = if component.starts_with('.') { 2 } else { 1 };
+            result.extend(component.chars().take(len));
+        } else {
+            result.push_str(component);
+        }
+        result.push('/');
+    }
+    result.push_str(last);
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn context<'a>() -> TitleContext<'a> {
+        TitleContext {
+            container_name: None,
+            image_name: None,
+            image_id: None,
+            cwd: Path::new("/home/user"),
+            home: Some(Path::new("/home/user")),
+            cwd_style: CwdStyle::Full,
+            cwd_max_components: None,
+            argv0: "-bash",
+            argv: &[],
+            command: "bash",
+            host: Some("workstation"),
+            local_host: Some("workstation"),
+            jobs: None,
+            bell: false,
+            login_user: Some("user"),
+            root: false,
+            root_indicator: DEFAULT_ROOT_INDICATOR,
+            tty: Some("pts/5"),
+            in_window_title: "ttymon",
+        }
+    }
+
+    fn cwd_title(cwd: &str, home: Option<&str>) -> String {
+        TitleFormat::default().compose(&TitleContext {
+            cwd: Path::new(cwd),
+            home: home.map(Path::new),
+            ..context()
+        })
+    }
+
+    #[test]
+    fn test_home_abbreviation() {
+        assert_eq!(
+            cwd_title("/home/user", Some("/home/user")),
+            "~ - bash - ttymon"
+        );
+        assert_eq!(
+            cwd_title("/home/user/src", Some("/home/user")),
+            "~/src - bash - ttymon"
+        );
+        assert_eq!(
+            cwd_title("/home/user/", Some("/home/user")),
+            "~ - bash - ttymon"
+        );
+        assert_eq!(
+            cwd_title("/home/user2", Some("/home/user")),
+            "/home/user2 - bash - ttymon"
+        );
+        assert_eq!(
+            cwd_title("/etc", Some("/home/user")),
+            "/etc - bash - ttymon"
+        );
+        assert_eq!(cwd_title("/etc", None), "/etc - bash - ttymon");
+        assert_eq!(cwd_title("/etc", Some("/")), "/etc - bash - ttymon");
+        assert_eq!(cwd_title("/etc", Some("")), "/etc - bash - ttymon");
+    }
+
+    #[test]
+    fn test_abbreviate_path() {
+        let short = |path| abbreviate_path(path, CwdStyle::Short, None);
+        assert_eq!(short("~/src/ttymon/src"), "~/s/t/src");
+        assert_eq!(short("/usr/share/doc"), "/u/s/doc");
+        assert_eq!(short("~/.config/ttymon"), "~/.c/ttymon");
+        assert_eq!(short("/\u{e9}t\u{e9}/x"), "/\u{e9}/x");
+        assert_eq!(short("~"), "~");
+        assert_eq!(short("/"), "/");
+        assert_eq!(short("/etc"), "/etc");
+
+        let last = |path, max| abbreviate_path(path, CwdStyle::Full, Some(max));
+        assert_eq!(last("~/src/ttymon/src", 2), "\u{2026}/ttymon/src");
+        assert_eq!(last("/usr/share/doc", 3), "/usr/share/doc");
+        assert_eq!(last("~/src", 2), "~/src");
+        assert_eq!(last("~/src", 1), "\u{2026}/src");
+        assert_eq!(last("~", 1), "~");
+        assert_eq!(last("/usr/share/doc", 0), "/usr/share/doc");
+
+        assert_eq!(
+            abbreviate_path("~/src/ttymon/src/filter", CwdStyle::Short, Some(3)),
+            "\u{2026}/t/s/filter"
+        );
+
+        let ctx = TitleContext {
+            cwd: Path::new("/home/user/src/ttymon"),
+            cwd_style: CwdStyle::Short,
+            ..context()
+        };
+        assert_eq!(TitleFormat::parse("{cwd}").compose(&ctx), "~/s/ttymon");
+    }
+
+    #[test]
+    fn test_compose() {
+        let title = TitleFormat::default().compose(&TitleContext {
+            container_name: Some("fedora-toolbox-34"),
+            cwd: Path::new("/home/user/src"),
+            argv0: "/usr/bin/vim",
+            command: "vim",
+            jobs: Some(2),
+            in_window_title: "main.rs",
+            ..context()
+        });
+        assert_eq!(title, "fedora-toolbox-34 - ~/src - vim [2 jobs] - main.rs");
+    }
+
+    #[test]
+    fn test_format() {
+        let ctx = context();
+        let compose = |format: &str| TitleFormat::parse(format).compose(&ctx);
+
+        assert_eq!(compose("{tty}: {command}"), "pts/5: bash");
+        assert_eq!(compose("[{title}]"), "[ttymon]");
+        assert_eq!(compose("{loginuser}@{tty}"), "user@pts/5");
+        assert_eq!(compose("{loginuser}@{host}: {cwd}"), "user@workstation: ~");
+
+        // When sshing somewhere, {host} is that host, {hostname} still ours
+        let ctx = TitleContext {
+            host: Some("server"),
+            command: "ssh",
+            ..context()
+        };
+        assert_eq!(
+            TitleFormat::parse("{hostname}: {command} {host}").compose(&ctx),
+            "workstation: ssh server"
+        );
+        assert_eq!(compose("{{{argv0}}}"), "{-bash}");
+        assert_eq!(compose("{command} {unknown}"), "bash {unknown}");
+        assert_eq!(compose("{command} {title"), "bash {title");
+
+        // Separators next to empty fields are dropped
+        assert_eq!(compose("{container} - {command}"), "bash");
+        assert_eq!(compose("{command} - {container}"), "bash");
+        assert_eq!(
+            compose("{command} - {container} - {title}"),
+            "bash - ttymon"
+        );
+        assert_eq!(compose("<{container}|{jobs}>"), "<>");
+
+        let ctx = TitleContext {
+            container_name: Some("box"),
+            image_name: Some("registry.fedoraproject.org/fedora-toolbox:34"),
+            image_id: Some("sha256:8a7b6c5d4e3f2a1b0c9d8e7f"),
+            ..context()
+        };
+        assert_eq!(
+            TitleFormat::parse("{container} ({image})").compose(&ctx),
+            "box (registry.fedoraproject.org/fedora-toolbox:34)"
+        );
+        assert_eq!(
+            TitleFormat::parse("{container}@{image_id}").compose(&ctx),
+            "box@8a7b6c5d4e3f"
+        );
+
+        assert_eq!(compose("{rootwarn} {command}"), "bash");
+        let ctx = TitleContext {
+            root: true,
+            ..context()
+        };
+        assert_eq!(
+            TitleFormat::parse("{rootwarn} {command}").compose(&ctx),
+            "[root] bash"
+        );
+
+        assert_eq!(compose("{command} {bell}"), "bash");
+        let ctx = TitleContext {
+            bell: true,
+            ..context()
+        };
+        assert_eq!(
+            TitleFormat::parse("{command} {bell}").compose(&ctx),
+            "bash [bell]"
+        );
+
+        let ctx = TitleContext {
+            tty: None,
+            ..context()
+        };
+        assert_eq!(TitleFormat::parse("{tty}: {command}").compose(&ctx), "bash");
+    }
+
+    #[test]
+    fn test_truncate_title() {
+        assert_eq!(truncate_title("bash - ~", 8, Ellipsis::End), "bash - ~");
+        assert_eq!(
+            truncate_title("vim - ~/src", 8, Ellipsis::End),
+            "vim - ~\u{2026}"
+        );
+        assert_eq!(
+            truncate_title("vim - ~/src", 8, Ellipsis::Middle),
+            "vim \u{2026}src"
+        );
+        assert_eq!(truncate_title("vim", 1, Ellipsis::Middle), "\u{2026}");
+
+        // Characters, not bytes, and combining characters stay with their base
+        assert_eq!(
+            truncate_title("\u{2713}\u{2713}\u{2713}\u{2713}", 3, Ellipsis::End),
+            "\u{2713}\u{2713}\u{2026}"
+        );
+        assert_eq!(
+            truncate_title("cafe\u{301}s", 5, Ellipsis::End),
+            "cafe\u{301}s"
+        );
+        assert_eq!(
+            truncate_title("cafe\u{301}s", 4, Ellipsis::End),
+            "caf\u{2026}"
+        );
+        assert_eq!(
+            truncate_title("xcafe\u{301}", 4, Ellipsis::Middle),
+            "xc\u{2026}e\u{301}"
+        );
+    }
+
+    #[test]
+    fn test_argv() {
+        let argv = |args: &[&str]| {
+            let args: Vec<String> = args.iter().map(|a| a.to_string()).collect();
+            let ctx = TitleContext {
+                argv: &args,
+                ..context()
+            };
+            TitleFormat::parse("{argv}").compose(&ctx)
+        };
+
+        assert_eq!(
+            argv(&["python", "train.py", "--epochs", "100"]),
+            "python train.py --epochs 100"
+        );
+        assert_eq!(
+            argv(&["grep", "-r", "two words", "it's", ""]),
+            "grep -r 'two words' 'it'\\''s' ''"
+        );
+        assert_eq!(argv(&[]), "");
+
+        let long = argv(&["cat", &"x".repeat(100)]);
+        assert_eq!(long.chars().count(), MAX_ARGV_LENGTH);
+        assert!(long.starts_with("cat xxx"));
+        assert!(long.ends_with("x\u{2026}"));
+    }
+}
@@ -0,0 +1,480 @@
+// Title scripts: choosing the title format by conditions on the context
+//
+// A title script is a file of rules, one per line:
+//
+//   container                    => {container}: {cwd}
+//   command == "ssh"             => ssh - {title}
+//   rootwarn && !container       => {rootwarn} {cwd} - {command}
+//                                => {cwd} - {command} {jobs}
+//
+// The format (see title.rs) of the first rule whose condition holds is used;
+// a rule without a condition always holds. Conditions are built from field
+// names, which are true when the field isn't empty, "string literals", ==, !=,
+// !, &&, || and parentheses. Blank lines and lines starting with # are ignored.
+//
+// The script is only parsed again when the file changes. If it can't be read
+// or parsed, or no rule matches, the built-in format is used.
+
+use crate::title::{field_value, is_field, TitleContext, TitleFormat};
+use std::fs;
+use std::iter::Peekable;
+use std::path::PathBuf;
+use std::str::Chars;
+use std::time::SystemTime;
+
+#[derive(Debug, PartialEq)]
+enum Expr {
+    Field(String),
+    Literal(String),
+    Not(Box<Expr>),
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+    Equal(Box<Expr>, Box<Expr>),
+    NotEqual(Box<Expr>, Box<Expr>),
+}
+
+impl Expr {
+    fn value(&self, ctx: &TitleContext) -> String {
+        match self {
+            Expr::Field(name) => field_value(name, ctx).unwrap_or_default(),
+            Expr::Literal(value) => value.clone(),
+            _ => String::from(if self.holds(ctx) { "true" } else { "" }),
+        }
+    }
+
+    fn holds(&self, ctx: &TitleContext) -> bool {
+        match self {
+            Expr::Field(_) | Expr::Literal(_) => !self.value(ctx).is_empty(),
+            Expr::Not(a) => !a.holds(ctx),
+            Expr::And(a, b) => a.holds(ctx) && b.holds(ctx),
+            Expr::Or(a, b) => a.holds(ctx) || b.holds(ctx),
+            Expr::Equal(a, b) => a.value(ctx) == b.value(ctx),
+            Expr::NotEqual(a, b) => a.value(ctx) != b.value(ctx),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Token {
+    Name(String),
+    Literal(String),
+    Not,
+    And,
+    Or,
+    Equal,
+    NotEqual,
+    Open,
+    Close,
+}
+
+fn expect(chars: &mut Peekable<Chars>, c: char) -> Result<(), String> {
+    match chars.next() {
+        Some(next) if next == c => Ok(()),
+        _ => Err(format!("expected '{}'", c)),
+    }
+}
+
+fn tokenize(condition: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = vec![];
+    let mut chars = condition.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        match c {
+            ' ' | '\t' => {
+                chars.next();
+            }
+            '(' | ')' => {
+                chars.next();
+                tokens.push(if c == '(' { Token::Open } else { Token::Close });
+            }
+            '!' => {
+                chars.next();
+                if chars.peek() == Some(&'=') {
+                    chars.next();
+                    tokens.push(Token::NotEqual);
+                } else {
+                    tokens.push(Token::Not);
+                }
+            }
+            '=' => {
+                chars.next();
+                expect(&mut chars, '=')?;
+                tokens.push(Token::Equal);
+            }
+            '&' => {
+                chars.next();
+                expect(&mut chars, '&')?;
+                tokens.push(Token::And);
+            }
+            '|' => {
+                chars.next();
+                expect(&mut chars, '|')?;
+                tokens.push(Token::Or);
+            }
+            '"' => {
+                chars.next();
+                let mut literal = String::new();
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => match chars.next() {
+                            Some(c) => literal.push(c),
+                            None => return Err(String::from("unterminated string")),
+                        },
+                        Some(c) => literal.push(c),
+                        None => return Err(String::from("unterminated string")),
+                    }
+                }
+                tokens.push(Token::Literal(literal));
+            }
+            c if c.is_ascii_alphanumeric() || c == '_' => {
+                let mut name = String::new();
+                while let Some(&c) = chars.peek() {
+                    if !(c.is_ascii_alphanumeric() || c == '_') {
+                        break;
+                    }
+                    name.push(c);
+                    chars.next();
+                }
+                tokens.push(Token::Name(name));
+            }
+            c => return Err(format!("unexpected '{}'", c)),
+        }
+    }
+
+    Ok(tokens)
+}
+
+// A recursive descent parser over the tokens of a condition:
+//
+//   or      := and ( "||" and )*
+//   and     := unary ( "&&" unary )*
+//   unary   := "!" unary | compare
+//   compare := primary ( ( "==" | "!=" ) primary )?
+//   primary := name | literal | "(" or ")"
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Option<&Token> {
+        self.pos += 1;
+        self.tokens.get(self.pos - 1)
+    }
+
+    fn parse_or(&mut self) -> Result<Expr, String> {
+        let mut expr = self.parse_and()?;
+        while self.peek() == Some(&Token::Or) {
+            self.next();
+            expr = Expr::Or(Box::new(expr), Box::new(self.parse_and()?));
+        }
+        Ok(expr)
+    }
+
+    fn parse_and(&mut self) -> Result<Expr, String> {
+        let mut expr = self.parse_unary()?;
+        while self.peek() == Some(&Token::And) {
+            self.next();
+            expr = Expr::And(Box::new(expr), Box::new(self.parse_unary()?));
+        }
+        Ok(expr)
+    }
+
+    fn parse_unary(&mut self) -> Result<Expr, String> {
+        if self.peek() == Some(&Token::Not) {
+            self.next();
+            return Ok(Expr::Not(Box::new(self.parse_unary()?)));
+        }
+        self.parse_compare()
+    }
+
+    fn parse_compare(&mut self) -> Result<Expr, String> {
+        let expr = self.parse_primary()?;
+        match self.peek() {
+            Some(Token::Equal) => {
+                self.next();
+                Ok(Expr::Equal(Box::new(expr), Box::new(self.parse_primary()?)))
+            }
+            Some(Token::NotEqual) => {
+                self.next();
+                Ok(Expr::NotEqual(
+                    Box::new(expr),
+                    Box::new(self.parse_primary()?),
+                ))
+            }
+            _ => Ok(expr),
+        }
+    }
+
+    fn parse_primary(&mut self) -> Result<Expr, String> {
+        match self.next() {
+            Some(Token::Name(name)) if is_field(name) => Ok(Expr::Field(name.clone())),
+            Some(Token::Name(name)) => Err(format!("unknown field '{}'", name)),
+            Some(Token::Literal(value)) => Ok(Expr::Literal(value.clone())),
+            Some(Token::Open) => {
+                let expr = self.parse_or()?;
+                match self.next() {
+                    Some(Token::Close) => Ok(expr),
+                    _ => Err(String::from("expected ')'")),
+                }
+            }
+            Some(token) => Err(format!("unexpected {:?}", token)),
+            None => Err(String::from("unexpected end of condition")),
+        }
+    }
+}
+
+fn parse_condition(condition: &str) -> Result<Expr, String> {
+    let mut parser = Parser {
+        tokens: tokenize(condition)?,
+        pos: 0,
+    };
+    let expr = parser.parse_or()?;
+    match parser.peek() {
+        None => Ok(expr),
+        Some(token) => Err(format!("unexpected {:?}", token)),
+    }
+}
+
+// Finds the "=>" separating the condition from the format, skipping over any
+// in string literals
+fn find_arrow(line: &str) -> Option<usize> {
+    let mut in_literal = false;
+    let mut escaped = false;
+    for (i, c) in line.char_indices() {
+        if in_literal {
+            match c {
+                _ if escaped => escaped = false,
+                '\\' => escaped = true,
+                '"' => in_literal = false,
+                _ => {}
+            }
+        } else if c == '"' {
+            in_literal = true;
+        } else if line[i..].starts_with("=>") {
+            return Some(i);
+        }
+    }
+
+    None
+}
+
+struct Rule {
+    condition: Option<Expr>,
+    format: TitleFormat,
+}
+
+fn parse_script(script: &str) -> Result<Vec<Rule>, String> {
+    let mut rules = vec![];
+    for (i, line) in script.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let arrow = match find_arrow(line) {
+            Some(arrow) => arrow,
+            None => return Err(format!("line {}: missing '=>'", i + 1)),
+        };
+        let condition = line[..arrow].trim();
+        let condition = if condition.is_empty() {
+            None
+        } else {
+            Some(parse_condition(condition).map_err(|e| format!("line {}: {}", i + 1, e))?)
+        };
+
+        rules.push(Rule {
+            condition,
+            format: TitleFormat::parse(line[arrow + 2..].trim()),
+        });
+    }
+
+    Ok(rules)
+}
+
+pub struct TitleScript {
+    path: PathBuf,
+    // None until the script is first loaded; Some(None) if the file was
+    // missing, so that we only complain about that once
+    modified: Option<Option<SystemTime>>,
+    rules: Vec<Rule>,
+}
+
+impl TitleScript {
+    pub fn new(path: PathBuf) -> TitleScript {
+        let mut script = TitleScript {
+            path,
+            modified: None,
+            rules: vec![],
+        };
+        script.reload_if_changed();
+
+        script
+    }
+
+    /// Parses the script again if the file has been modified since last time
+    pub fn reload_if_changed(&mut self) {
+        let modified = Some(fs::metadata(&self.path).and_then(|m| m.modified()).ok());
+        if modified == self.modified {
+            return;
+        }
+        self.modified = modified;
+
+        let result = fs::read_to_string(&self.path)
+            .map_err(|e| e.to_string())
+            .and_then(|script| parse_script(&script));
+        self.rules = match result {
+            Ok(rules) => rules,
+            Err(e) => {
+                warn!("Can't load title script {}: {}", self.path.display(), e);
+                vec![]
+            }
+        };
+    }
+
+    /// Composes the title with the first matching rule, or None if there is none
+    pub fn compose(&self, ctx: &TitleContext) -> Option<String> {
+        self.rules
+            .iter()
+            .find(|rule| match &rule.condition {
+                Some(condition) => condition.holds(ctx),
+                None => true,
+            })
+            .map(|rule| rule.format.compose(ctx))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::title::{CwdStyle, DEFAULT_ROOT_INDICATOR};
+    use std::path::Path;
+
+    fn context<'a>() -> TitleContext<'a> {
+        TitleContext {
+            container_name: None,
+            image_name: None,
+            image_id: None,
+            cwd: Path::new("/home/user/src"),
+            home: Some(Path::new("/home/user")),
+            cwd_style: CwdStyle::Full,
+            cwd_max_components: None,
+            argv0: "-bash",
+            argv: &[],
+            command: "bash",
+            host: Some("workstation"),
+            local_host: Some("workstation"),
+            jobs: None,
+            bell: false,
+            login_user: None,
+            root: false,
+            root_indicator: DEFAULT_ROOT_INDICATOR,
+            tty: Some("pts/5"),
+            in_window_title: "ttymon",
+        }
+    }
+
+    fn compose(script: &str, ctx: &TitleContext) -> Option<String> {
+        let rules = parse_script(script).unwrap();
+        TitleScript {
+            path: PathBuf::new(),
+            modified: None,
+            rules,
+        }
+        .compose(ctx)
+    }
+
+    const SCRIPT: &str = r#"
+# Containers first
+container                => {container}: {cwd}
+command == "ssh"         => ssh - {title}
+rootwarn && !container   => {rootwarn} {cwd}
+                         => {cwd} - {command}
+"#;
+
+    #[test]
+    fn test_reload() {
+        let path =
+            std::env::temp_dir().join(format!("ttymon-test-{}-title-script", std::process::id()));
+        let _ = fs::remove_file(&path);
+
+        let mut script = TitleScript::new(path.clone());
+        assert!(script.rules.is_empty());
+        assert_eq!(script.modified, Some(None));
+        // Still missing, so not read again
+        script.reload_if_changed();
+        assert_eq!(script.modified, Some(None));
+
+        fs::write(&path, SCRIPT).unwrap();
+        script.reload_if_changed();
+        assert_eq!(script.rules.len(), 4);
+
+        fs::remove_file(&path).unwrap();
+        script.reload_if_changed();
+        assert!(script.rules.is_empty());
+        assert_eq!(script.modified, Some(None));
+    }
+
+    #[test]
+    fn test_rules() {
+        assert_eq!(compose(SCRIPT, &context()).unwrap(), "~/src - bash");
+
+        let ctx = TitleContext {
+            container_name: Some("fedora"),
+            ..context()
+        };
+        assert_eq!(compose(SCRIPT, &ctx).unwrap(), "fedora: ~/src");
+
+        let ctx = TitleContext {
+            command: "ssh",
+            in_window_title: "server",
+            ..context()
+        };
+        assert_eq!(compose(SCRIPT, &ctx).unwrap(), "ssh - server");
+
+        let ctx = TitleContext {
+            root: true,
+            ..context()
+        };
+        assert_eq!(compose(SCRIPT, &ctx).unwrap(), "[root] ~/src");
+
+        // No rule matching
+        assert_eq!(compose("container => {container}", &context()), None);
+    }
+
+    #[test]
+    fn test_conditions() {
+        let ctx = context();
+        let holds = |condition: &str| parse_condition(condition).unwrap().holds(&ctx);
+
+        assert!(holds("command"));
+        assert!(!holds("container"));
+        assert!(holds("!container"));
+        assert!(holds(r#"command == "bash""#));
+        assert!(holds(r#"command != "vim""#));
+        assert!(holds(r#"container || tty == "pts/5""#));
+        assert!(!holds(r#"container || command && tty == "pts/6""#));
+        assert!(holds(r#"(container || command) && !(tty == "pts/6")"#));
+        assert!(holds(r#"title == "ttymon" && "\"=>\"" == "\"=>\"""#));
+    }
+
+    #[test]
+    fn test_errors() {
+        assert!(parse_condition("nonexistent").is_err());
+        assert!(parse_condition("command ==").is_err());
+        assert!(parse_condition("command = \"bash\"").is_err());
+        assert!(parse_condition("(command").is_err());
+        assert!(parse_condition("command command").is_err());
+        assert!(parse_condition("\"unterminated").is_err());
+
+        assert!(parse_script("command {command}").is_err());
+        assert_eq!(
+            parse_script("\n\ncommand == => {command}").err().unwrap(),
+            "line 3: unexpected end of condition"
+        );
+    }
+}
@@ -0,0 +1,129 @@
+// file:// URIs, as used by OSC 7 to report the working directory
+//
+// The format is file://HOST/PATH, where HOST may be empty, and bytes of
+// PATH other than unreserved characters and '/' are %XX escaped.
+
+use nix::unistd::gethostname;
+use std::ffi::OsString;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::path::{Path, PathBuf};
+
+lazy_static! {
+    static ref HOST_NAME: Option<String> = {
+        let mut buf = [0u8; 256];
+        gethostname(&mut buf)
+            .ok()
+            .map(|name| name.to_string_lossy().into_owned())
+    };
+}
+
+fn is_unescaped(c: u8) -> bool {
+    c.is_ascii_alphanumeric() || b"-._~/".contains(&c)
+}
+
+fn is_local_host(host: &str, host_name: Option<&str>) -> bool {
+    host.is_empty()
+        || host.eq_ignore_ascii_case("localhost")
+        || matches!(host_name, Some(name) if host.eq_ignore_ascii_case(name))
+}
+
+/// A file:// URI for path on this host
+#[allow(dead_code)]
+pub fn encode_file_uri(path: &Path) -> String {
+    let mut uri = String::from("file://");
+    if let Some(host_name) = HOST_NAME.as_deref() {
+        uri.push_str(host_name);
+    }
+    for &c in path.as_os_str().as_bytes() {
+        if is_unescaped(c) {
+            uri.push(c as char);
+        } else {
+            uri.push_str(&format!("%{:02X}", c));
+        }
+    }
+
+    uri
+}
+
+/// The path of a file:// URI, or None if it isn't one, or names a file on
+/// some other host. Invalid %XX escapes are kept literally.
+pub fn decode_file_uri(uri: &str) -> Option<PathBuf> {
+    decode_file_uri_for_host(uri, HOST_NAME.as_deref())
+}
+
+fn decode_file_uri_for_host(uri: &str, host_name: Option<&str>) -> Option<PathBuf> {
+    let rest = uri.strip_prefix("file://")?;
+    let slash = rest.find('/')?;
+    if !is_local_host(&rest[..slash], host_name) {
+        return None;
+    }
+    let path = &rest.as_bytes()[slash..];
+
+    let mut decoded = Vec::with_capacity(path.len());
+    let mut i = 0;
+    while i < path.len() {
+        let escaped = path
+            .get(i + 1..i + 3)
+            .filter(|_| path[i] == b'%')
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match escaped {
+            Some(byte) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            None => {
+                decoded.push(path[i]);
+                i += 1;
+            }
+        }
+    }
+
+    Some(PathBuf::from(OsString::from_vec(decoded)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::OsStr;
+
+    fn decode(uri: &str) -> Option<PathBuf> {
+        decode_file_uri_for_host(uri, Some("workstation.example.com"))
+    }
+
+    #[test]
+    fn test_decode() {
+        assert_eq!(decode("file:///tmp"), Some(PathBuf::from("/tmp")));
+        assert_eq!(decode("file://localhost/tmp"), Some(PathBuf::from("/tmp")));
+        assert_eq!(
+            decode("file://Workstation.example.com/home/user/a%20b;c%2"),
+            Some(PathBuf::from("/home/user/a b;c%2"))
+        );
+        assert_eq!(
+            decode("file://localhost/%e2%9C%93%zz%"),
+            Some(PathBuf::from("/\u{2713}%zz%"))
+        );
+
+        // Not a local file URI
+        assert_eq!(decode("file://other.example.com/tmp"), None);
+        assert_eq!(decode("file://localhost"), None);
+        assert_eq!(decode("http://localhost/tmp"), None);
+        assert_eq!(decode_file_uri_for_host("file://host/tmp", None), None);
+    }
+
+    #[test]
+    fn test_encode() {
+        let path = Path::new("/home/user/My Documents/\u{2713}/a%b");
+        let uri = encode_file_uri(path);
+        assert!(uri.starts_with("file://"));
+        assert!(uri.ends_with("/home/user/My%20Documents/%E2%9C%93/a%25b"));
+        assert_eq!(decode_file_uri(&uri).as_deref(), Some(path));
+
+        // Bytes that aren't UTF-8 round-trip too
+        let path = Path::new(OsStr::from_bytes(b"/tmp/\xff~x"));
+        assert_eq!(
+            decode_file_uri(&encode_file_uri(path)).as_deref(),
+            Some(path)
+        );
+    }
+}